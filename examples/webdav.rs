@@ -0,0 +1,31 @@
+use std::error::Error;
+
+use config::Config;
+use org_server::{parser::ParserConfig, server::{Server, shutdown_signal}, webdav::WebDavSource};
+use reqwest::Url;
+
+/// Serves org files from a Nextcloud instance. Reads `base-url`, `username`
+/// and `password` from a `config.toml` in the working directory.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::builder()
+        .add_source(config::File::with_name("config"))
+        .build()?;
+
+    let base_url = Url::parse(&config.get_string("base-url")?)?;
+    let username = config.get_string("username")?;
+    let password = config.get_string("password")?;
+    let collection = format!("remote.php/dav/files/{username}/org");
+
+    let source = WebDavSource::new(base_url, &username, &password, &collection);
+    let server = Server{
+        port: 8080,
+        parser_config: ParserConfig::with_keywords(&["NEW", "NEXT", "SOME", "WAIT", "PROJ"], &["DONE", "CLND"]),
+        ..Default::default()
+    };
+    let handle = server.start(source).await?;
+    handle.shutdown_on(shutdown_signal());
+    handle.wait().await?;
+
+    Ok(())
+}
@@ -0,0 +1,404 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveTime};
+
+use crate::{parser::TodoItem, timestamp::{DelayKind, Interval, TimeUnit, Timestamp}};
+
+/// Days before a deadline it starts showing up on today's agenda, unless the
+/// deadline has its own warning period like `-3d`.
+const DEFAULT_WARNING_DAYS: i64 = 14;
+
+/// Upper bound on the repetitions expanded for a single timestamp.
+const MAX_REPETITIONS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Span {
+    Day,
+    #[default]
+    Week,
+    Month,
+}
+
+impl FromStr for Span {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "day" => Ok(Span::Day),
+            "week" => Ok(Span::Week),
+            "month" => Ok(Span::Month),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Span {
+    /// The first day of the span containing `date`, like `org-agenda` chooses it.
+    pub fn default_start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Span::Day => date,
+            Span::Week => date - Duration::days(date.weekday().num_days_from_monday().into()),
+            Span::Month => date.with_day(1).expect("First day of month always exists"),
+        }
+    }
+
+    /// The day after the span starting at `start`, which is where the next one
+    /// starts. `None` past the last date chrono knows.
+    pub fn end(self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Span::Day => start.checked_add_signed(Duration::days(1)),
+            Span::Week => start.checked_add_signed(Duration::days(7)),
+            Span::Month => start.checked_add_months(Months::new(1)),
+        }
+    }
+
+    /// Where the span before the one starting at `start` starts.
+    pub fn previous(self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Span::Day => start.checked_sub_signed(Duration::days(1)),
+            Span::Week => start.checked_sub_signed(Duration::days(7)),
+            Span::Month => start.checked_sub_months(Months::new(1)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Span::Day => "day",
+            Span::Week => "week",
+            Span::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    /// A deadline that has passed `days` ago and is still not done
+    DeadlineOverdue { days: i64 },
+    Deadline,
+    /// A deadline in its warning period, shown on today's agenda
+    DeadlineUpcoming { days: i64 },
+    Scheduled,
+    /// A scheduled item that was not done on the day, shown on today's agenda
+    ScheduledPast { days: i64 },
+    /// An active timestamp in the heading or body
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgendaEntry {
+    pub kind: EntryKind,
+    pub time: Option<NaiveTime>,
+    pub doc: String,
+    pub keyword: Option<String>,
+    pub done: bool,
+    pub priority: Option<char>,
+    pub heading: String,
+    pub category: Option<String>,
+    pub tags: Vec<String>,
+}
+
+pub struct Agenda {
+    start: NaiveDate,
+    end: NaiveDate,
+    today: NaiveDate,
+    days: BTreeMap<NaiveDate, Vec<AgendaEntry>>,
+}
+
+impl Agenda {
+    pub fn new(start: NaiveDate, span: Span, today: NaiveDate) -> Self {
+        Self { start, end: span.end(start).unwrap_or(NaiveDate::MAX), today, days: BTreeMap::new() }
+    }
+
+    pub fn start(&self) -> NaiveDate {
+        self.start
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.today
+    }
+
+    /// Adds the entries of a headline found in the document `doc`.
+    pub fn add(&mut self, doc: &str, item: &TodoItem) {
+        let entry = |kind, time| AgendaEntry {
+            kind,
+            time,
+            doc: doc.to_string(),
+            keyword: item.keyword().map(String::from),
+            done: item.is_done(),
+            priority: item.priority(),
+            heading: item.heading().to_string(),
+            category: item.category().map(String::from),
+            tags: item.tags().map(String::from).collect(),
+        };
+
+        if let Some(deadline) = item.deadline() {
+            for date in self.occurrences(deadline) {
+                self.push(date, entry(EntryKind::Deadline, deadline.time));
+            }
+
+            let days = (deadline.date - self.today).num_days();
+            let warning = deadline.delay.map(|delay| interval_days(delay.interval)).unwrap_or(DEFAULT_WARNING_DAYS);
+            if !item.is_done() && days < 0 {
+                self.push_today(entry(EntryKind::DeadlineOverdue { days: -days }, None));
+            } else if !item.is_done() && days > 0 && days <= warning {
+                self.push_today(entry(EntryKind::DeadlineUpcoming { days }, None));
+            }
+        }
+
+        if let Some(scheduled) = item.scheduled() {
+            let delay = scheduled.delay.map(|delay| (delay.kind, interval_days(delay.interval)));
+            let mut shown_today = false;
+            for (n, date) in self.occurrences(scheduled).into_iter().enumerate() {
+                let shown = match delay {
+                    Some((DelayKind::All, days)) => date.checked_add_signed(Duration::days(days)),
+                    Some((DelayKind::First, days)) if n == 0 && date == scheduled.date => date.checked_add_signed(Duration::days(days)),
+                    _ => Some(date),
+                };
+                // Delayed past the last date there is
+                let Some(shown) = shown else {
+                    continue;
+                };
+                shown_today |= shown == self.today;
+                self.push(shown, entry(EntryKind::Scheduled, scheduled.time));
+            }
+
+            // A repeating entry due again today is only shown once
+            let days = (self.today - scheduled.date).num_days();
+            if !item.is_done() && days > 0 && !shown_today {
+                self.push_today(entry(EntryKind::ScheduledPast { days }, None));
+            }
+        }
+
+        for timestamp in item.timestamps() {
+            for date in self.occurrences(timestamp) {
+                let last = match timestamp.end {
+                    Some((end, _)) => end.checked_add_signed(date - timestamp.date).unwrap_or(NaiveDate::MAX),
+                    None => date,
+                };
+                // Only the days of a range that are in the span
+                let mut day = date.max(self.start);
+                let last = last.min(self.end.pred_opt().unwrap_or(self.end));
+                while day <= last {
+                    let time = if day == date { timestamp.time } else { None };
+                    self.push(day, entry(EntryKind::Timestamp, time));
+                    match day.succ_opt() {
+                        Some(next) => day = next,
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every day of the span with its entries, ordered by time.
+    pub fn days(&self) -> Vec<(NaiveDate, Vec<&AgendaEntry>)> {
+        let mut days = Vec::new();
+        let mut day = self.start;
+        while day < self.end {
+            let mut entries: Vec<&AgendaEntry> = self.days.get(&day).map(|e| e.iter().collect()).unwrap_or_default();
+            entries.sort_by_key(|entry| (entry.time.is_none(), entry.time, entry.kind, entry.priority.unwrap_or('B')));
+            days.push((day, entries));
+            day += Duration::days(1);
+        }
+
+        days
+    }
+
+    fn push(&mut self, date: NaiveDate, entry: AgendaEntry) {
+        if date >= self.start && date < self.end {
+            self.days.entry(date).or_default().push(entry);
+        }
+    }
+
+    fn push_today(&mut self, entry: AgendaEntry) {
+        self.push(self.today, entry);
+    }
+
+    /// Dates the timestamp falls on, expanding its repeater, that can end up in the span.
+    fn occurrences(&self, timestamp: &Timestamp) -> Vec<NaiveDate> {
+        let Some(repeater) = timestamp.repeater else {
+            return vec![timestamp.date];
+        };
+
+        let mut dates = Vec::new();
+        let mut date = timestamp.date;
+        while date < self.end && dates.len() < MAX_REPETITIONS {
+            dates.push(date);
+            match advance(date, repeater.interval) {
+                Some(next) if next > date => date = next,
+                _ => break,
+            }
+        }
+
+        dates
+    }
+}
+
+fn advance(date: NaiveDate, interval: Interval) -> Option<NaiveDate> {
+    match interval.unit {
+        // Hourly repeaters put the entry on every day
+        TimeUnit::Hour => date.checked_add_signed(Duration::days(1)),
+        TimeUnit::Day => date.checked_add_signed(Duration::days(interval.value.into())),
+        TimeUnit::Week => date.checked_add_signed(Duration::weeks(interval.value.into())),
+        TimeUnit::Month => date.checked_add_months(Months::new(interval.value)),
+        TimeUnit::Year => date.checked_add_months(Months::new(interval.value.checked_mul(12)?)),
+    }
+}
+
+fn interval_days(interval: Interval) -> i64 {
+    let value = i64::from(interval.value);
+    match interval.unit {
+        TimeUnit::Hour => 0,
+        TimeUnit::Day => value,
+        TimeUnit::Week => value * 7,
+        TimeUnit::Month => value * 30,
+        TimeUnit::Year => value * 365,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{doc_to_headlines, ParserConfig};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn agenda(doc: &str, start: NaiveDate, span: Span, today: NaiveDate) -> Vec<(NaiveDate, Vec<(EntryKind, String)>)> {
+        let mut agenda = Agenda::new(start, span, today);
+        doc_to_headlines(doc, &ParserConfig::default(), |item| agenda.add("tasks.org", &item));
+        agenda.days().into_iter()
+            .map(|(day, entries)| (day, entries.into_iter().map(|e| (e.kind, e.heading.clone())).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_span_start() {
+        assert_eq!(Span::Week.default_start(date(2024, 1, 31)), date(2024, 1, 29));
+        assert_eq!(Span::Month.default_start(date(2024, 1, 31)), date(2024, 1, 1));
+        assert_eq!(Span::Day.default_start(date(2024, 1, 31)), date(2024, 1, 31));
+    }
+
+    #[test]
+    fn test_week_with_scheduled_items() {
+        let doc = "
+* TODO Water plants
+SCHEDULED: <2024-01-30 Tue>
+* Meeting <2024-02-01 Thu 10:00>
+* TODO Outside of span
+SCHEDULED: <2024-02-10 Sat>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Week, date(2024, 1, 29));
+
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[1], (date(2024, 1, 30), vec![(EntryKind::Scheduled, "Water plants".to_string())]));
+        assert_eq!(days[3], (date(2024, 2, 1), vec![(EntryKind::Timestamp, "Meeting <2024-02-01 Thu 10:00>".to_string())]));
+        assert!(days.iter().filter(|(_, entries)| !entries.is_empty()).count() == 2);
+    }
+
+    #[test]
+    fn test_repeaters() {
+        let doc = "
+* TODO Weekly review
+SCHEDULED: <2024-01-01 Mon +1w>
+* TODO Daily habit
+SCHEDULED: <2024-01-29 Mon .+2d>
+* TODO Rent
+DEADLINE: <2023-11-05 Sun ++1m>";
+        let days = agenda(doc, date(2024, 2, 1), Span::Month, date(2024, 1, 1));
+
+        let dates_of = |heading: &str| -> Vec<u32> {
+            days.iter()
+                .filter(|(_, entries)| entries.iter().any(|(_, h)| h == heading))
+                .map(|(day, _)| day.day())
+                .collect()
+        };
+        assert_eq!(dates_of("Weekly review"), [5, 12, 19, 26]);
+        assert_eq!(dates_of("Daily habit"), [2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28]);
+        assert_eq!(dates_of("Rent"), [5]);
+    }
+
+    #[test]
+    fn test_overdue_and_warnings() {
+        let doc = "
+* TODO Overdue
+DEADLINE: <2024-01-25 Thu>
+* TODO Soon
+DEADLINE: <2024-02-02 Fri -5d>
+* TODO Later
+DEADLINE: <2024-02-20 Tue -3d>
+* DONE Finished
+DEADLINE: <2024-01-20 Sat>
+* TODO Missed
+SCHEDULED: <2024-01-27 Sat>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Day, date(2024, 1, 29));
+
+        assert_eq!(days, [(date(2024, 1, 29), vec![
+            (EntryKind::DeadlineOverdue { days: 4 }, "Overdue".to_string()),
+            (EntryKind::DeadlineUpcoming { days: 4 }, "Soon".to_string()),
+            (EntryKind::ScheduledPast { days: 2 }, "Missed".to_string()),
+        ])]);
+    }
+
+    #[test]
+    fn test_scheduled_repeating_past() {
+        let doc = "
+* TODO Water plants
+SCHEDULED: <2024-01-27 Sat +1d>
+* TODO Pay rent
+SCHEDULED: <2024-01-01 Mon +1m>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Day, date(2024, 1, 29));
+
+        assert_eq!(days, [(date(2024, 1, 29), vec![
+            (EntryKind::Scheduled, "Water plants".to_string()),
+            (EntryKind::ScheduledPast { days: 28 }, "Pay rent".to_string()),
+        ])]);
+    }
+
+    #[test]
+    fn test_scheduled_delay() {
+        let doc = "
+* TODO Delayed
+SCHEDULED: <2024-01-29 Mon -2d>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Week, date(2024, 1, 20));
+
+        assert!(days[0].1.is_empty());
+        assert_eq!(days[2].1, [(EntryKind::Scheduled, "Delayed".to_string())]);
+    }
+
+    #[test]
+    fn test_timestamp_range() {
+        let doc = "* Conference <2024-01-30 Tue>--<2024-02-01 Thu>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Week, date(2024, 1, 29));
+
+        let with_entries: Vec<u32> = days.iter().filter(|(_, e)| !e.is_empty()).map(|(d, _)| d.day()).collect();
+        assert_eq!(with_entries, [30, 31, 1]);
+    }
+
+    #[test]
+    fn test_extreme_dates() {
+        let doc = "
+* TODO Much later
+SCHEDULED: <2024-01-29 Mon -100000000d>
+* Forever <0001-01-01 Mon +1d>--<9999-12-31 Fri>
+* Long ago <0001-01-01 Mon>--<2024-01-30 Tue>";
+        let days = agenda(doc, date(2024, 1, 29), Span::Week, date(2024, 1, 29));
+
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[0].1.iter().filter(|(_, heading)| heading.starts_with("Forever")).count(), MAX_REPETITIONS);
+        assert_eq!(days[0].1.iter().filter(|(_, heading)| heading.starts_with("Long ago")).count(), 1);
+        assert_eq!(days[2].1.iter().filter(|(_, heading)| heading.starts_with("Long ago")).count(), 0);
+        assert!(days.iter().all(|(_, entries)| entries.iter().all(|(_, heading)| heading != "Much later")));
+
+        let last = agenda(doc, NaiveDate::MAX, Span::Week, date(2024, 1, 29));
+        assert_eq!(last.len(), 0);
+    }
+
+    #[test]
+    fn test_span_bounds() {
+        assert_eq!(Span::Month.end(date(2024, 1, 31)), Some(date(2024, 2, 29)));
+        assert_eq!(Span::Week.previous(date(2024, 1, 31)), Some(date(2024, 1, 24)));
+        assert_eq!(Span::Day.end(NaiveDate::MAX), None);
+        assert_eq!(Span::Month.previous(NaiveDate::MIN), None);
+    }
+}
@@ -0,0 +1,570 @@
+use std::sync::Arc;
+
+use axum::{
+    Json, Router, routing, extract,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    doc::{OrgDoc, OrgSource, SourceError, WritableOrgSource, WriteError},
+    edit::{self, content_version},
+    links::DocLink,
+    parser::{self, TodoItem},
+    query::{self, QueryError, QueryGroup, QueryMatch},
+    search::SearchHit,
+    server::ServerState,
+    timestamp::Timestamp,
+};
+
+/// Routes of the JSON API, nested under `/api/v1` by the server.
+pub(crate) fn router<D, S>() -> Router<Arc<ServerState<D, S>>>
+where D: OrgDoc + 'static,
+      S: OrgSource<Doc = D> + 'static
+{
+    Router::new()
+        .route("/docs", routing::get(get_docs))
+        .route("/docs/*path", routing::get(get_doc))
+        .route("/todos", routing::get(get_todos))
+        .route("/search", routing::get(get_search))
+        .route("/query", routing::get(get_query))
+        .route("/links", routing::get(get_links))
+}
+
+/// Routes changing documents, only served for writable sources.
+pub(crate) fn write_router<D, S>() -> Router<Arc<ServerState<D, S>>>
+where D: OrgDoc + 'static,
+      S: WritableOrgSource<Doc = D> + 'static
+{
+    Router::new()
+        .route("/state", routing::post(post_state))
+}
+
+/// Whether the client prefers JSON over HTML, judging by its `Accept` header.
+pub(crate) fn wants_json(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .take_while(|media_type| *media_type != "text/html")
+        .any(|media_type| media_type.eq_ignore_ascii_case("application/json"))
+}
+
+#[derive(Serialize)]
+pub(crate) struct ApiError {
+    error: String,
+    /// Character offset of the problem in a query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<usize>,
+}
+
+impl ApiError {
+    pub(crate) fn new(error: &str) -> Self {
+        ApiError { error: error.to_string(), position: None }
+    }
+}
+
+pub(crate) type ApiResult<T> = Result<Json<T>, (StatusCode, Json<ApiError>)>;
+
+/// Status and message for a failure of the source, while reading `doc` if given.
+pub(crate) fn source_error(doc: Option<&str>, e: &SourceError) -> (StatusCode, String) {
+    let status = match e {
+        SourceError::NotFound | SourceError::InvalidPath => StatusCode::NOT_FOUND,
+        SourceError::Forbidden => StatusCode::FORBIDDEN,
+        SourceError::Remote(_) => StatusCode::BAD_GATEWAY,
+        SourceError::Io(_) | SourceError::Parse(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let message = match doc {
+        Some(doc) if status == StatusCode::NOT_FOUND => format!("Document not found: {doc}"),
+        Some(doc) => format!("{doc}: {e}"),
+        None => e.to_string(),
+    };
+    (status, message)
+}
+
+pub(crate) fn api_error((status, error): (StatusCode, String)) -> (StatusCode, Json<ApiError>) {
+    (status, Json(ApiError { error, position: None }))
+}
+
+pub(crate) fn query_error(e: QueryError) -> (StatusCode, Json<ApiError>) {
+    (StatusCode::BAD_REQUEST, Json(ApiError { error: e.to_string(), position: Some(e.position) }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct DocSummary {
+    path: String,
+    name: String,
+    /// RFC 3339, if the source can tell
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DocDetails {
+    path: String,
+    name: String,
+    /// See [`content_version`]
+    version: String,
+    content: String,
+    headlines: Vec<Headline>,
+}
+
+/// A headline as seen by [`OrgDoc::headlines`], `doc` and its `version` are
+/// only set when listing items of several documents.
+#[derive(Serialize)]
+pub(crate) struct Headline {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    doc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    line: usize,
+    anchor: String,
+    level: usize,
+    keyword: Option<String>,
+    done: bool,
+    priority: Option<char>,
+    heading: String,
+    tags: Vec<String>,
+    own_tags: Vec<String>,
+    scheduled: Option<ApiTimestamp>,
+    deadline: Option<ApiTimestamp>,
+    closed: Option<ApiTimestamp>,
+    properties: Vec<(String, String)>,
+    category: Option<String>,
+    path: Vec<String>,
+    timestamps: Vec<ApiTimestamp>,
+}
+
+impl Headline {
+    fn new(doc: Option<(&str, &str)>, item: &TodoItem) -> Self {
+        Headline {
+            doc: doc.map(|(doc, _)| doc.to_string()),
+            version: doc.map(|(_, version)| version.to_string()),
+            line: item.line(),
+            anchor: item.anchor().to_string(),
+            level: item.level(),
+            keyword: item.keyword().map(str::to_string),
+            done: item.is_done(),
+            priority: item.priority(),
+            heading: item.heading().to_string(),
+            tags: item.tags().map(str::to_string).collect(),
+            own_tags: item.own_tags().map(str::to_string).collect(),
+            scheduled: item.scheduled().map(ApiTimestamp::from),
+            deadline: item.deadline().map(ApiTimestamp::from),
+            closed: item.closed().map(ApiTimestamp::from),
+            properties: item.properties().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            category: item.category().map(str::to_string),
+            path: item.path().iter().map(|heading| heading.to_string()).collect(),
+            timestamps: item.timestamps().iter().map(ApiTimestamp::from).collect(),
+        }
+    }
+}
+
+/// A timestamp with its org syntax and the parts a client is most likely to need.
+#[derive(Serialize)]
+pub(crate) struct ApiTimestamp {
+    raw: String,
+    active: bool,
+    date: String,
+    time: Option<String>,
+    end_date: Option<String>,
+    end_time: Option<String>,
+    repeater: Option<String>,
+}
+
+impl From<&Timestamp> for ApiTimestamp {
+    fn from(ts: &Timestamp) -> Self {
+        let format_time = |time: chrono::NaiveTime| time.format("%H:%M").to_string();
+        ApiTimestamp {
+            raw: ts.to_string(),
+            active: ts.active,
+            date: ts.date.to_string(),
+            time: ts.time.map(format_time),
+            end_date: ts.end.map(|(date, _)| date.to_string()),
+            end_time: ts.end.and_then(|(_, time)| time).map(format_time),
+            repeater: ts.repeater.as_ref().map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TodoFilter {
+    pub(crate) keyword: Option<String>,
+    pub(crate) tag: Option<String>,
+    pub(crate) done: Option<bool>,
+}
+
+impl TodoFilter {
+    fn matches(&self, item: &TodoItem) -> bool {
+        self.keyword.as_deref().is_none_or(|keyword| item.keyword() == Some(keyword))
+            && self.tag.as_deref().is_none_or(|tag| item.tags().any(|t| t == tag))
+            && self.done.is_none_or(|done| item.is_done() == done)
+    }
+}
+
+/// Calls `f` with every document of the source. Documents that can't be read
+/// on their own, e.g. because they were deleted since listing them, are left
+/// out, but failures of the whole source are returned.
+pub(crate) async fn for_each_doc<D, S>(state: &ServerState<D, S>, mut f: impl FnMut(&str, &D) + Send) -> Result<(), SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    for path in state.source.list().await? {
+        match state.source.read(&path).await {
+            Ok(doc) => f(&path, &doc),
+            Err(e @ (SourceError::Io(_) | SourceError::Remote(_))) => return Err(e),
+            Err(_) => continue,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn docs<D, S>(state: &ServerState<D, S>) -> Result<Vec<DocSummary>, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let mut docs = Vec::new();
+    for path in state.source.list().await? {
+        let modified = state.source.modified(&path).await
+            .map(|modified| DateTime::<Utc>::from(modified).to_rfc3339_opts(SecondsFormat::Secs, true));
+        docs.push(DocSummary { name: state.source.doc_name(&path), path, modified });
+    }
+    Ok(docs)
+}
+
+pub(crate) async fn doc<D, S>(state: &ServerState<D, S>, path: &str) -> Result<DocDetails, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let doc = state.source.read(path).await?;
+    let mut headlines = Vec::new();
+    doc.headlines(&state.parser_config, &mut |item| headlines.push(Headline::new(None, item)));
+
+    Ok(DocDetails {
+        path: path.to_string(),
+        name: state.source.doc_name(path),
+        version: content_version(doc.content()),
+        content: doc.content().to_string(),
+        headlines,
+    })
+}
+
+pub(crate) async fn todos<D, S>(state: &ServerState<D, S>, filter: &TodoFilter) -> Result<Vec<Headline>, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let mut items = Vec::new();
+    for_each_doc(state, |path, doc| {
+        let version = content_version(doc.content());
+        doc.items(&state.parser_config, &mut |item| {
+            if filter.matches(item) {
+                items.push(Headline::new(Some((path, &version)), item));
+            }
+        });
+    }).await?;
+    Ok(items)
+}
+
+async fn get_docs<D, S>(State(state): State<Arc<ServerState<D, S>>>) -> ApiResult<Vec<DocSummary>>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    docs(&state).await.map(Json).map_err(|e| api_error(source_error(None, &e)))
+}
+
+async fn get_doc<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                       extract::Path(path): extract::Path<String>) -> ApiResult<DocDetails>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let path = format!("/{path}");
+    doc(&state, &path).await.map(Json).map_err(|e| api_error(source_error(Some(&path), &e)))
+}
+
+async fn get_todos<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                         Query(filter): Query<TodoFilter>) -> ApiResult<Vec<Headline>>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    todos(&state, &filter).await.map(Json).map_err(|e| api_error(source_error(None, &e)))
+}
+
+/// Results shown when no `limit` is given.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+pub(crate) struct SearchParams {
+    pub(crate) q: String,
+    pub(crate) limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SearchResult {
+    doc: String,
+    line: usize,
+    heading: Option<String>,
+    anchor: Option<String>,
+    /// Link to the headline in the rendered document
+    url: String,
+    /// HTML with the matching words in `<mark>`
+    snippet: String,
+    score: u32,
+}
+
+impl From<SearchHit> for SearchResult {
+    fn from(hit: SearchHit) -> Self {
+        SearchResult {
+            url: hit.url(),
+            doc: hit.doc,
+            line: hit.line,
+            heading: hit.heading,
+            anchor: hit.anchor,
+            snippet: hit.snippet,
+            score: hit.score,
+        }
+    }
+}
+
+pub(crate) async fn search<D, S>(state: &ServerState<D, S>, params: &SearchParams) -> Result<Vec<SearchHit>, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    state.search.refresh(&state.source, &state.parser_config).await?;
+    Ok(state.search.search(&params.q, params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT)))
+}
+
+async fn get_search<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                          Query(params): Query<SearchParams>) -> ApiResult<Vec<SearchResult>>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let hits = search(&state, &params).await.map_err(|e| api_error(source_error(None, &e)))?;
+    Ok(Json(hits.into_iter().map(SearchResult::from).collect()))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct QueryParams {
+    pub(crate) q: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct QueryResult {
+    groups: Vec<ApiQueryGroup>,
+}
+
+/// Headlines grouped as the query asks for, a single group without a name otherwise.
+#[derive(Serialize)]
+struct ApiQueryGroup {
+    name: Option<String>,
+    headlines: Vec<Headline>,
+}
+
+impl From<Vec<QueryGroup>> for QueryResult {
+    fn from(groups: Vec<QueryGroup>) -> Self {
+        let groups = groups.into_iter()
+            .map(|group| ApiQueryGroup {
+                name: group.name,
+                headlines: group.matches.iter()
+                    .map(|found| Headline::new(Some((&found.doc, &found.version)), &found.item))
+                    .collect(),
+            })
+            .collect();
+        QueryResult { groups }
+    }
+}
+
+/// Runs `query` over the headlines of all documents, see [`query::Query`].
+pub(crate) async fn query<D, S>(state: &ServerState<D, S>, query: &query::Query) -> Result<Vec<QueryGroup>, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let today = Local::now().date_naive();
+    let mut matches = Vec::new();
+    for_each_doc(state, |path, doc| {
+        let version = content_version(doc.content());
+        doc.headlines(&state.parser_config, &mut |item| {
+            if query.matches(path, item, today) {
+                matches.push(QueryMatch { doc: path.to_string(), version: version.clone(), item: item.clone().into_owned() });
+            }
+        });
+    }).await?;
+    Ok(query.arrange(matches))
+}
+
+async fn get_query<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                         Query(params): Query<QueryParams>) -> ApiResult<QueryResult>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let parsed = query::Query::parse(&params.q).map_err(query_error)?;
+    let groups = query(&state, &parsed).await.map_err(|e| api_error(source_error(None, &e)))?;
+    Ok(Json(QueryResult::from(groups)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LinksParams {
+    /// Only the links to this document
+    doc: Option<String>,
+}
+
+/// A link from a document to another, see [`DocLink`].
+#[derive(Serialize)]
+pub(crate) struct LinkResult {
+    from: String,
+    line: usize,
+    heading: Option<String>,
+    anchor: Option<String>,
+    /// Link to where the link is in the rendered document
+    url: String,
+    link: String,
+    to: String,
+    /// Anchor of the headline linked to, `None` for the whole document
+    to_anchor: Option<String>,
+    to_heading: Option<String>,
+}
+
+impl From<DocLink> for LinkResult {
+    fn from(link: DocLink) -> Self {
+        let url = link.url();
+        let (to_anchor, to_heading) = link.target.unzip();
+        LinkResult {
+            url,
+            from: link.from,
+            line: link.line,
+            heading: link.heading,
+            anchor: link.anchor,
+            link: link.link,
+            to: link.to,
+            to_anchor,
+            to_heading,
+        }
+    }
+}
+
+/// The links between documents, or those to `doc`.
+pub(crate) async fn links<D, S>(state: &ServerState<D, S>, doc: Option<&str>) -> Result<Vec<DocLink>, SourceError>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    state.links.refresh(&state.source, &state.parser_config).await?;
+    Ok(match doc {
+        Some(doc) => state.links.backlinks(doc),
+        None => state.links.links(),
+    })
+}
+
+async fn get_links<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                         Query(params): Query<LinksParams>) -> ApiResult<Vec<LinkResult>>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let links = links(&state, params.doc.as_deref()).await.map_err(|e| api_error(source_error(None, &e)))?;
+    Ok(Json(links.into_iter().map(LinkResult::from).collect()))
+}
+
+/// Changes the TODO keyword of a headline, identified by its line or `ID` property.
+#[derive(Deserialize)]
+pub(crate) struct StateChange {
+    pub(crate) doc: String,
+    pub(crate) line: Option<usize>,
+    pub(crate) id: Option<String>,
+    /// The keyword to set, the empty string removes it. Defaults to the next
+    /// keyword in the sequence.
+    pub(crate) keyword: Option<String>,
+    /// [`content_version`] of the document the change was made on, to refuse
+    /// it if the document changed since
+    pub(crate) version: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StateChanged {
+    headline: Headline,
+}
+
+pub(crate) async fn change_state<D, S>(state: &ServerState<D, S>, change: &StateChange) -> Result<StateChanged, (StatusCode, String)>
+where D: OrgDoc,
+      S: WritableOrgSource<Doc = D>
+{
+    let (content, found) = {
+        let doc = state.source.read(&change.doc).await
+            .map_err(|e| source_error(Some(&change.doc), &e))?;
+        let mut found = None;
+        doc.headlines(&state.parser_config, &mut |item| {
+            let matches = match (change.line, &change.id) {
+                (Some(line), _) => item.line() == line,
+                (None, Some(id)) => item.property("ID") == Some(id.as_str()),
+                (None, None) => false,
+            };
+            if matches && found.is_none() {
+                found = Some((item.line(), item.keyword().map(str::to_string)));
+            }
+        });
+        (doc.content().to_string(), found)
+    };
+    if change.version.as_ref().map(|version| *version != content_version(&content)).unwrap_or(false) {
+        return Err((StatusCode::CONFLICT, format!("{} was changed in the meantime, reload it and try again", change.doc)));
+    }
+
+    let (line, current) = match (found, change.line, &change.id) {
+        (Some(found), _, _) => found,
+        (None, Some(line), _) => return Err((StatusCode::NOT_FOUND, format!("No headline at line {line}"))),
+        (None, None, Some(id)) => return Err((StatusCode::NOT_FOUND, format!("No headline with ID {id}"))),
+        (None, None, None) => return Err((StatusCode::BAD_REQUEST, "Either line or id is required".to_string())),
+    };
+
+    let keyword = match change.keyword.as_deref() {
+        Some("") => None,
+        Some(keyword) => Some(keyword.into()),
+        None => state.parser_config.for_doc(&content).next_keyword(current.as_deref()),
+    };
+    let new_content = edit::set_keyword(&content, line, keyword.as_deref(), &state.parser_config, Local::now().naive_local())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state.source.write(&change.doc, &content, &new_content).await
+        .map_err(|e| match e {
+            WriteError::Conflict => (StatusCode::CONFLICT, e.to_string()),
+            WriteError::NotFound => (StatusCode::NOT_FOUND, e.to_string()),
+            WriteError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+
+    let version = content_version(&new_content);
+    let mut headline = None;
+    parser::doc_to_headlines(&new_content, &state.parser_config, |item| if item.line() == line {
+        headline = Some(Headline::new(Some((&change.doc, &version)), &item));
+    });
+    let headline = headline.ok_or((StatusCode::INTERNAL_SERVER_ERROR, format!("Lost headline at line {line}")))?;
+
+    Ok(StateChanged { headline })
+}
+
+async fn post_state<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                          Json(change): Json<StateChange>) -> ApiResult<StateChanged>
+where D: OrgDoc,
+      S: WritableOrgSource<Doc = D>
+{
+    change_state(&state, &change).await.map(Json).map_err(api_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_wants_json() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            wants_json(&headers)
+        };
+
+        assert!(accept("application/json"));
+        assert!(accept("application/json; charset=utf-8, */*;q=0.8"));
+        assert!(!accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!accept("text/html, application/json"));
+        assert!(!accept("*/*"));
+        assert!(!wants_json(&HeaderMap::new()));
+    }
+}
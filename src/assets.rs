@@ -0,0 +1,182 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{self, State},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{doc::{OrgDoc, OrgSource}, server::ServerState};
+
+/// Where the assets are served from.
+pub const STATIC_PATH: &str = "/static";
+
+/// The stylesheet shipped with the server, light or dark as the browser prefers.
+const BUILTIN_STYLESHEET: &str = include_str!("../assets/style.css");
+
+/// Name of the stylesheet in an asset directory that replaces the built-in one.
+pub const STYLESHEET_FILE: &str = "style.css";
+
+/// Hashed names never change their content.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// Files from the asset directory keep their names, so browsers have to check back.
+const REVALIDATE: &str = "public, max-age=300";
+
+/// The stylesheet and other files served under [`STATIC_PATH`].
+///
+/// The stylesheet is served under a name with a hash of its content, so it can
+/// be cached for good. Other files are only served from an asset directory,
+/// by their own names.
+#[derive(Clone, Debug)]
+pub struct Assets {
+    stylesheet: String,
+    stylesheet_name: String,
+    dir: Option<PathBuf>,
+}
+
+impl Default for Assets {
+    fn default() -> Self {
+        Assets::with_stylesheet(BUILTIN_STYLESHEET.to_string(), None)
+    }
+}
+
+impl Assets {
+    fn with_stylesheet(stylesheet: String, dir: Option<PathBuf>) -> Self {
+        let hash = Sha256::digest(stylesheet.as_bytes());
+        let stylesheet_name = format!("style.{}.css", hex(&hash[..6]));
+        Assets { stylesheet, stylesheet_name, dir }
+    }
+
+    /// Serves the files in `dir`, whose `style.css` replaces the built-in stylesheet.
+    pub fn with_dir(self, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a directory", dir.display())));
+        }
+        let stylesheet = match fs::read_to_string(dir.join(STYLESHEET_FILE)) {
+            Ok(stylesheet) => stylesheet,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.stylesheet,
+            Err(e) => return Err(e),
+        };
+        Ok(Assets::with_stylesheet(stylesheet, Some(dir)))
+    }
+
+    /// Replaces the built-in stylesheet with the one at `path`.
+    pub fn with_css(self, path: &Path) -> io::Result<Self> {
+        Ok(Assets::with_stylesheet(fs::read_to_string(path)?, self.dir))
+    }
+
+    /// URL of the stylesheet, which changes with its content.
+    pub fn stylesheet_url(&self) -> String {
+        format!("{STATIC_PATH}/{}", self.stylesheet_name)
+    }
+
+    /// Looks up `path` below [`STATIC_PATH`], refusing anything outside of
+    /// the asset directory or hidden.
+    fn file(&self, path: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let relative = Path::new(path);
+        for component in relative.components() {
+            let Component::Normal(name) = component else {
+                return None;
+            };
+            if name.to_str()?.starts_with('.') {
+                return None;
+            }
+        }
+        Some(dir.join(relative))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Media type of an asset by its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(crate) async fn get_static<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                                     extract::Path(path): extract::Path<String>) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let assets = &state.assets;
+    let path = path.trim_start_matches('/');
+    if path == assets.stylesheet_name {
+        return ([(header::CONTENT_TYPE, HeaderValue::from_static("text/css; charset=utf-8")),
+                 (header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE))],
+                assets.stylesheet.clone()).into_response();
+    }
+
+    let Some(file) = assets.file(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match tokio::fs::read(&file).await {
+        Ok(content) => ([(header::CONTENT_TYPE, HeaderValue::from_static(content_type(&file))),
+                         (header::CACHE_CONTROL, HeaderValue::from_static(REVALIDATE))],
+                        content).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stylesheet_name() {
+        let builtin = Assets::default();
+        assert!(builtin.stylesheet_url().starts_with("/static/style."));
+        assert_eq!(builtin.stylesheet_url(), Assets::default().stylesheet_url());
+
+        let dir = tempfile::tempdir().unwrap();
+        let css = dir.path().join("custom.css");
+        fs::write(&css, "body { color: red }").unwrap();
+        let custom = Assets::default().with_css(&css).unwrap();
+        assert_ne!(custom.stylesheet_url(), builtin.stylesheet_url());
+        assert_eq!(custom.stylesheet, "body { color: red }");
+
+        // Without a style.css the directory keeps the built-in stylesheet
+        let assets = Assets::default().with_dir(dir.path()).unwrap();
+        assert_eq!(assets.stylesheet_url(), builtin.stylesheet_url());
+        fs::write(dir.path().join(STYLESHEET_FILE), "body { color: blue }").unwrap();
+        let assets = Assets::default().with_dir(dir.path()).unwrap();
+        assert_eq!(assets.stylesheet, "body { color: blue }");
+        assert!(Assets::default().with_dir(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_asset_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Assets::default().file("logo.png"), None);
+
+        let assets = Assets::default().with_dir(dir.path()).unwrap();
+        assert_eq!(assets.file("fonts/serif.woff2"), Some(dir.path().join("fonts/serif.woff2")));
+        assert_eq!(assets.file("../secret.txt"), None);
+        assert_eq!(assets.file("/etc/passwd"), None);
+        assert_eq!(assets.file(".htpasswd"), None);
+        assert_eq!(content_type(Path::new("fonts/serif.WOFF2")), "font/woff2");
+    }
+}
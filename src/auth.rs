@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use axum::{
+    Json,
+    extract::{Form, Query, State},
+    http::{HeaderMap, HeaderValue, Request, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use lazy_static::lazy_static;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api, assets,
+    doc::{OrgDoc, OrgSource},
+    server::{self, ServerState},
+    settings::{AuthSettings, SettingsError},
+};
+
+/// Path of the login page, the only one served without authentication.
+pub const LOGIN_PATH: &str = "/login";
+
+/// Cookie holding the session of a browser that logged in.
+pub const SESSION_COOKIE: &str = "org_session";
+
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const REALM: &str = "org-server";
+
+lazy_static! {
+    /// Checked for unknown users when there are no hashes to borrow, see [`Auth::dummy_hash`].
+    static ref DUMMY_HASH: String = hash_password("not a password");
+}
+
+/// Users and API tokens allowed to access the server, and the sessions of the
+/// browsers they logged in with.
+///
+/// Users authenticate with HTTP basic auth or the login form, tokens as
+/// `Authorization: Bearer <name>.<secret>` on the JSON API. The name picks the
+/// one hash to check.
+pub struct Auth {
+    users: HashMap<String, String>,
+    /// Hashes of the tokens' secrets by their names
+    tokens: HashMap<String, String>,
+    session_ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Names of the users and tokens whose credentials were verified before, by a
+    /// hash of the credentials, as checking a password hash is deliberately slow
+    verified: Mutex<HashMap<[u8; 32], String>>,
+}
+
+struct Session {
+    username: String,
+    expires: Instant,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            users: HashMap::new(),
+            tokens: HashMap::new(),
+            session_ttl: DEFAULT_SESSION_TTL,
+            sessions: Mutex::default(),
+            verified: Mutex::default(),
+        }
+    }
+}
+
+impl Auth {
+    pub fn from_settings(settings: &AuthSettings) -> Result<Self, SettingsError> {
+        let mut auth = Auth::default().with_session_ttl(settings.session_ttl());
+        for user in &settings.users {
+            check_hash(&user.password_hash)
+                .map_err(|e| SettingsError::Invalid(format!("password_hash of auth user {}: {e}", user.username)))?;
+            auth = auth.with_user(&user.username, &user.password_hash);
+        }
+        for token in &settings.tokens {
+            if token.name.is_empty() || token.name.contains('.') {
+                return Err(SettingsError::Invalid(format!("auth token name {:?} is empty or contains a '.'", token.name)));
+            }
+            check_hash(&token.token_hash)
+                .map_err(|e| SettingsError::Invalid(format!("token_hash of auth token {}: {e}", token.name)))?;
+            auth = auth.with_token(&token.name, &token.token_hash);
+        }
+        Ok(auth)
+    }
+
+    /// Adds a user with an argon2 or bcrypt `password_hash`, see [`hash_password`].
+    pub fn with_user(mut self, username: &str, password_hash: &str) -> Self {
+        self.users.insert(username.to_string(), password_hash.to_string());
+        self
+    }
+
+    /// Adds an API token sent as `<name>.<secret>`, with an argon2 or bcrypt hash of the secret.
+    pub fn with_token(mut self, name: &str, secret_hash: &str) -> Self {
+        self.tokens.insert(name.to_string(), secret_hash.to_string());
+        self
+    }
+
+    pub fn with_session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.session_ttl = session_ttl;
+        self
+    }
+
+    /// The user with this password, if any.
+    pub async fn check_password(&self, username: &str, password: &str) -> Option<String> {
+        let key = credentials_key("user", username, password);
+        if let Some(name) = self.verified.lock().unwrap().get(&key) {
+            return Some(name.clone());
+        }
+
+        let hash = self.users.get(username);
+        let valid = verify(password, hash.map_or(self.dummy_hash(), String::as_str)).await;
+        if hash.is_none() || !valid {
+            return None;
+        }
+        self.verified.lock().unwrap().insert(key, username.to_string());
+        Some(username.to_string())
+    }
+
+    /// The name of the API token, if it's a valid one.
+    pub async fn check_token(&self, token: &str) -> Option<String> {
+        let key = credentials_key("token", "", token);
+        if let Some(name) = self.verified.lock().unwrap().get(&key) {
+            return Some(name.clone());
+        }
+
+        let (name, secret) = token.split_once('.')?;
+        let hash = self.tokens.get(name);
+        let valid = verify(secret, hash.map_or(self.dummy_hash(), String::as_str)).await;
+        if hash.is_none() || !valid {
+            return None;
+        }
+        self.verified.lock().unwrap().insert(key, name.to_string());
+        Some(name.to_string())
+    }
+
+    /// A hash to check for unknown users and tokens, so telling them apart from
+    /// known ones by the time the answer takes doesn't work. One of the configured
+    /// hashes costs the same as the others.
+    fn dummy_hash(&self) -> &str {
+        self.users.values().chain(self.tokens.values()).next().map_or(&DUMMY_HASH, String::as_str)
+    }
+
+    /// Starts a session for the user, returning its ID for the session cookie.
+    pub fn start_session(&self, username: &str) -> String {
+        let mut id = [0u8; 32];
+        getrandom::getrandom(&mut id).expect("no random numbers for the session ID");
+        let id: String = id.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(id.clone(), Session { username: username.to_string(), expires: now + self.session_ttl });
+        id
+    }
+
+    /// The user of the session, unless it's expired.
+    pub fn session_user(&self, id: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?;
+        if session.expires <= Instant::now() {
+            sessions.remove(id);
+            return None;
+        }
+        Some(session.username.clone())
+    }
+
+    pub fn end_session(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    /// The user or token the request is made by: a session cookie, basic auth, or a
+    /// bearer token when `allow_token` is set.
+    async fn authenticate(&self, headers: &HeaderMap, allow_token: bool) -> Option<String> {
+        if let Some(user) = session_id(headers).and_then(|id| self.session_user(id)) {
+            return Some(user);
+        }
+
+        let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, credentials) = authorization.split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(BASE64.decode(credentials).ok()?).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            self.check_password(username, password).await
+        } else if scheme.eq_ignore_ascii_case("bearer") && allow_token {
+            self.check_token(credentials).await
+        } else {
+            None
+        }
+    }
+}
+
+/// Hashes a password, or a token, for the configuration with argon2id.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut salt).expect("no random numbers for the salt");
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes are a valid salt");
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .expect("default argon2 parameters are valid")
+        .to_string()
+}
+
+/// Checks that `hash` is an argon2 or bcrypt hash in the usual `$…$` format.
+pub fn check_hash(hash: &str) -> Result<(), String> {
+    if is_bcrypt(hash) {
+        hash.parse::<bcrypt::HashParts>().map(|_| ()).map_err(|e| e.to_string())
+    } else if hash.starts_with("$argon2") {
+        let hash = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+        if hash.salt.is_none() || hash.hash.is_none() {
+            return Err("argon2 hash without salt or output".to_string());
+        }
+        argon2::Params::try_from(&hash).map(|_| ()).map_err(|e| e.to_string())
+    } else {
+        Err("not an argon2 or bcrypt hash".to_string())
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+/// Checks `secret` against `hash` on the blocking threads, hashing takes long on purpose.
+async fn verify(secret: &str, hash: &str) -> bool {
+    let (secret, hash) = (secret.to_string(), hash.to_string());
+    tokio::task::spawn_blocking(move || verify_hash(&secret, &hash)).await.unwrap_or(false)
+}
+
+fn verify_hash(secret: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        bcrypt::verify(secret, hash).unwrap_or(false)
+    } else {
+        PasswordHash::new(hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok())
+    }
+}
+
+fn credentials_key(kind: &str, name: &str, secret: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in [kind, name, secret] {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn session_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// Name of the authenticated user or token, added to the requests' extensions.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser(pub String);
+
+/// Middleware turning away requests without valid credentials, when the server
+/// has [`Auth`] configured.
+pub(crate) async fn require_auth<D, S, B>(State(state): State<Arc<ServerState<D, S>>>,
+                                          mut request: Request<B>, next: Next<B>) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let Some(auth) = &state.auth else {
+        return next.run(request).await;
+    };
+    // The login page needs its stylesheet, and the assets are no secret
+    let path = request.uri().path();
+    if path == LOGIN_PATH || path.starts_with(&format!("{}/", assets::STATIC_PATH)) {
+        return next.run(request).await;
+    }
+
+    // Tokens are for JSON, from the API or pages like `/query` that also answer in it
+    let api = request.uri().path().starts_with("/api/");
+    let allow_token = api || api::wants_json(request.headers());
+    match auth.authenticate(request.headers(), allow_token).await {
+        Some(user) => {
+            request.extensions_mut().insert(AuthenticatedUser(user));
+            next.run(request).await
+        },
+        None => {
+            let next_path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+            unauthorized(&state, request.headers(), api, next_path, false)
+        },
+    }
+}
+
+/// Middleware turning away requests changing something that another site made the
+/// browser send, which would come with the user's session cookie or basic auth
+/// credentials. Browsers tell the site with `Sec-Fetch-Site`, older ones with
+/// `Origin`; clients other than browsers send neither and pass.
+pub(crate) async fn reject_cross_site<D, S, B>(State(state): State<Arc<ServerState<D, S>>>,
+                                               request: Request<B>, next: Next<B>) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    if request.method().is_safe() || is_same_origin(request.headers()) {
+        return next.run(request).await;
+    }
+    let error = (StatusCode::FORBIDDEN, "Cross-site requests are not allowed".to_string());
+    if request.uri().path().starts_with("/api/") || api::wants_json(request.headers()) {
+        return api::api_error(error).into_response();
+    }
+    state.error_page(error)
+}
+
+fn is_same_origin(headers: &HeaderMap) -> bool {
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return site == "same-origin" || site == "none";
+    }
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let origin_host = origin.to_str().ok().and_then(|origin| origin.split_once("://")).map(|(_, host)| host);
+    let host = headers.get(header::HOST).and_then(|host| host.to_str().ok());
+    origin_host.is_some() && origin_host == host
+}
+
+/// A 401 response in the format the client asked for: the login form for browsers,
+/// JSON for API clients, and a basic auth challenge for everything else.
+fn unauthorized<D, S>(state: &ServerState<D, S>, headers: &HeaderMap, api: bool, next: &str, failed: bool) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let basic = HeaderValue::from_str(&format!("Basic realm=\"{REALM}\", charset=\"UTF-8\"")).unwrap();
+    if api || api::wants_json(headers) {
+        let bearer = HeaderValue::from_str(&format!("Bearer realm=\"{REALM}\"")).unwrap();
+        let mut response = (StatusCode::UNAUTHORIZED, Json(api::ApiError::new("Authentication required"))).into_response();
+        response.headers_mut().append(header::WWW_AUTHENTICATE, bearer);
+        response.headers_mut().append(header::WWW_AUTHENTICATE, basic);
+        return response;
+    }
+
+    if accepts_html(headers) {
+        // Browsers would show their own dialog for a basic auth challenge
+        let challenge = HeaderValue::from_str(
+            &format!("Cookie realm=\"{REALM}\" form-action=\"{LOGIN_PATH}\" cookie-name=\"{SESSION_COOKIE}\"")).unwrap();
+        let page = state.page().titled("Log in").render(login_form(next, failed));
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)], page).into_response();
+    }
+
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, basic)], "Authentication required\n").into_response()
+}
+
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("text/html"))
+}
+
+fn login_form(next: &str, failed: bool) -> Markup {
+    html! {
+        form.login method="post" action=(LOGIN_PATH) {
+            @if failed {
+                p.login-error { "Invalid username or password" }
+            }
+            input type="hidden" name="next" value=(next);
+            label { "Username " input type="text" name="username" autocomplete="username" required; }
+            label { "Password " input type="password" name="password" autocomplete="current-password" required; }
+            button type="submit" { "Log in" }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginParams {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginForm {
+    username: String,
+    password: String,
+    /// Page to go to after logging in, the index by default
+    next: Option<String>,
+}
+
+pub(crate) async fn get_login<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                                    Query(params): Query<LoginParams>) -> Markup
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let next = local_target(params.next);
+    state.page().titled("Log in").render(login_form(&next, false))
+}
+
+pub(crate) async fn post_login<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                                     headers: HeaderMap, Form(form): Form<LoginForm>) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let next = local_target(form.next);
+    let Some(auth) = &state.auth else {
+        return Redirect::to(&next).into_response();
+    };
+    let Some(user) = auth.check_password(&form.username, &form.password).await else {
+        return unauthorized(&state, &headers, false, &next, true);
+    };
+
+    let id = auth.start_session(&user);
+    let cookie = session_cookie(&state, &id, auth.session_ttl);
+    ([(header::SET_COOKIE, cookie)], Redirect::to(&next)).into_response()
+}
+
+pub(crate) async fn post_logout<D, S>(State(state): State<Arc<ServerState<D, S>>>, headers: HeaderMap) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    if let (Some(auth), Some(id)) = (&state.auth, session_id(&headers)) {
+        auth.end_session(id);
+    }
+    let cookie = session_cookie(&state, "", Duration::ZERO);
+    ([(header::SET_COOKIE, cookie)], Redirect::to(LOGIN_PATH)).into_response()
+}
+
+fn session_cookie<D, S>(state: &ServerState<D, S>, id: &str, max_age: Duration) -> String
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let secure = if state.https { "; Secure" } else { "" };
+    format!("{SESSION_COOKIE}={id}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{secure}", max_age.as_secs())
+}
+
+fn local_target(target: Option<String>) -> String {
+    target.filter(|target| server::is_local_path(target) && !target.starts_with(LOGIN_PATH))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, Version};
+
+    use super::*;
+
+    /// A cheap argon2 hash, the default parameters take a while in debug builds
+    fn argon2_hash(secret: &str) -> String {
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap());
+        let salt = SaltString::encode_b64(b"not very random").unwrap();
+        argon2.hash_password(secret.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    fn basic(username: &str, password: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = format!("Basic {}", BASE64.encode(format!("{username}:{password}")));
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_passwords_and_tokens() {
+        let auth = Auth::default()
+            .with_user("alice", &argon2_hash("wonderland"))
+            .with_user("bob", &bcrypt::hash("builder", 4).unwrap())
+            .with_token("phone", &argon2_hash("t0ken"));
+
+        assert_eq!(auth.check_password("alice", "wonderland").await.as_deref(), Some("alice"));
+        assert_eq!(auth.check_password("alice", "wonderland").await.as_deref(), Some("alice"));
+        assert_eq!(auth.check_password("bob", "builder").await.as_deref(), Some("bob"));
+        assert_eq!(auth.check_password("alice", "builder").await, None);
+        assert_eq!(auth.check_password("carol", "wonderland").await, None);
+        assert_eq!(auth.check_password("carol", "not a password").await, None);
+        assert_eq!(auth.check_token("phone.t0ken").await.as_deref(), Some("phone"));
+        assert_eq!(auth.check_token("t0ken").await, None);
+        assert_eq!(auth.check_token("laptop.t0ken").await, None);
+        assert_eq!(auth.check_token("phone.wonderland").await, None);
+
+        assert_eq!(auth.authenticate(&basic("alice", "wonderland"), false).await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(&basic("alice", "wrong"), false).await, None);
+
+        let mut bearer = HeaderMap::new();
+        bearer.insert(header::AUTHORIZATION, "Bearer phone.t0ken".parse().unwrap());
+        assert_eq!(auth.authenticate(&bearer, true).await.as_deref(), Some("phone"));
+        assert_eq!(auth.authenticate(&bearer, false).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let auth = Auth::default().with_user("alice", &argon2_hash("wonderland"));
+        let id = auth.start_session("alice");
+        assert_eq!(id.len(), 64);
+        assert_eq!(auth.session_user(&id).as_deref(), Some("alice"));
+        assert_eq!(auth.session_user("nonsense"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, format!("theme=dark; {SESSION_COOKIE}={id}").parse().unwrap());
+        assert_eq!(auth.authenticate(&headers, false).await.as_deref(), Some("alice"));
+
+        auth.end_session(&id);
+        assert_eq!(auth.authenticate(&headers, false).await, None);
+
+        let auth = auth.with_session_ttl(Duration::ZERO);
+        let id = auth.start_session("alice");
+        assert_eq!(auth.session_user(&id), None);
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let headers = |pairs: &[(&'static str, &'static str)]| -> HeaderMap {
+            pairs.iter().map(|(name, value)| (header::HeaderName::from_static(name), value.parse().unwrap())).collect()
+        };
+        assert!(is_same_origin(&headers(&[])));
+        assert!(is_same_origin(&headers(&[("sec-fetch-site", "same-origin"), ("origin", "https://evil.example")])));
+        assert!(!is_same_origin(&headers(&[("sec-fetch-site", "cross-site"), ("host", "notes.example")])));
+        assert!(!is_same_origin(&headers(&[("sec-fetch-site", "same-site")])));
+        assert!(is_same_origin(&headers(&[("origin", "https://notes.example"), ("host", "notes.example")])));
+        assert!(!is_same_origin(&headers(&[("origin", "https://evil.example"), ("host", "notes.example")])));
+        assert!(!is_same_origin(&headers(&[("origin", "null"), ("host", "notes.example")])));
+    }
+
+    #[test]
+    fn test_check_hash() {
+        assert!(check_hash(&argon2_hash("secret")).is_ok());
+        assert!(check_hash(&bcrypt::hash("secret", 4).unwrap()).is_ok());
+        assert!(check_hash("secret").is_err());
+        assert!(check_hash("$argon2id$nonsense").is_err());
+        assert!(check_hash("$2b$04$short").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    doc::{OrgDoc, OrgSource, SourceError, WritableOrgSource, WriteError},
+    fs_doc::IGNORE_FILE,
+    parser::{ParserConfig, TodoItem},
+};
+
+/// Files that decide which documents of their directory a source serves.
+const IGNORE_FILES: &[&str] = &[IGNORE_FILE, ".gitignore"];
+
+/// Keeps the documents of another source and their parsed headlines in memory.
+///
+/// Entries are dropped when the filesystem watcher reports a change below the
+/// source's [`OrgSource::root_dir`]. Sources without one, or where the watcher
+/// can't be set up, are polled instead: after `poll_interval` an entry is
+/// revalidated against [`OrgSource::modified`], or re-read if that is unknown.
+pub struct CachedSource<S: OrgSource> {
+    inner: S,
+    cache: Arc<Mutex<Cache>>,
+    poll_interval: Duration,
+    watcher: Option<RecommendedWatcher>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Bumped on every invalidation, so reads started before it don't store stale content
+    generation: u64,
+    list: Option<(Vec<String>, Instant)>,
+    docs: HashMap<String, (Arc<CachedEntry>, Instant)>,
+}
+
+impl Cache {
+    fn invalidate(&mut self, doc: Option<&str>) {
+        self.generation += 1;
+        self.list = None;
+        match doc {
+            Some(doc) => self.docs.retain(|key, _| {
+                key != doc && !key.strip_prefix(doc).map(|rest| rest.starts_with('/')).unwrap_or(false)
+            }),
+            None => self.docs.clear(),
+        }
+    }
+}
+
+struct CachedEntry {
+    content: String,
+    modified: Option<SystemTime>,
+    headlines: Mutex<Option<(ParserConfig, Arc<[TodoItem<'static>]>)>>,
+}
+
+pub struct CachedDoc(Arc<CachedEntry>);
+
+impl OrgDoc for CachedDoc {
+    fn content(&self) -> &str {
+        &self.0.content
+    }
+
+    fn headlines(&self, config: &ParserConfig, consumer: &mut dyn FnMut(&TodoItem)) {
+        let cached = self.0.headlines.lock().unwrap().as_ref()
+            .filter(|(cached_config, _)| cached_config == config)
+            .map(|(_, headlines)| Arc::clone(headlines));
+        let headlines = match cached {
+            Some(headlines) => headlines,
+            None => {
+                let mut headlines = Vec::new();
+                crate::parser::doc_to_headlines(&self.0.content, config, |item| headlines.push(item.into_owned()));
+                let headlines: Arc<[TodoItem<'static>]> = headlines.into();
+                *self.0.headlines.lock().unwrap() = Some((config.clone(), Arc::clone(&headlines)));
+                headlines
+            },
+        };
+
+        for item in headlines.iter() {
+            consumer(item);
+        }
+    }
+}
+
+impl<S: OrgSource> CachedSource<S> {
+    /// Watches the root directory of `inner` if it has one, polling every
+    /// `poll_interval` otherwise.
+    pub fn new(inner: S, poll_interval: Duration) -> Self {
+        let mut source = Self::polling(inner, poll_interval);
+        if let Some(root) = source.inner.root_dir() {
+            source.watcher = watch(root, Arc::clone(&source.cache)).ok();
+        }
+        source
+    }
+
+    /// Never watches, only polls every `poll_interval`.
+    pub fn polling(inner: S, poll_interval: Duration) -> Self {
+        CachedSource {
+            inner,
+            cache: Default::default(),
+            poll_interval,
+            watcher: None,
+        }
+    }
+
+    /// Whether changes are picked up from filesystem notifications rather than polling.
+    pub fn is_watching(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    fn is_fresh(&self, checked: Instant) -> bool {
+        self.is_watching() || checked.elapsed() < self.poll_interval
+    }
+}
+
+fn watch(root: &Path, cache: Arc<Mutex<Cache>>) -> notify::Result<RecommendedWatcher> {
+    let root_dir = root.to_path_buf();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let mut cache = cache.lock().unwrap();
+        match event {
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {},
+            Ok(event) if !event.need_rescan() && !event.paths.is_empty() => {
+                for path in &event.paths {
+                    match doc_path(&root_dir, path) {
+                        Some(doc) => cache.invalidate(Some(changed_docs(&doc))),
+                        None => cache.invalidate(None),
+                    }
+                }
+            },
+            _ => cache.invalidate(None),
+        }
+    })?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    Ok(watcher)
+}
+
+/// The doc path or directory whose documents a change to `doc` affects: for
+/// an ignore file, all documents next to and below it.
+fn changed_docs(doc: &str) -> &str {
+    match doc.rsplit_once('/') {
+        Some((dir, name)) if IGNORE_FILES.contains(&name) => dir,
+        _ => doc,
+    }
+}
+
+/// Maps a changed file below `root` to the doc path the source uses for it, e.g. `/tasks.org`.
+fn doc_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut doc = String::new();
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            return None;
+        };
+        doc.push('/');
+        doc.push_str(name.to_str()?);
+    }
+    (!doc.is_empty()).then_some(doc)
+}
+
+#[async_trait]
+impl<S: OrgSource> OrgSource for CachedSource<S> {
+    type Doc = CachedDoc;
+
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        let generation = {
+            let cache = self.cache.lock().unwrap();
+            if let Some((list, checked)) = &cache.list {
+                if self.is_fresh(*checked) {
+                    return Ok(list.clone());
+                }
+            }
+            cache.generation
+        };
+
+        let list = self.inner.list().await?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.list = Some((list.clone(), Instant::now()));
+        }
+        Ok(list)
+    }
+
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError> {
+        let (cached, generation) = {
+            let cache = self.cache.lock().unwrap();
+            let cached = cache.docs.get(doc).cloned();
+            if let Some((entry, checked)) = &cached {
+                if self.is_fresh(*checked) {
+                    return Ok(CachedDoc(Arc::clone(entry)));
+                }
+            }
+            (cached.map(|(entry, _)| entry), cache.generation)
+        };
+
+        let modified = self.inner.modified(doc).await;
+        let entry = match cached {
+            Some(entry) if modified.is_some() && entry.modified == modified => entry,
+            _ => {
+                let content = self.inner.read(doc).await?.content().to_string();
+                Arc::new(CachedEntry { content, modified, headlines: Mutex::new(None) })
+            },
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.docs.insert(doc.to_string(), (Arc::clone(&entry), Instant::now()));
+        }
+        Ok(CachedDoc(entry))
+    }
+
+    fn doc_name(&self, doc: &str) -> String {
+        self.inner.doc_name(doc)
+    }
+
+    fn root_dir(&self) -> Option<&Path> {
+        self.inner.root_dir()
+    }
+
+    async fn modified(&self, doc: &str) -> Option<SystemTime> {
+        self.inner.modified(doc).await
+    }
+
+    /// Only known while watching, polled changes don't bump it.
+    fn generation(&self) -> Option<u64> {
+        self.is_watching().then(|| self.cache.lock().unwrap().generation)
+    }
+}
+
+#[async_trait]
+impl<S: WritableOrgSource> WritableOrgSource for CachedSource<S> {
+    async fn write(&self, doc: &str, expected: &str, content: &str) -> Result<(), WriteError> {
+        let result = self.inner.write(doc, expected, content).await;
+        self.cache.lock().unwrap().invalidate(Some(doc));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::atomic::{AtomicUsize, Ordering}};
+
+    use super::*;
+    use crate::doc::{StaticOrgDoc, StaticOrgSource};
+    use tempfile::tempdir;
+
+    /// Counts the reads hitting the wrapped source.
+    struct CountingSource {
+        inner: StaticOrgSource,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl OrgSource for CountingSource {
+        type Doc = StaticOrgDoc;
+
+        async fn list(&self) -> Result<Vec<String>, SourceError> {
+            self.inner.list().await
+        }
+
+        async fn read(&self, doc: &str) -> Result<StaticOrgDoc, SourceError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.inner.read(doc).await
+        }
+    }
+
+    fn headings(doc: &impl OrgDoc) -> Vec<String> {
+        let mut headings = Vec::new();
+        doc.items(&ParserConfig::default(), &mut |item| headings.push(item.heading().to_string()));
+        headings
+    }
+
+    #[tokio::test]
+    async fn test_polling_cache() {
+        let mut inner = StaticOrgSource::default();
+        inner.add_doc("tasks.org", "* TODO Cached");
+        let source = CachedSource::new(CountingSource { inner, reads: AtomicUsize::new(0) }, Duration::from_secs(60));
+        assert!(!source.is_watching());
+
+        for _ in 0..3 {
+            let doc = source.read("/tasks.org").await.unwrap();
+            assert_eq!(headings(&doc), ["Cached"]);
+        }
+        assert_eq!(source.inner.reads.load(Ordering::Relaxed), 1);
+        assert!(source.read("/missing.org").await.is_err());
+
+        let source = CachedSource::polling(source.inner, Duration::ZERO);
+        source.read("/tasks.org").await.unwrap();
+        source.read("/tasks.org").await.unwrap();
+        assert_eq!(source.inner.reads.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_write_through() {
+        let mut inner = StaticOrgSource::default();
+        inner.add_doc("tasks.org", "* TODO Task");
+        let source = CachedSource::new(inner, Duration::from_secs(60));
+
+        assert_eq!(headings(&source.read("/tasks.org").await.unwrap()), ["Task"]);
+        source.write("/tasks.org", "* TODO Task", "* TODO Changed").await.unwrap();
+        assert_eq!(headings(&source.read("/tasks.org").await.unwrap()), ["Changed"]);
+    }
+
+    #[tokio::test]
+    async fn test_watched_directory() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("tasks.org"), "* TODO First").unwrap();
+        let source = CachedSource::new(crate::fs_doc::FilesystemSource::new(&root), Duration::from_secs(60));
+        assert!(source.is_watching());
+
+        assert_eq!(source.list().await.unwrap(), ["/tasks.org"]);
+        assert_eq!(headings(&source.read("/tasks.org").await.unwrap()), ["First"]);
+
+        let source = &source;
+        fs::write(root.join("tasks.org"), "* TODO Second").unwrap();
+        fs::write(root.join("more.org"), "").unwrap();
+        eventually(|| async move { headings(&source.read("/tasks.org").await.unwrap()) == ["Second"] }).await;
+        eventually(|| async move { source.list().await.unwrap().len() == 2 }).await;
+
+        fs::remove_file(root.join("tasks.org")).unwrap();
+        eventually(|| async move { source.read("/tasks.org").await.is_err() }).await;
+    }
+
+    #[tokio::test]
+    async fn test_polling_directory() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("tasks.org"), "* TODO First").unwrap();
+        let source = CachedSource::polling(crate::fs_doc::FilesystemSource::new(&root), Duration::ZERO);
+
+        assert_eq!(headings(&source.read("/tasks.org").await.unwrap()), ["First"]);
+        let file = fs::File::options().write(true).open(root.join("tasks.org")).unwrap();
+        file.set_len(0).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(headings(&source.read("/tasks.org").await.unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_doc_path() {
+        let root = Path::new("/srv/org");
+        assert_eq!(doc_path(root, Path::new("/srv/org/tasks.org")).as_deref(), Some("/tasks.org"));
+        assert_eq!(doc_path(root, Path::new("/srv/org/projects/a.org")).as_deref(), Some("/projects/a.org"));
+        assert_eq!(doc_path(root, Path::new("/srv/org")), None);
+        assert_eq!(doc_path(root, Path::new("/elsewhere/tasks.org")), None);
+    }
+
+    #[test]
+    fn test_changed_docs() {
+        assert_eq!(changed_docs("/tasks.org"), "/tasks.org");
+        assert_eq!(changed_docs("/projects/.orgignore"), "/projects");
+        assert_eq!(changed_docs("/projects/.gitignore"), "/projects");
+        assert_eq!(changed_docs("/.orgignore"), "");
+    }
+
+    #[tokio::test]
+    async fn test_watched_ignore_file() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("projects")).unwrap();
+        fs::write(root.join("projects/old.org"), "* TODO Old").unwrap();
+        fs::write(root.join("tasks.org"), "* TODO Task").unwrap();
+        let source = CachedSource::new(crate::fs_doc::FilesystemSource::new(&root), Duration::from_secs(60));
+        assert!(source.is_watching());
+        assert_eq!(headings(&source.read("/projects/old.org").await.unwrap()), ["Old"]);
+        assert_eq!(headings(&source.read("/tasks.org").await.unwrap()), ["Task"]);
+
+        let source = &source;
+        fs::write(root.join(".orgignore"), "old.org\n").unwrap();
+        eventually(|| async move { matches!(source.read("/projects/old.org").await, Err(SourceError::NotFound)) }).await;
+        assert_eq!(source.list().await.unwrap(), ["/tasks.org"]);
+    }
+
+    #[test]
+    fn test_invalidate_directory() {
+        let mut cache = Cache::default();
+        let entry = Arc::new(CachedEntry { content: String::new(), modified: None, headlines: Mutex::new(None) });
+        for doc in ["/projects/a.org", "/projects.org", "/tasks.org"] {
+            cache.docs.insert(doc.to_string(), (Arc::clone(&entry), Instant::now()));
+        }
+
+        cache.invalidate(Some("/projects"));
+        let mut docs: Vec<&String> = cache.docs.keys().collect();
+        docs.sort();
+        assert_eq!(docs, ["/projects.org", "/tasks.org"]);
+    }
+
+    async fn eventually<F: std::future::Future<Output = bool>>(condition: impl Fn() -> F) {
+        for _ in 0..100 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition not met in time");
+    }
+}
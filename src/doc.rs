@@ -1,41 +1,179 @@
 
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, fmt, path::Path, sync::{Arc, RwLock}, time::SystemTime};
 
 
 use async_trait::async_trait;
 
+use crate::{edit::content_version, parser::{self, ParserConfig, TodoItem}};
+
 pub trait OrgDoc {
     fn content(&self) -> &str;
+
+    /// Calls `consumer` with every headline of the document, see [`parser::doc_to_headlines`].
+    fn headlines(&self, config: &ParserConfig, consumer: &mut dyn FnMut(&TodoItem)) {
+        parser::doc_to_headlines(self.content(), config, |item| consumer(&item));
+    }
+
+    /// Calls `consumer` with every headline that has a TODO keyword.
+    fn items(&self, config: &ParserConfig, consumer: &mut dyn FnMut(&TodoItem)) {
+        self.headlines(config, &mut |item| if item.keyword().is_some() {
+            consumer(item)
+        });
+    }
 }
 
 #[async_trait]
 pub trait OrgSource: Send + Sync {
     type Doc: OrgDoc;
 
-    async fn list(&self) -> Vec<String>;
-    async fn read(&self, doc: &str) -> Result<Self::Doc, ()>;
+    async fn list(&self) -> Result<Vec<String>, SourceError>;
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError>;
 
     fn doc_name(&self, doc: &str) -> String {
         String::from(doc)
     }
+
+    /// Directory the documents live in, with doc paths relative to it. Lets
+    /// [`crate::cache::CachedSource`] watch it for changes.
+    fn root_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// When `doc` was last changed, if the source can tell.
+    async fn modified(&self, _doc: &str) -> Option<SystemTime> {
+        None
+    }
+
+    /// A counter that changes whenever any document might have, for sources
+    /// that can tell. Lets what is built from all documents be rebuilt only then.
+    fn generation(&self) -> Option<u64> {
+        None
+    }
+
+    /// Size, modification time and ETag of `doc`. Unless the source knows
+    /// better, the document is read to hash its content.
+    async fn metadata(&self, doc: &str) -> Result<DocMetadata, SourceError> {
+        let modified = self.modified(doc).await;
+        let doc = self.read(doc).await?;
+        Ok(DocMetadata {
+            size: doc.content().len() as u64,
+            modified,
+            etag: content_version(doc.content()),
+        })
+    }
+}
+
+/// What [`OrgSource::metadata`] tells about a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocMetadata {
+    /// Size of the content in bytes
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Opaque tag that changes whenever the content does, without the quotes
+    /// of the `ETag` header
+    pub etag: String,
+}
+
+/// Why an [`OrgSource`] couldn't list or read documents.
+#[derive(Debug)]
+pub enum SourceError {
+    NotFound,
+    /// The source refused access to the document
+    Forbidden,
+    /// The doc path isn't one the source could ever serve, e.g. outside its root
+    InvalidPath,
+    Io(std::io::Error),
+    /// A remote source failed or didn't answer as expected
+    Remote(String),
+    /// The document isn't valid text
+    Parse(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::NotFound => write!(f, "The document doesn't exist"),
+            SourceError::Forbidden => write!(f, "Access to the document is not allowed"),
+            SourceError::InvalidPath => write!(f, "Not a valid document path"),
+            SourceError::Io(e) => write!(f, "Cannot read the document: {e}"),
+            SourceError::Remote(e) => write!(f, "The remote source failed: {e}"),
+            SourceError::Parse(e) => write!(f, "Cannot parse the document: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<std::io::Error> for SourceError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => SourceError::NotFound,
+            std::io::ErrorKind::PermissionDenied => SourceError::Forbidden,
+            std::io::ErrorKind::InvalidData => SourceError::Parse(e.to_string()),
+            _ => SourceError::Io(e),
+        }
+    }
+}
+
+/// Why a [`WritableOrgSource`] didn't write a document.
+#[derive(Debug)]
+pub enum WriteError {
+    /// The document changed since it was read
+    Conflict,
+    NotFound,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Conflict => write!(f, "The document was changed in the meantime"),
+            WriteError::NotFound => write!(f, "The document doesn't exist"),
+            WriteError::Io(e) => write!(f, "Cannot write the document: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl From<std::io::Error> for WriteError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => WriteError::NotFound,
+            _ => WriteError::Io(e),
+        }
+    }
+}
+
+#[async_trait]
+pub trait WritableOrgSource: OrgSource {
+    /// Replaces the content of `doc`, but only while it is still `expected`,
+    /// so edits made elsewhere since it was read are never overwritten.
+    async fn write(&self, doc: &str, expected: &str, content: &str) -> Result<(), WriteError>;
 }
 
 #[derive(Clone)]
-pub struct StaticOrgDoc(pub &'static str);
+pub struct StaticOrgDoc(Arc<str>);
+
+impl StaticOrgDoc {
+    pub fn new(content: &str) -> Self {
+        StaticOrgDoc(Arc::from(content))
+    }
+}
 
 impl OrgDoc for StaticOrgDoc {
     fn content(&self) -> &str {
-        self.0
+        &self.0
     }
 }
 
 #[derive(Default)]
-pub struct StaticOrgSource(HashMap<String, StaticOrgDoc>);
+pub struct StaticOrgSource(RwLock<HashMap<String, StaticOrgDoc>>);
 
 impl StaticOrgSource {
     #[allow(dead_code)]
-    pub fn add_doc(&mut self, name: &str, content: &'static str) {
-        self.0.insert(name.to_string(), StaticOrgDoc(content));
+    pub fn add_doc(&mut self, name: &str, content: &str) {
+        self.0.get_mut().unwrap().insert(name.to_string(), StaticOrgDoc::new(content));
     }
 }
 
@@ -43,13 +181,63 @@ impl StaticOrgSource {
 impl OrgSource for StaticOrgSource {
     type Doc = StaticOrgDoc;
 
-    async fn list(&self) -> Vec<String> {
-        self.0.keys().map(|key| format!("/{key}")).collect()
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        Ok(self.0.read().unwrap().keys().map(|key| format!("/{key}")).collect())
+    }
+
+    async fn read(&self, doc: &str) -> Result<StaticOrgDoc, SourceError> {
+        self.0.read().unwrap().get(doc.trim_start_matches('/')).cloned().ok_or(SourceError::NotFound)
     }
+}
+
+#[async_trait]
+impl WritableOrgSource for StaticOrgSource {
+    async fn write(&self, doc: &str, expected: &str, content: &str) -> Result<(), WriteError> {
+        let mut docs = self.0.write().unwrap();
+        let current = docs.get_mut(doc.trim_start_matches('/')).ok_or(WriteError::NotFound)?;
+        if current.content() != expected {
+            return Err(WriteError::Conflict);
+        }
+        *current = StaticOrgDoc::new(content);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_static_write() {
+        let mut source = StaticOrgSource::default();
+        source.add_doc("tasks.org", "* TODO Task");
+
+        source.write("/tasks.org", "* TODO Task", "* DONE Task").await.unwrap();
+        assert_eq!(source.read("/tasks.org").await.unwrap().content(), "* DONE Task");
+        assert!(matches!(source.write("/tasks.org", "* TODO Task", "* NEXT Task").await, Err(WriteError::Conflict)));
+        assert!(matches!(source.write("/other.org", "", "").await, Err(WriteError::NotFound)));
+        assert_eq!(source.read("/tasks.org").await.unwrap().content(), "* DONE Task");
+    }
+
+    #[tokio::test]
+    async fn test_default_metadata() {
+        let mut source = StaticOrgSource::default();
+        source.add_doc("tasks.org", "* TODO Task");
+
+        let metadata = source.metadata("/tasks.org").await.unwrap();
+        assert_eq!(metadata, DocMetadata { size: 11, modified: None, etag: content_version("* TODO Task") });
+        source.write("/tasks.org", "* TODO Task", "* DONE Task").await.unwrap();
+        assert_ne!(source.metadata("/tasks.org").await.unwrap().etag, metadata.etag);
+        assert!(matches!(source.metadata("/missing.org").await, Err(SourceError::NotFound)));
+    }
+
+    #[test]
+    fn test_source_error_from_io() {
+        use std::io::{Error, ErrorKind};
 
-    async fn read(&self, doc: &str) -> Result<StaticOrgDoc, ()> {
-        let path = Path::new(doc).file_name().ok_or(())?;
-        let doc = path.to_str().ok_or(())?;
-        self.0.get(doc).cloned().ok_or(())
+        assert!(matches!(SourceError::from(Error::from(ErrorKind::NotFound)), SourceError::NotFound));
+        assert!(matches!(SourceError::from(Error::from(ErrorKind::PermissionDenied)), SourceError::Forbidden));
+        assert!(matches!(SourceError::from(Error::from(ErrorKind::InvalidData)), SourceError::Parse(_)));
+        assert!(matches!(SourceError::from(Error::from(ErrorKind::Interrupted)), SourceError::Io(_)));
     }
 }
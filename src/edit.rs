@@ -0,0 +1,187 @@
+use std::fmt;
+
+use chrono::{NaiveDateTime, Timelike};
+use sha2::{Digest, Sha256};
+
+use crate::{parser::ParserConfig, timestamp::{Planning, Timestamp}};
+
+#[derive(Debug, PartialEq)]
+pub enum EditError {
+    /// The line doesn't exist or isn't a headline
+    NoHeadline(usize),
+    UnknownKeyword(String),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::NoHeadline(line) => write!(f, "No headline at line {line}"),
+            EditError::UnknownKeyword(keyword) => write!(f, "Unknown TODO keyword: {keyword}"),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+/// Short fingerprint of a document's content, to tell whether it changed
+/// between reading it and writing it back. It's also used in ETags, so it
+/// must stay the same across builds and Rust releases.
+pub fn content_version(content: &str) -> String {
+    Sha256::digest(content.as_bytes())[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Replaces the keyword of the headline at `line` (starting at 1) with `keyword`,
+/// removing it for `None`. The document's own `#+TODO:` keywords take precedence.
+/// Entering a done state adds a `CLOSED:` timestamp for `now` to the planning
+/// line, leaving one removes it.
+pub fn set_keyword(content: &str, line: usize, keyword: Option<&str>,
+                   config: &ParserConfig, now: NaiveDateTime) -> Result<String, EditError> {
+    let config = &*config.for_doc(content);
+    if let Some(keyword) = keyword.filter(|keyword| !config.is_keyword(keyword)) {
+        return Err(EditError::UnknownKeyword(keyword.to_string()));
+    }
+
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(String::from).collect();
+    let index = line.checked_sub(1).filter(|index| *index < lines.len()).ok_or(EditError::NoHeadline(line))?;
+    let (text, ending) = split_line_ending(&lines[index]);
+
+    let level = text.find(|c| c != '*').unwrap_or(text.len());
+    let rest = &text[level..];
+    if level == 0 || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return Err(EditError::NoHeadline(line));
+    }
+    let rest = rest.trim_start();
+    let first_word = rest.split([' ', '\t']).next().unwrap_or_default();
+    let (current, title) = if config.is_keyword(first_word) {
+        (Some(first_word), rest[first_word.len()..].trim_start())
+    } else {
+        (None, rest)
+    };
+    let was_done = current.map(|keyword| config.is_done(keyword)).unwrap_or(false);
+    let is_done = keyword.map(|keyword| config.is_done(keyword)).unwrap_or(false);
+
+    let mut headline = "*".repeat(level);
+    for part in [keyword.unwrap_or_default(), title] {
+        if !part.is_empty() {
+            headline.push(' ');
+            headline.push_str(part);
+        }
+    }
+    let ending = ending.to_string();
+    headline.push_str(&ending);
+    lines[index] = headline;
+
+    let planning_index = Some(index + 1)
+        .filter(|i| lines.get(*i).and_then(|line| Planning::parse(split_line_ending(line).0)).is_some());
+    if is_done && !was_done {
+        let closed = format!("CLOSED: {}", closed_timestamp(now));
+        match planning_index {
+            Some(i) => {
+                let (planning, planning_ending) = split_line_ending(&lines[i]);
+                let indent = &planning[..planning.len() - planning.trim_start().len()];
+                lines[i] = format!("{indent}{closed} {}{planning_ending}", planning.trim_start());
+            },
+            None => {
+                if ending.is_empty() {
+                    lines[index].push('\n');
+                }
+                let planning_ending = if ending.is_empty() { "" } else { &ending };
+                lines.insert(index + 1, format!("{closed}{planning_ending}"));
+            },
+        }
+    } else if was_done && !is_done {
+        if let Some(i) = planning_index {
+            let (planning, planning_ending) = split_line_ending(&lines[i]);
+            let remaining = remove_closed(planning);
+            if remaining.trim().is_empty() {
+                lines.remove(i);
+            } else {
+                lines[i] = format!("{remaining}{planning_ending}");
+            }
+        }
+    }
+
+    Ok(lines.concat())
+}
+
+fn split_line_ending(line: &str) -> (&str, &str) {
+    let text = line.trim_end_matches(['\n', '\r']);
+    (text, &line[text.len()..])
+}
+
+fn closed_timestamp(now: NaiveDateTime) -> Timestamp {
+    Timestamp {
+        active: false,
+        date: now.date(),
+        time: now.time().with_second(0).and_then(|time| time.with_nanosecond(0)),
+        end: None,
+        repeater: None,
+        delay: None,
+    }
+}
+
+/// Drops the `CLOSED: [...]` part of a planning line.
+fn remove_closed(planning: &str) -> String {
+    let Some(start) = planning.find("CLOSED:") else {
+        return planning.to_string();
+    };
+    let after = &planning[start + "CLOSED:".len()..];
+    let timestamp_end = after.find(']').map(|i| i + 1).unwrap_or(after.len());
+    let rest = after[timestamp_end..].trim_start();
+
+    format!("{}{rest}", &planning[..start]).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn now() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 29).unwrap().and_hms_opt(10, 15, 42).unwrap()
+    }
+
+    fn config() -> ParserConfig {
+        ParserConfig::from_sequences(&["TODO NEXT | DONE CLND"]).unwrap()
+    }
+
+    #[test]
+    fn test_change_keyword() {
+        let doc = "* TODO Task :tag:\nbody\n";
+        assert_eq!(set_keyword(doc, 1, Some("NEXT"), &config(), now()).unwrap(), "* NEXT Task :tag:\nbody\n");
+        assert_eq!(set_keyword(doc, 1, None, &config(), now()).unwrap(), "* Task :tag:\nbody\n");
+        assert_eq!(set_keyword("** Plain\n", 1, Some("TODO"), &config(), now()).unwrap(), "** TODO Plain\n");
+    }
+
+    #[test]
+    fn test_closed_timestamp() {
+        let doc = "* NEXT Task\r\n  SCHEDULED: <2024-01-29 Mon>\r\n";
+        let done = set_keyword(doc, 1, Some("DONE"), &config(), now()).unwrap();
+        assert_eq!(done, "* DONE Task\r\n  CLOSED: [2024-01-29 Mon 10:15] SCHEDULED: <2024-01-29 Mon>\r\n");
+        assert_eq!(set_keyword(&done, 1, Some("CLND"), &config(), now()).unwrap(),
+                   "* CLND Task\r\n  CLOSED: [2024-01-29 Mon 10:15] SCHEDULED: <2024-01-29 Mon>\r\n");
+        assert_eq!(set_keyword(&done, 1, Some("TODO"), &config(), now()).unwrap(),
+                   "* TODO Task\r\n  SCHEDULED: <2024-01-29 Mon>\r\n");
+
+        let done = set_keyword("* TODO Task", 1, Some("DONE"), &config(), now()).unwrap();
+        assert_eq!(done, "* DONE Task\nCLOSED: [2024-01-29 Mon 10:15]");
+        assert_eq!(set_keyword(&done, 1, None, &config(), now()).unwrap(), "* Task\n");
+    }
+
+    #[test]
+    fn test_invalid_edits() {
+        let doc = "Text\n* TODO Task\n";
+        assert_eq!(set_keyword(doc, 1, Some("DONE"), &config(), now()), Err(EditError::NoHeadline(1)));
+        assert_eq!(set_keyword(doc, 3, Some("DONE"), &config(), now()), Err(EditError::NoHeadline(3)));
+        assert_eq!(set_keyword(doc, 0, Some("DONE"), &config(), now()), Err(EditError::NoHeadline(0)));
+        assert_eq!(set_keyword(doc, 2, Some("WAIT"), &config(), now()), Err(EditError::UnknownKeyword("WAIT".to_string())));
+    }
+
+    #[test]
+    fn test_content_version() {
+        assert_eq!(content_version("* TODO Task"), content_version("* TODO Task"));
+        assert_ne!(content_version("* TODO Task"), content_version("* DONE Task"));
+        assert_eq!(content_version("* TODO Task"), "d0fc972adb4f99b4");
+    }
+}
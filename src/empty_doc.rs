@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use crate::doc::{OrgSource, OrgDoc};
+use crate::doc::{OrgSource, OrgDoc, SourceError};
 
 pub struct EmptyOrgSource;
 pub struct EmptyDoc;
@@ -9,12 +9,12 @@ pub struct EmptyDoc;
 impl OrgSource for EmptyOrgSource {
     type Doc = EmptyDoc;
 
-    async fn list(&self) -> Vec<String> {
-        vec![]
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        Ok(vec![])
     }
 
-    async fn read(&self, _: &str) -> Result<EmptyDoc, ()> {
-        Err(())
+    async fn read(&self, _: &str) -> Result<EmptyDoc, SourceError> {
+        Err(SourceError::NotFound)
     }
 }
 
@@ -1,13 +1,26 @@
-use std::path::Path;
+use std::{path::{Component, Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
 use async_trait::async_trait;
-use tokio::{fs::{read_dir, File as AsyncFile}, io::AsyncReadExt};
-use tokio_stream::wrappers::ReadDirStream;
-use futures_util::stream::StreamExt;
+use ignore::{Match, WalkBuilder, gitignore::{Gitignore, GitignoreBuilder}};
+use tokio::{fs::{self, metadata, read_to_string, File as AsyncFile}, io::AsyncReadExt};
 
-use crate::doc::{OrgDoc, OrgSource};
+use crate::{
+    doc::{DocMetadata, OrgDoc, OrgSource, SourceError, WritableOrgSource, WriteError},
+    edit::content_version,
+};
 
-pub struct FilesystemSource<'a>(&'a Path);
+/// Per-directory ignore file, with the same syntax as `.gitignore`.
+pub const IGNORE_FILE: &str = ".orgignore";
+
+/// Top level directories whose documents would be shadowed by the server's
+/// own routes, e.g. `/todo/:keyword` or `/static/*path`.
+const RESERVED_DIRS: &[&str] = &["api", "static", "todo"];
+
+pub struct FilesystemSource {
+    root: PathBuf,
+    max_depth: Option<usize>,
+    ignore: Gitignore,
+}
 
 pub struct FilesystemDoc(String);
 
@@ -17,55 +30,209 @@ impl OrgDoc for FilesystemDoc {
     }
 }
 
-impl<'a> FilesystemSource<'a> {
-    pub fn new(path: &'a Path) -> Self {
-        assert!(path.is_absolute());
-        Self(path)
+impl FilesystemSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let root = path.into();
+        assert!(root.is_absolute());
+        Self { root, max_depth: None, ignore: Gitignore::empty() }
+    }
+
+    /// How many levels of subdirectories to descend into, `Some(0)` only
+    /// serves the files at the top level.
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Skips files and directories matching any of the `.gitignore` style
+    /// `patterns`, in addition to what the `.orgignore` files exclude.
+    pub fn with_ignore(mut self, patterns: &[impl AsRef<str>]) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(&self.root);
+        for pattern in patterns {
+            builder.add_line(None, pattern.as_ref())?;
+        }
+        self.ignore = builder.build()?;
+        Ok(self)
+    }
+
+    /// Maps a doc path like `/projects/foo.org` to the file, refusing anything
+    /// that `list` wouldn't return or that points outside of the root.
+    async fn resolve(&self, doc: &str) -> Result<PathBuf, SourceError> {
+        let relative = Path::new(doc.strip_prefix('/').unwrap_or(doc));
+        if relative.extension().map(|ext| ext != "org").unwrap_or(true) {
+            return Err(SourceError::InvalidPath);
+        }
+        let mut depth = 0;
+        for component in relative.components() {
+            let Component::Normal(name) = component else {
+                return Err(SourceError::InvalidPath);
+            };
+            if name.to_str().map(|name| name.starts_with('.')).unwrap_or(true) {
+                return Err(SourceError::InvalidPath);
+            }
+            depth += 1;
+        }
+        if self.max_depth.map(|max_depth| depth > max_depth + 1).unwrap_or(false) {
+            return Err(SourceError::NotFound);
+        }
+        if depth > 1 && relative.iter().next().and_then(|dir| dir.to_str()).is_some_and(is_reserved) {
+            return Err(SourceError::NotFound);
+        }
+
+        let path = self.root.join(relative);
+        let mut ignored = self.ignore.matched_path_or_any_parents(relative, false).is_ignore();
+        for dir in path.ancestors().skip(1).take(depth).collect::<Vec<_>>().into_iter().rev() {
+            let Ok(content) = read_to_string(dir.join(IGNORE_FILE)).await else {
+                continue;
+            };
+            let invalid = |e: ignore::Error| SourceError::Parse(format!("{}: {e}", dir.join(IGNORE_FILE).display()));
+            let mut builder = GitignoreBuilder::new(dir);
+            for line in content.lines() {
+                builder.add_line(None, line).map_err(invalid)?;
+            }
+            match builder.build().map_err(invalid)?.matched_path_or_any_parents(&path, false) {
+                Match::None => {},
+                matched => ignored = matched.is_ignore(),
+            }
+        }
+
+        if ignored {
+            return Err(SourceError::NotFound);
+        }
+        // Symlinks may lead anywhere, only follow those that stay inside
+        let path = fs::canonicalize(&path).await?;
+        if !path.starts_with(fs::canonicalize(&self.root).await?) {
+            return Err(SourceError::InvalidPath);
+        }
+        Ok(path)
     }
 }
 
+fn is_reserved(dir: &str) -> bool {
+    RESERVED_DIRS.contains(&dir)
+}
+
 #[async_trait]
-impl OrgSource for FilesystemSource<'_> {
+impl OrgSource for FilesystemSource {
     type Doc = FilesystemDoc;
 
-    async fn list(&self) -> Vec<String> {
-        if let Ok(contents) = read_dir(self.0).await {
-            let mut res = Vec::new();
-            let mut stream = ReadDirStream::new(contents);
-            while let Some(Ok(file)) = stream.next().await {
-                if file.path().extension().map(|ext| ext == "org").unwrap_or(false) {
-                    if let Some(filename) = file.path().file_name().and_then(|s| s.to_str()) {
-                        res.push(format!("/{filename}"));
-                    }
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        // The walk skips what it can't read, but a missing or unreadable root
+        // means the source is broken, not that some document is missing
+        let _ = fs::read_dir(&self.root).await.map_err(SourceError::Io)?;
+
+        let ignore = self.ignore.clone();
+        let mut walker = WalkBuilder::new(&self.root);
+        walker.standard_filters(false)
+            .hidden(true)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .max_depth(self.max_depth.map(|max_depth| max_depth + 1))
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if is_dir && entry.depth() == 1 && entry.file_name().to_str().is_some_and(is_reserved) {
+                    return false;
                 }
-            }
+                !ignore.matched(entry.path(), is_dir).is_ignore()
+            });
 
-            res
-        } else {
-            Vec::new()
-        }
+        let root = self.root.to_path_buf();
+        let canonical_root = fs::canonicalize(&self.root).await.map_err(SourceError::Io)?;
+        let walk = move || {
+            let mut docs: Vec<String> = walker.build()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().map(|t| !t.is_dir()).unwrap_or(false))
+                .filter(|entry| entry.path().extension().map(|ext| ext == "org").unwrap_or(false))
+                .filter(|entry| !entry.path_is_symlink() || std::fs::canonicalize(entry.path())
+                    .map(|target| target.starts_with(&canonical_root))
+                    .unwrap_or(false))
+                .filter_map(|entry| {
+                    let relative = entry.path().strip_prefix(&root).ok()?;
+                    let segments: Option<Vec<&str>> = relative.components()
+                        .map(|component| component.as_os_str().to_str())
+                        .collect();
+                    Some(format!("/{}", segments?.join("/")))
+                })
+                .collect();
+            docs.sort();
+            docs
+        };
+
+        tokio::task::spawn_blocking(walk).await
+            .map_err(|e| SourceError::Io(std::io::Error::other(e)))
     }
 
-    async fn read(&self, doc: &str) -> Result<Self::Doc, ()> {
-        let doc = Path::new(doc).file_name().ok_or(())?;
-        let path = self.0.join(doc);
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError> {
+        let path = self.resolve(doc).await?;
         let mut content = String::new();
-        AsyncFile::open(path).await.map_err(|_| ())?
-            .read_to_string(&mut content).await.map_err(|_| ())?;
+        AsyncFile::open(path).await?
+            .read_to_string(&mut content).await?;
 
         Ok(FilesystemDoc(content))
     }
 
     fn doc_name(&self, doc: &str) -> String {
-        Path::new(doc).file_name()
-            .map(|s| s.to_str().expect("Path has to be a valid string").to_string())
-            .expect("Has to be a vaild name")
+        doc.strip_prefix('/').unwrap_or(doc).to_string()
+    }
+
+    fn root_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    async fn modified(&self, doc: &str) -> Option<SystemTime> {
+        metadata(self.resolve(doc).await.ok()?).await.ok()?.modified().ok()
+    }
+
+    /// Takes size and modification time from the file system and tags the
+    /// document with both, without reading it.
+    async fn metadata(&self, doc: &str) -> Result<DocMetadata, SourceError> {
+        let path = self.resolve(doc).await?;
+        let file = metadata(&path).await?;
+        if !file.is_file() {
+            return Err(SourceError::NotFound);
+        }
+        let modified = file.modified().ok();
+        let etag = match modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()) {
+            Some(since_epoch) => format!("{:x}-{:x}", since_epoch.as_nanos(), file.len()),
+            None => content_version(&read_to_string(&path).await?),
+        };
+        Ok(DocMetadata { size: file.len(), modified, etag })
+    }
+}
+
+#[async_trait]
+impl WritableOrgSource for FilesystemSource {
+    /// Writes to a temporary file next to the document and renames it over
+    /// the document, so readers never see it half written.
+    async fn write(&self, doc: &str, expected: &str, content: &str) -> Result<(), WriteError> {
+        // Resolves symlinks, so this replaces the target and not the link
+        let path = self.resolve(doc).await.map_err(|_| WriteError::NotFound)?;
+        let file_name = path.file_name().and_then(|name| name.to_str()).ok_or(WriteError::NotFound)?;
+        let temp_path = path.with_file_name(format!(".{file_name}.org-server.tmp"));
+
+        if read_to_string(&path).await? != expected {
+            return Err(WriteError::Conflict);
+        }
+        let permissions = metadata(&path).await?.permissions();
+        let result = async {
+            fs::write(&temp_path, content).await?;
+            fs::set_permissions(&temp_path, permissions).await?;
+            if read_to_string(&path).await? != expected {
+                return Err(WriteError::Conflict);
+            }
+            fs::rename(&temp_path, &path).await?;
+            Ok(())
+        }.await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, collections::BTreeSet};
+    use std::{fs::{self, File}, collections::BTreeSet};
     use std::io::Write;
 
     use super::*;
@@ -96,7 +263,7 @@ mod tests {
     async fn test_no_files_found() {
         let dir = tempdir().unwrap();
         let source = FilesystemSource::new(dir.path());
-        assert!(source.list().await.is_empty());
+        assert!(source.list().await.unwrap().is_empty());
     }
 
     #[tokio::test]
@@ -106,7 +273,7 @@ mod tests {
         make_files(dir.path(), &["tasks.pdf", "events.pdf"]);
 
         let source = FilesystemSource::new(dir.path());
-        let docs: BTreeSet<String> = source.list().await.iter().cloned().collect();
+        let docs: BTreeSet<String> = source.list().await.unwrap().iter().cloned().collect();
         assert_eq!(docs, set!("/tasks.org", "/events.org"));
     }
 
@@ -139,13 +306,154 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_subdirectories() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("projects/old")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        make_files(dir.path(), &["tasks.org", "projects/foo.org", "projects/old/bar.org", ".git/x.org"]);
+
+        let source = FilesystemSource::new(dir.path());
+        assert_eq!(source.list().await.unwrap(), ["/projects/foo.org", "/projects/old/bar.org", "/tasks.org"]);
+        assert!(source.read("/projects/old/bar.org").await.is_ok());
+        assert_eq!(source.doc_name("/projects/foo.org"), "projects/foo.org");
+
+        let source = FilesystemSource::new(dir.path()).with_max_depth(Some(1));
+        assert_eq!(source.list().await.unwrap(), ["/projects/foo.org", "/tasks.org"]);
+        assert!(source.read("/projects/old/bar.org").await.is_err());
+        assert!(source.read("/.git/x.org").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ignored_files() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("archive")).unwrap();
+        fs::create_dir_all(dir.path().join("journal")).unwrap();
+        make_files(dir.path(), &["tasks.org", "archive/2020.org", "journal/today.org", "journal/draft.org"]);
+        fs::write(dir.path().join("journal").join(IGNORE_FILE), "draft.org\n").unwrap();
+
+        let source = FilesystemSource::new(dir.path()).with_ignore(&["archive/"]).unwrap();
+        assert_eq!(source.list().await.unwrap(), ["/journal/today.org", "/tasks.org"]);
+        assert!(source.read("/archive/2020.org").await.is_err());
+        assert!(source.read("/journal/draft.org").await.is_err());
+        assert!(source.read("/journal/today.org").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_escaping_paths() {
+        let parent = tempdir().unwrap();
+        fs::create_dir_all(parent.path().join("root")).unwrap();
+        make_files(parent.path(), &["secret.org", "root/tasks.org"]);
+        let root = parent.path().join("root");
+        let source = FilesystemSource::new(&root);
+
+        assert!(source.read("/tasks.org").await.is_ok());
+        assert!(source.read("/../secret.org").await.is_err());
+        assert!(source.read("/./tasks.org").await.is_err());
+        assert!(source.read("/tasks.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_dirs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("todo")).unwrap();
+        fs::create_dir_all(dir.path().join("static")).unwrap();
+        fs::create_dir_all(dir.path().join("api/v1")).unwrap();
+        fs::create_dir_all(dir.path().join("projects/todo")).unwrap();
+        make_files(dir.path(), &["todo.org", "todo/x.org", "static/x.org", "api/v1/x.org", "projects/todo/x.org"]);
+        let source = FilesystemSource::new(dir.path());
+
+        assert_eq!(source.list().await.unwrap(), vec!["/projects/todo/x.org", "/todo.org"]);
+        assert!(source.read("/todo.org").await.is_ok());
+        assert!(source.read("/projects/todo/x.org").await.is_ok());
+        assert!(matches!(source.read("/todo/x.org").await, Err(SourceError::NotFound)));
+        assert!(matches!(source.read("/static/x.org").await, Err(SourceError::NotFound)));
+        assert!(matches!(source.read("/api/v1/x.org").await, Err(SourceError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_symlinks() {
+        let parent = tempdir().unwrap();
+        fs::create_dir_all(parent.path().join("root")).unwrap();
+        fs::write(parent.path().join("secret.org"), "* Secret").unwrap();
+        fs::write(parent.path().join("root/tasks.org"), "* TODO Task").unwrap();
+        let root = parent.path().join("root");
+        std::os::unix::fs::symlink(parent.path().join("secret.org"), root.join("outside.org")).unwrap();
+        std::os::unix::fs::symlink(root.join("tasks.org"), root.join("inside.org")).unwrap();
+        let source = FilesystemSource::new(&root);
+
+        assert_eq!(source.list().await.unwrap(), vec!["/inside.org", "/tasks.org"]);
+        assert!(matches!(source.read("/outside.org").await, Err(SourceError::InvalidPath)));
+        assert!(matches!(source.metadata("/outside.org").await, Err(SourceError::InvalidPath)));
+        assert!(matches!(source.write("/outside.org", "* Secret", "* Leaked").await, Err(WriteError::NotFound)));
+        assert_eq!(fs::read_to_string(parent.path().join("secret.org")).unwrap(), "* Secret");
+
+        source.write("/inside.org", "* TODO Task", "* DONE Task").await.unwrap();
+        assert_eq!(fs::read_to_string(root.join("tasks.org")).unwrap(), "* DONE Task");
+        assert!(fs::symlink_metadata(root.join("inside.org")).unwrap().file_type().is_symlink());
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("tasks.org"), "* TODO Task").unwrap();
+        let source = FilesystemSource::new(dir.path());
+
+        let first = source.metadata("/tasks.org").await.unwrap();
+        assert_eq!(first.size, 11);
+        assert_eq!(first.modified, Some(fs::metadata(dir.path().join("tasks.org")).unwrap().modified().unwrap()));
+        assert_eq!(source.metadata("/tasks.org").await.unwrap(), first);
+
+        source.write("/tasks.org", "* TODO Task", "* DONE Task!").await.unwrap();
+        assert_ne!(source.metadata("/tasks.org").await.unwrap().etag, first.etag);
+        assert!(matches!(source.metadata("/missing.org").await, Err(SourceError::NotFound)));
+        assert!(matches!(source.metadata("/../tasks.org").await, Err(SourceError::InvalidPath)));
+    }
+
+    #[tokio::test]
+    async fn test_read_errors() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("archive")).unwrap();
+        make_files(dir.path(), &["archive/2020.org"]);
+        fs::write(dir.path().join("invalid.org"), b"\xff\xfe").unwrap();
+        let source = FilesystemSource::new(dir.path()).with_ignore(&["archive/"]).unwrap();
+
+        assert!(matches!(source.read("/missing.org").await, Err(SourceError::NotFound)));
+        assert!(matches!(source.read("/archive/2020.org").await, Err(SourceError::NotFound)));
+        assert!(matches!(source.read("/../secret.org").await, Err(SourceError::InvalidPath)));
+        assert!(matches!(source.read("/tasks.txt").await, Err(SourceError::InvalidPath)));
+        assert!(matches!(source.read("/invalid.org").await, Err(SourceError::Parse(_))));
+
+        let missing = FilesystemSource::new(dir.path().join("missing"));
+        assert!(matches!(missing.list().await, Err(SourceError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("projects")).unwrap();
+        fs::write(dir.path().join("projects/foo.org"), "* TODO Task").unwrap();
+        let source = FilesystemSource::new(dir.path());
+
+        source.write("/projects/foo.org", "* TODO Task", "* DONE Task").await.unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("projects/foo.org")).unwrap(), "* DONE Task");
+        assert_eq!(fs::read_dir(dir.path().join("projects")).unwrap().count(), 1);
+
+        let result = source.write("/projects/foo.org", "* TODO Task", "* NEXT Task").await;
+        assert!(matches!(result, Err(WriteError::Conflict)));
+        assert_eq!(fs::read_to_string(dir.path().join("projects/foo.org")).unwrap(), "* DONE Task");
+
+        assert!(matches!(source.write("/missing.org", "", "").await, Err(WriteError::NotFound)));
+        assert!(matches!(source.write("/../foo.org", "", "").await, Err(WriteError::NotFound)));
+    }
+
     #[tokio::test]
     async fn test_doc_name() {
         let dir = tempdir().unwrap();
         make_files(dir.path(), &["tasks.org", "events.org"]);
         let source = FilesystemSource::new(dir.path());
 
-        let files = source.list().await;
+        let files = source.list().await.unwrap();
         let names = files.iter().map(|name| source.doc_name(name)).collect::<BTreeSet<String>>();
         assert_eq!(names, set!("tasks.org", "events.org"));
     }
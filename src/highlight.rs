@@ -0,0 +1,136 @@
+use lazy_static::lazy_static;
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+/// Highlighted code gets classes like `hl-keyword`, to be styled by the stylesheet.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Org language names that differ from the syntaxes' names and extensions.
+const ALIASES: &[(&str, &str)] = &[
+    ("shell", "bash"),
+    ("emacs-lisp", "lisp"),
+    ("elisp", "lisp"),
+    ("scheme", "lisp"),
+    ("c++", "cpp"),
+    ("javascript", "js"),
+    ("sqlite", "sql"),
+    ("conf", "ini"),
+];
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+}
+
+fn syntax(language: &str) -> Option<&'static SyntaxReference> {
+    if language.is_empty() {
+        return None;
+    }
+    let language = ALIASES.iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(language))
+        .map_or(language, |(_, name)| name);
+    SYNTAXES.find_syntax_by_token(language)
+}
+
+/// Highlights `code` as `language`, the name used in `#+BEGIN_SRC`, as HTML with
+/// `hl-` classes. Returns `None` for unknown languages.
+pub fn highlight(code: &str, language: &str) -> Option<String> {
+    let syntax = syntax(language)?;
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        generator.parse_html_for_line_which_includes_newline(line).ok()?;
+    }
+    Some(generator.finalize())
+}
+
+/// Writes `html` to `out` with a `<span class="line-number">` before every line,
+/// counting from `first`. Returns the number of lines. Spans that continue over
+/// a line break, like a multi-line string, are closed before the number and
+/// reopened after it.
+pub fn number_lines(html: &str, first: usize, out: &mut String) -> usize {
+    let mut open: Vec<&str> = Vec::new();
+    let mut count = 0;
+    for line in html.split_inclusive('\n') {
+        // The tags closing the last line aren't a line of their own
+        if count > 0 && !line.ends_with('\n') && tags(line).map(str::len).sum::<usize>() == line.len() {
+            out.push_str(line);
+            break;
+        }
+        out.push_str(&"</span>".repeat(open.len()));
+        out.push_str(&format!("<span class=\"line-number\">{}</span>", first.saturating_add(count)));
+        open.iter().for_each(|tag| out.push_str(tag));
+        out.push_str(line);
+        for tag in tags(line) {
+            match tag.starts_with("</") {
+                true => { open.pop(); },
+                false => open.push(tag),
+            }
+        }
+        count += 1;
+    }
+    count
+}
+
+/// The tags in `html`, whose text is escaped so that every `<` starts one.
+fn tags(html: &str) -> impl Iterator<Item = &str> {
+    html.match_indices('<').map(move |(start, _)| {
+        let end = html[start..].find('>').map_or(html.len(), |end| start + end + 1);
+        &html[start..end]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let html = highlight("fn main() {}\n", "rust").unwrap();
+        assert!(html.contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"));
+        assert!(!html.contains("style="));
+
+        assert!(highlight("echo \"<b>\"\n", "sh").unwrap().contains("&lt;b&gt;"));
+        assert!(highlight("(setq x 1)\n", "emacs-lisp").is_some());
+        assert!(highlight("(setq x 1)\n", "Python").is_some());
+        assert_eq!(highlight("x\n", "no-such-language"), None);
+        assert_eq!(highlight("x\n", ""), None);
+    }
+
+    #[test]
+    fn test_number_lines() {
+        let mut out = String::new();
+        assert_eq!(number_lines("a\n<span>b</span>\n", 10, &mut out), 2);
+        assert_eq!(out, "<span class=\"line-number\">10</span>a\n<span class=\"line-number\">11</span><span>b</span>\n");
+
+        let mut out = String::new();
+        assert_eq!(number_lines("<span class=\"a\">x\n<span>y\nz</span></span>\n", 1, &mut out), 3);
+        assert_eq!(out, concat!(
+            "<span class=\"line-number\">1</span><span class=\"a\">x\n",
+            "</span><span class=\"line-number\">2</span><span class=\"a\"><span>y\n",
+            "</span></span><span class=\"line-number\">3</span><span class=\"a\"><span>z</span></span>\n",
+        ));
+    }
+
+    #[test]
+    fn test_number_multiline_string() {
+        let html = highlight("let s = \"one\ntwo\nthree\";\n", "rust").unwrap();
+        let mut out = String::new();
+        assert_eq!(number_lines(&html, 1, &mut out), 3);
+
+        // Line numbers are never inside the string's spans
+        let mut depth = 0;
+        let mut numbers = 0;
+        for (i, _) in out.match_indices('<') {
+            if out[i..].starts_with("<span class=\"line-number\">") {
+                assert_eq!(depth, 0, "{out}");
+                numbers += 1;
+            }
+            if out[i..].starts_with("</") { depth -= 1 } else { depth += 1 }
+        }
+        assert_eq!(depth, 0);
+        assert_eq!(numbers, 3);
+        assert!(out.contains("<span class=\"line-number\">2</span><span class=\"hl-source hl-rust\"><span class=\"hl-string"), "{out}");
+    }
+}
@@ -0,0 +1,472 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+
+use crate::{
+    parser::TodoItem,
+    timestamp::{Repeater, TimeUnit, Timestamp},
+};
+
+/// Years of time zone transitions written after the last date in the calendar,
+/// so repeating entries keep their local time.
+const TRANSITION_YEARS_AHEAD: i32 = 10;
+
+/// Years before and after now that time zone transitions are written for at
+/// most, so a timestamp in the year 1 or 9999 doesn't scan millennia.
+const MAX_TRANSITION_YEARS: i32 = 50;
+
+/// An iCalendar (RFC 5545) feed of the dated headlines.
+///
+/// TODO items with a SCHEDULED or DEADLINE date become VTODOs starting and due
+/// then, other headlines become VEVENTs for those dates. Every active timestamp
+/// in a heading or body is a VEVENT as well. Times are local to `tz`, which is
+/// included as a VTIMEZONE.
+pub struct Calendar {
+    tz: Tz,
+    name: Option<String>,
+    stamp: DateTime<Utc>,
+    components: Vec<Component>,
+}
+
+struct Component {
+    kind: &'static str,
+    properties: Vec<String>,
+    /// Dates the component refers to, to know which time zone transitions to include
+    dates: Vec<NaiveDate>,
+}
+
+impl Calendar {
+    /// `now` is the time the feed is generated at, written as `DTSTAMP`.
+    pub fn new(tz: Tz, now: DateTime<Utc>) -> Self {
+        Calendar { tz, name: None, stamp: now, components: Vec::new() }
+    }
+
+    pub fn with_name(mut self, name: Option<&str>) -> Self {
+        self.name = name.map(str::to_string);
+        self
+    }
+
+    pub fn add(&mut self, doc: &str, item: &TodoItem) {
+        let uid = item_uid(doc, item);
+        let summary = summary(item.heading());
+
+        if item.keyword().is_some() {
+            if item.scheduled().is_some() || item.deadline().is_some() {
+                self.add_todo(&uid, &summary, item);
+            }
+        } else {
+            if let Some(scheduled) = item.scheduled() {
+                self.add_event(&format!("SC-{uid}"), &summary, item, scheduled);
+            }
+            if let Some(deadline) = item.deadline() {
+                self.add_event(&format!("DL-{uid}"), &format!("Deadline: {summary}"), item, deadline);
+            }
+        }
+        for (i, timestamp) in item.timestamps().iter().enumerate() {
+            self.add_event(&format!("TS{}-{uid}", i + 1), &summary, item, timestamp);
+        }
+    }
+
+    fn add_todo(&mut self, uid: &str, summary: &str, item: &TodoItem) {
+        let mut component = self.component("VTODO", &format!("TODO-{uid}"), summary, item);
+        let mut start = item.scheduled();
+        let due = item.deadline();
+        // DTSTART and DUE need the same value type, and DUE can't be earlier
+        let dates_only = matches!((start, due), (Some(start), Some(due)) if start.time.is_some() != due.time.is_some());
+        if let (Some(s), Some(d)) = (start, due) {
+            if d.date < s.date || (d.date == s.date && d.time < s.time) {
+                start = None;
+            }
+        }
+
+        if let Some(start) = start {
+            component.date_property(self.tz, "DTSTART", start.date, start.time.filter(|_| !dates_only));
+            if let Some(repeater) = start.repeater {
+                component.properties.push(rrule(repeater));
+            }
+        }
+        if let Some(due) = due {
+            component.date_property(self.tz, "DUE", due.date, due.time.filter(|_| !dates_only));
+        }
+        if item.is_done() {
+            component.properties.push("STATUS:COMPLETED".to_string());
+            if let Some(closed) = item.closed() {
+                let completed = self.to_utc(closed.date.and_time(closed.time.unwrap_or_default()));
+                component.properties.push(format!("COMPLETED:{}", format_utc(completed)));
+            }
+        } else {
+            component.properties.push("STATUS:NEEDS-ACTION".to_string());
+        }
+        if let Some(priority) = item.priority().and_then(priority) {
+            component.properties.push(format!("PRIORITY:{priority}"));
+        }
+        self.components.push(component);
+    }
+
+    fn add_event(&mut self, uid: &str, summary: &str, item: &TodoItem, timestamp: &Timestamp) {
+        let mut component = self.component("VEVENT", uid, summary, item);
+        component.date_property(self.tz, "DTSTART", timestamp.date, timestamp.time);
+        match (timestamp.time, timestamp.end) {
+            // Whole days end the day after the last one
+            (None, Some((end, _))) => component.date_property(self.tz, "DTEND", end + Duration::days(1), None),
+            (None, None) => component.date_property(self.tz, "DTEND", timestamp.date + Duration::days(1), None),
+            (Some(_), Some((end, end_time))) => {
+                component.date_property(self.tz, "DTEND", end, Some(end_time.unwrap_or(NaiveTime::MIN)));
+            },
+            (Some(_), None) => {},
+        }
+        if let Some(repeater) = timestamp.repeater {
+            component.properties.push(rrule(repeater));
+        }
+        self.components.push(component);
+    }
+
+    fn component(&self, kind: &'static str, uid: &str, summary: &str, item: &TodoItem) -> Component {
+        let mut properties = vec![
+            format!("UID:{}", escape(uid)),
+            format!("DTSTAMP:{}", format_utc(self.stamp)),
+            format!("SUMMARY:{}", escape(summary)),
+        ];
+        let tags: Vec<String> = item.tags().map(escape).collect();
+        if !tags.is_empty() {
+            properties.push(format!("CATEGORIES:{}", tags.join(",")));
+        }
+        Component { kind, properties, dates: Vec::new() }
+    }
+
+    fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        self.tz.from_local_datetime(&local).earliest()
+            // Skipped by a transition, an hour later exists
+            .or_else(|| self.tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+            .map(|time| time.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+    }
+
+    /// The calendar in iCalendar format, with CRLF line endings and long lines folded.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".to_string(),
+            format!("PRODID:-//org-server//org-server {}//EN", env!("CARGO_PKG_VERSION")),
+            "CALSCALE:GREGORIAN".to_string(),
+        ];
+        if let Some(name) = &self.name {
+            lines.push(format!("X-WR-CALNAME:{}", escape(name)));
+        }
+        lines.push(format!("X-WR-TIMEZONE:{}", self.tz.name()));
+
+        let dates = self.components.iter().flat_map(|component| component.dates.iter().copied());
+        let (first, last) = dates.fold((self.stamp.year(), self.stamp.year()), |(first, last), date| {
+            (first.min(date.year()), last.max(date.year()))
+        });
+        let now = self.stamp.year();
+        lines.extend(vtimezone(
+            self.tz,
+            (first - 1).max(now - MAX_TRANSITION_YEARS),
+            (last + TRANSITION_YEARS_AHEAD).min(now + MAX_TRANSITION_YEARS),
+        ));
+
+        for component in &self.components {
+            lines.push(format!("BEGIN:{}", component.kind));
+            lines.extend(component.properties.iter().cloned());
+            lines.push(format!("END:{}", component.kind));
+        }
+        lines.push("END:VCALENDAR".to_string());
+
+        let mut out = String::new();
+        for line in lines {
+            fold(&mut out, &line);
+        }
+        out
+    }
+}
+
+impl Component {
+    /// Adds a `DATE` property, or a `DATE-TIME` local to `tz` when there is a time.
+    fn date_property(&mut self, tz: Tz, name: &str, date: NaiveDate, time: Option<NaiveTime>) {
+        self.dates.push(date);
+        self.properties.push(match time {
+            Some(time) => format!("{name};TZID={}:{}", tz.name(), date.and_time(time).format("%Y%m%dT%H%M%S")),
+            None => format!("{name};VALUE=DATE:{}", date.format("%Y%m%d")),
+        });
+    }
+}
+
+/// Identifies a headline across changes to it: its `ID` property, or a hash of
+/// the document and the outline path.
+fn item_uid(doc: &str, item: &TodoItem) -> String {
+    if let Some(id) = item.property("ID").filter(|id| !id.is_empty()) {
+        return id.to_string();
+    }
+
+    // FNV-1a, unlike `DefaultHasher` it is guaranteed to stay the same
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let parts = std::iter::once(doc).chain(item.path().iter().map(AsRef::as_ref)).chain([item.heading()]);
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}@org-server")
+}
+
+/// The heading without the timestamps in it, those are the dates of the entries.
+fn summary(heading: &str) -> String {
+    let mut summary = String::new();
+    let mut rest = heading;
+    while let Some(start) = rest.find('<') {
+        summary.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let end = candidate.find('>').map(|end| {
+            // Ranges like `<...>--<...>`
+            match candidate[end + 1..].strip_prefix("--<").and_then(|range| range.find('>')) {
+                Some(range_end) if Timestamp::parse(&candidate[..end + 4 + range_end + 1]).is_some() => end + 4 + range_end + 1,
+                _ => end + 1,
+            }
+        });
+        match end.filter(|end| Timestamp::parse(&candidate[..*end]).is_some()) {
+            Some(end) => rest = &candidate[end..],
+            None => {
+                summary.push('<');
+                rest = &candidate[1..];
+            },
+        }
+    }
+    summary.push_str(rest);
+    summary.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Maps org's `A`-`C` to the high, medium and low priorities of iCalendar.
+fn priority(priority: char) -> Option<u8> {
+    match priority {
+        'A' => Some(1),
+        'B' => Some(5),
+        'C' => Some(9),
+        _ => None,
+    }
+}
+
+fn rrule(repeater: Repeater) -> String {
+    let freq = match repeater.interval.unit {
+        TimeUnit::Hour => "HOURLY",
+        TimeUnit::Day => "DAILY",
+        TimeUnit::Week => "WEEKLY",
+        TimeUnit::Month => "MONTHLY",
+        TimeUnit::Year => "YEARLY",
+    };
+    match repeater.interval.value {
+        0 | 1 => format!("RRULE:FREQ={freq}"),
+        interval => format!("RRULE:FREQ={freq};INTERVAL={interval}"),
+    }
+}
+
+/// The definition of `tz` with its UTC offset changes between the given years.
+fn vtimezone(tz: Tz, first_year: i32, last_year: i32) -> Vec<String> {
+    let offset_at = |utc: NaiveDateTime| tz.offset_from_utc_datetime(&utc);
+    let mut lines = vec!["BEGIN:VTIMEZONE".to_string(), format!("TZID:{}", tz.name())];
+
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap_or_default().and_time(NaiveTime::MIN);
+    let start = NaiveDate::from_ymd_opt(first_year, 1, 1).map_or(epoch, |date| date.and_time(NaiveTime::MIN));
+    let end = NaiveDate::from_ymd_opt(last_year + 1, 1, 1).map_or(epoch, |date| date.and_time(NaiveTime::MIN));
+
+    let mut transitions = Vec::new();
+    let mut day = start;
+    while day < end {
+        let next = day + Duration::days(1);
+        if offset_at(day).fix() != offset_at(next).fix() {
+            // Narrow the change down to the second
+            let (mut before, mut after) = (day, next);
+            while after - before > Duration::seconds(1) {
+                let middle = before + (after - before) / 2;
+                if offset_at(middle).fix() == offset_at(day).fix() {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            transitions.push(after);
+        }
+        day = next;
+    }
+
+    if transitions.is_empty() {
+        let offset = offset_at(day);
+        lines.extend(observance(&offset, offset.fix().local_minus_utc(), epoch));
+    }
+    for utc in transitions {
+        let from = offset_at(utc - Duration::seconds(1)).fix().local_minus_utc();
+        let offset = offset_at(utc);
+        lines.extend(observance(&offset, from, utc + Duration::seconds(i64::from(from))));
+    }
+
+    lines.push("END:VTIMEZONE".to_string());
+    lines
+}
+
+fn observance(offset: &<Tz as TimeZone>::Offset, from: i32, local_start: NaiveDateTime) -> Vec<String> {
+    let kind = if offset.dst_offset().is_zero() { "STANDARD" } else { "DAYLIGHT" };
+    let lines = vec![
+        format!("BEGIN:{kind}"),
+        format!("DTSTART:{}", local_start.format("%Y%m%dT%H%M%S")),
+        format!("TZOFFSETFROM:{}", format_offset(from)),
+        format!("TZOFFSETTO:{}", format_offset(offset.fix().local_minus_utc())),
+        format!("TZNAME:{}", escape(offset.abbreviation())),
+        format!("END:{kind}"),
+    ];
+    lines
+}
+
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    match seconds % 60 {
+        0 => format!("{sign}{:02}{:02}", seconds / 3600, seconds / 60 % 60),
+        s => format!("{sign}{:02}{:02}{s:02}", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn format_utc(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a TEXT value.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                out.push('\\');
+                out.push(c);
+            },
+            '\n' => out.push_str("\\n"),
+            '\r' => {},
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends `line` with CRLF, folded so that no line is longer than 75 octets.
+fn fold(out: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            length = 1;
+        }
+        out.push(c);
+        length += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// The time zone of the system, UTC if it is unknown.
+pub fn local_timezone() -> Tz {
+    iana_time_zone::get_timezone().ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParserConfig, doc_to_headlines};
+
+    fn calendar(doc: &str, tz: Tz) -> String {
+        let now = Utc.with_ymd_and_hms(2024, 1, 29, 8, 0, 0).unwrap();
+        let mut calendar = Calendar::new(tz, now).with_name(Some("Tasks"));
+        doc_to_headlines(doc, &ParserConfig::default(), |item| calendar.add("/tasks.org", &item));
+        calendar.render()
+    }
+
+    fn components(ics: &str, kind: &str) -> Vec<String> {
+        ics.split(&format!("BEGIN:{kind}\r\n")).skip(1)
+            .map(|rest| rest.split(&format!("END:{kind}")).next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_todos() {
+        let ics = calendar("* TODO [#A] Water plants, daily :home:
+SCHEDULED: <2024-01-29 Mon 09:00 +1d> DEADLINE: <2024-02-01 Thu 18:00>
+:PROPERTIES:
+:ID: 5b3c
+:END:
+* DONE Pay rent
+CLOSED: [2024-01-28 Sun 10:00] DEADLINE: <2024-01-31 Wed>
+* TODO Someday
+", chrono_tz::Europe::Berlin);
+
+        let todos = components(&ics, "VTODO");
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[0], "UID:TODO-5b3c\r\nDTSTAMP:20240129T080000Z\r\nSUMMARY:Water plants\\, daily\r\nCATEGORIES:home\r\n\
+                              DTSTART;TZID=Europe/Berlin:20240129T090000\r\nRRULE:FREQ=DAILY\r\n\
+                              DUE;TZID=Europe/Berlin:20240201T180000\r\nSTATUS:NEEDS-ACTION\r\nPRIORITY:1\r\n");
+        assert!(todos[1].contains("DUE;VALUE=DATE:20240131\r\nSTATUS:COMPLETED\r\nCOMPLETED:20240128T090000Z\r\n"));
+        assert!(components(&ics, "VEVENT").is_empty());
+    }
+
+    #[test]
+    fn test_events() {
+        let ics = calendar("* Meeting <2024-01-30 Tue 10:00-11:30 +2w>
+* Holiday
+SCHEDULED: <2024-02-05 Mon>
+Trip <2024-02-10 Sat>--<2024-02-12 Mon>
+", chrono_tz::UTC);
+
+        let events = components(&ics, "VEVENT");
+        assert_eq!(events.len(), 3);
+        assert!(events[0].contains("SUMMARY:Meeting\r\n"));
+        assert!(events[0].contains("DTSTART;TZID=UTC:20240130T100000\r\nDTEND;TZID=UTC:20240130T113000\r\nRRULE:FREQ=WEEKLY;INTERVAL=2\r\n"));
+        assert!(events[1].starts_with("UID:SC-"));
+        assert!(events[1].contains("DTSTART;VALUE=DATE:20240205\r\nDTEND;VALUE=DATE:20240206\r\n"));
+        assert!(events[2].starts_with("UID:TS1-"));
+        assert!(events[2].contains("DTSTART;VALUE=DATE:20240210\r\nDTEND;VALUE=DATE:20240213\r\n"));
+
+        // Stable across runs, different for different headlines
+        let uid = |event: &str| event.lines().next().unwrap()[4..].to_string();
+        assert_eq!(uid(&events[1])[3..], uid(&events[2])[4..]);
+        assert_eq!(uid(&events[1])[3..], uid(&components(&calendar("* Holiday\nSCHEDULED: <2024-02-05 Mon>", chrono_tz::UTC), "VEVENT")[0])[3..]);
+        assert_ne!(uid(&events[0])[4..], uid(&events[1])[3..]);
+    }
+
+    #[test]
+    fn test_timezone() {
+        let ics = calendar("* Meeting <2024-03-01 Fri 10:00>", chrono_tz::Europe::Berlin);
+        let timezone = &components(&ics, "VTIMEZONE")[0];
+        assert!(timezone.starts_with("TZID:Europe/Berlin\r\n"));
+        assert!(timezone.contains("BEGIN:DAYLIGHT\r\nDTSTART:20240331T020000\r\nTZOFFSETFROM:+0100\r\nTZOFFSETTO:+0200\r\nTZNAME:CEST\r\nEND:DAYLIGHT"));
+        assert!(timezone.contains("BEGIN:STANDARD\r\nDTSTART:20241027T030000\r\nTZOFFSETFROM:+0200\r\nTZOFFSETTO:+0100\r\nTZNAME:CET\r\nEND:STANDARD"));
+
+        let ics = calendar("* Meeting <2024-03-01 Fri 10:00>", chrono_tz::UTC);
+        assert!(ics.contains("BEGIN:STANDARD\r\nDTSTART:19700101T000000\r\nTZOFFSETFROM:+0000\r\nTZOFFSETTO:+0000\r\n"));
+
+        // Far away dates don't widen the transitions beyond a window around now
+        let ics = calendar("* Founded <0001-01-01 Mon>\n* Someday <9999-12-31 Fri>", chrono_tz::Europe::Berlin);
+        let timezone = &components(&ics, "VTIMEZONE")[0];
+        assert!(timezone.contains("DTSTART:1980"));
+        assert!(timezone.contains("DTSTART:2074"));
+        assert!(!timezone.contains("DTSTART:2075"));
+        assert!(ics.contains("DTSTART;VALUE=DATE:99991231"), "{ics}");
+    }
+
+    #[test]
+    fn test_vtimezone_bounds() {
+        let lines = vtimezone(chrono_tz::Europe::Berlin, i32::MAX - 1, i32::MAX - 1);
+        assert_eq!(lines.first().map(String::as_str), Some("BEGIN:VTIMEZONE"));
+        assert_eq!(lines.last().map(String::as_str), Some("END:VTIMEZONE"));
+        assert!(lines.iter().any(|line| line.starts_with("BEGIN:STANDARD")));
+    }
+
+    #[test]
+    fn test_format() {
+        let ics = calendar(&format!("* {} <2024-01-30 Tue>", "Long heading ".repeat(10)), chrono_tz::UTC);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert!(ics.contains("X-WR-CALNAME:Tasks\r\n"));
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+        assert!(ics.replace("\r\n ", "").contains(&format!("SUMMARY:{}\r\n", "Long heading ".repeat(10).trim_end())));
+        assert_eq!(escape("a;b,c\\d\ne"), "a\\;b\\,c\\\\d\\ne");
+        assert_eq!(summary("Trip <2024-02-10 Sat>--<2024-02-12 Mon> to <Rome>"), "Trip to <Rome>");
+    }
+}
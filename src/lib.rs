@@ -1,6 +1,23 @@
 pub mod server;
+pub mod api;
+pub mod assets;
+pub mod auth;
+pub mod cache;
 pub mod doc;
+pub mod edit;
 pub mod empty_doc;
 pub mod fs_doc;
+pub mod highlight;
+pub mod ical;
+pub mod links;
+pub mod listen;
 pub mod parser;
+pub mod query;
 pub mod page;
+pub mod render;
+pub mod search;
+pub mod agenda;
+pub mod settings;
+pub mod tls;
+pub mod webdav;
+pub mod timestamp;
@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    doc::{OrgDoc, OrgSource, SourceError},
+    edit::content_version,
+    parser::{ParserConfig, doc_properties},
+};
+
+/// Index of what links between the documents of a source can point to: the
+/// headlines of every document and the `ID` properties of documents and headlines.
+/// It also keeps the links in each document, to tell what links to a document.
+///
+/// Like [`crate::search::SearchIndex`], [`LinkIndex::refresh`] only re-reads
+/// documents whose modification time or content changed. As it runs for every
+/// page, it only looks at the source when its [`OrgSource::generation`] changed,
+/// or, for sources without one, once the refresh interval passed.
+pub struct LinkIndex {
+    index: Mutex<Index>,
+    interval: Duration,
+    /// Generation of the source and time of the last refresh
+    refreshed: Mutex<Option<(Option<u64>, Instant)>>,
+}
+
+impl Default for LinkIndex {
+    fn default() -> Self {
+        LinkIndex::new(Duration::ZERO)
+    }
+}
+
+/// Where a link in a rendered document points to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkTarget {
+    /// Not a link to a document, left as it is
+    External,
+    /// A document or one of its headlines, at this URL
+    Internal(String),
+    /// A document, headline or ID that doesn't exist
+    Broken,
+}
+
+/// A link from a document to another, see [`LinkIndex::links`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocLink {
+    /// Document the link is in
+    pub from: String,
+    pub line: usize,
+    /// Headline the link is in, `None` for text before the first headline
+    pub heading: Option<String>,
+    /// See [`crate::parser::TodoItem::anchor`]
+    pub anchor: Option<String>,
+    /// The link as written, like `id:…`
+    pub link: String,
+    /// Document linked to
+    pub to: String,
+    /// Anchor and heading of the headline linked to, `None` for the whole document
+    pub target: Option<(String, String)>,
+}
+
+impl DocLink {
+    /// Link to where the link is in the rendered document.
+    pub fn url(&self) -> String {
+        match &self.anchor {
+            Some(anchor) => format!("{}#{anchor}", self.from),
+            None => self.from.clone(),
+        }
+    }
+}
+
+/// A link between documents, as written in org syntax.
+#[derive(Debug, PartialEq)]
+enum OrgLink<'a> {
+    /// `id:…`
+    Id(&'a str),
+    /// `file:projects.org::*Heading`, or a path ending in `.org`
+    File { path: &'a str, search: Option<&'a str> },
+    /// `*Heading` or `#custom-id` in the same document
+    Search(&'a str),
+}
+
+#[derive(Default)]
+struct Index {
+    docs: BTreeMap<String, IndexedDoc>,
+    /// Document and anchor of every `ID`, `None` for the `ID` of a document itself
+    ids: HashMap<String, (String, Option<String>)>,
+    /// Directory of the source, to find documents linked by absolute paths
+    root: Option<PathBuf>,
+}
+
+struct IndexedDoc {
+    modified: Option<SystemTime>,
+    version: String,
+    id: Option<String>,
+    headlines: Vec<Headline>,
+    /// Line and path of every link
+    links: Vec<(usize, String)>,
+}
+
+struct Headline {
+    line: usize,
+    heading: String,
+    anchor: String,
+    id: Option<String>,
+}
+
+impl LinkIndex {
+    /// An index refreshed at most every `interval` from sources without a generation.
+    pub fn new(interval: Duration) -> Self {
+        LinkIndex { index: Mutex::default(), interval, refreshed: Mutex::new(None) }
+    }
+
+    /// Brings the index up to date with the documents `source` lists, unless
+    /// it is considered current. Documents that can't be read are left out and
+    /// logged, only a failure to list the documents is returned.
+    pub async fn refresh<S: OrgSource>(&self, source: &S, config: &ParserConfig) -> Result<(), SourceError> {
+        let generation = source.generation();
+        if let Some((refreshed, at)) = *self.refreshed.lock().unwrap() {
+            let current = match generation {
+                Some(_) => refreshed == generation,
+                None => at.elapsed() < self.interval,
+            };
+            if current {
+                return Ok(());
+            }
+        }
+
+        let docs = source.list().await?;
+        self.index.lock().unwrap().root = source.root_dir().map(Path::to_path_buf);
+        for path in &docs {
+            let modified = source.modified(path).await;
+            if modified.is_some() && self.index.lock().unwrap().modified(path) == modified {
+                continue;
+            }
+            let doc = match source.read(path).await {
+                Ok(doc) => doc,
+                Err(SourceError::NotFound) => continue,
+                Err(e) => {
+                    eprintln!("Leaving the links of {path} out: {e}");
+                    continue;
+                },
+            };
+            self.index.lock().unwrap().update(path, modified, &doc, config);
+        }
+        self.index.lock().unwrap().retain(&docs);
+        *self.refreshed.lock().unwrap() = Some((generation, Instant::now()));
+        Ok(())
+    }
+
+    /// Changes whenever a document in the index does, for the validators of
+    /// pages depending on other documents.
+    pub fn version(&self) -> String {
+        let index = self.index.lock().unwrap();
+        let versions: String = index.docs.iter().map(|(path, doc)| format!("{path}:{}\n", doc.version)).collect();
+        content_version(&versions)
+    }
+
+    /// All links from one document to another, in the order of the documents.
+    pub fn links(&self) -> Vec<DocLink> {
+        let index = self.index.lock().unwrap();
+        let mut links = Vec::new();
+        for (from, doc) in &index.docs {
+            for (line, link) in &doc.links {
+                let LinkTarget::Internal(url) = index.resolve(from, link) else {
+                    continue;
+                };
+                let (to, target) = url.split_once('#').map_or((url.as_str(), None), |(to, anchor)| (to, Some(anchor)));
+                if to.is_empty() || to == from {
+                    continue;
+                }
+                let headline = doc.headlines.iter().take_while(|headline| headline.line <= *line).last();
+                let target = target.and_then(|anchor| {
+                    let headline = index.docs.get(to)?.headlines.iter().find(|headline| headline.anchor == anchor)?;
+                    Some((headline.anchor.clone(), headline.heading.clone()))
+                });
+                links.push(DocLink {
+                    from: from.clone(),
+                    line: *line,
+                    heading: headline.map(|headline| headline.heading.clone()),
+                    anchor: headline.map(|headline| headline.anchor.clone()),
+                    link: link.clone(),
+                    to: to.to_string(),
+                    target,
+                });
+            }
+        }
+        links
+    }
+
+    /// The links from other documents to `doc`.
+    pub fn backlinks(&self, doc: &str) -> Vec<DocLink> {
+        let mut links = self.links();
+        links.retain(|link| link.to == doc);
+        links
+    }
+
+    /// Resolves the links of the document at `doc`.
+    pub fn resolver<'a>(&'a self, doc: &'a str) -> LinkResolver<'a> {
+        LinkResolver { index: self, doc }
+    }
+}
+
+impl Index {
+    fn modified(&self, doc: &str) -> Option<SystemTime> {
+        self.docs.get(doc).and_then(|indexed| indexed.modified)
+    }
+
+    /// Indexes `org_doc` as `doc`, reusing the headlines a cached document already parsed.
+    fn update(&mut self, doc: &str, modified: Option<SystemTime>, org_doc: &impl OrgDoc, config: &ParserConfig) {
+        let content = org_doc.content();
+        let version = content_version(content);
+        if let Some(indexed) = self.docs.get_mut(doc) {
+            if indexed.version == version {
+                indexed.modified = modified;
+                return;
+            }
+        }
+
+        let id = doc_properties(content).into_iter()
+            .find(|(key, value)| key.eq_ignore_ascii_case("ID") && !value.is_empty())
+            .map(|(_, value)| value);
+        let mut headlines = Vec::new();
+        org_doc.headlines(config, &mut |item| headlines.push(Headline {
+            line: item.line(),
+            heading: item.heading().to_string(),
+            anchor: item.anchor().to_string(),
+            id: item.property("ID").filter(|id| !id.is_empty()).map(str::to_string),
+        }));
+        let links = doc_links(content).map(|(line, link)| (line, link.to_string())).collect();
+        self.docs.insert(doc.to_string(), IndexedDoc { modified, version, id, headlines, links });
+        self.index_ids();
+    }
+
+    fn retain(&mut self, docs: &[String]) {
+        let before = self.docs.len();
+        self.docs.retain(|path, _| docs.contains(path));
+        if self.docs.len() != before {
+            self.index_ids();
+        }
+    }
+
+    /// Collects the IDs of all documents, the first one wins if an ID is used twice.
+    fn index_ids(&mut self) {
+        self.ids.clear();
+        for (path, doc) in &self.docs {
+            let ids = doc.id.iter().map(|id| (id, None))
+                .chain(doc.headlines.iter().filter_map(|headline| Some((headline.id.as_ref()?, Some(&headline.anchor)))));
+            for (id, anchor) in ids {
+                self.ids.entry(id.clone()).or_insert_with(|| (path.clone(), anchor.cloned()));
+            }
+        }
+    }
+
+    /// Resolves `link` in the document `doc`.
+    fn resolve(&self, doc: &str, link: &str) -> LinkTarget {
+        let Some(link) = parse_link(link) else {
+            return LinkTarget::External;
+        };
+        match link {
+            OrgLink::Id(id) => match self.ids.get(id) {
+                Some((doc, Some(anchor))) => LinkTarget::Internal(format!("{doc}#{anchor}")),
+                Some((doc, None)) => LinkTarget::Internal(doc.clone()),
+                None => LinkTarget::Broken,
+            },
+            OrgLink::File { path, search } => match relative_doc(doc, path, self.root.as_deref()) {
+                Some(target) => self.search_url(&target, search),
+                None => LinkTarget::Broken,
+            },
+            OrgLink::Search(search) => match self.search_url(doc, Some(search)) {
+                LinkTarget::Internal(url) => LinkTarget::Internal(url[doc.len()..].to_string()),
+                target => target,
+            },
+        }
+    }
+
+    /// URL of `search` in `doc`, the doc itself for searches other than
+    /// headings and custom IDs, which can't be followed.
+    fn search_url(&self, doc: &str, search: Option<&str>) -> LinkTarget {
+        let Some(indexed) = self.docs.get(doc) else {
+            return LinkTarget::Broken;
+        };
+        let anchor = match search.map(str::trim) {
+            Some(search) if search.starts_with('*') => {
+                let heading = search[1..].trim();
+                indexed.headlines.iter().find(|headline| headline.heading.eq_ignore_ascii_case(heading))
+            },
+            Some(search) if search.starts_with('#') => {
+                indexed.headlines.iter().find(|headline| headline.anchor == search[1..])
+            },
+            _ => return LinkTarget::Internal(doc.to_string()),
+        };
+        match anchor {
+            Some(headline) => LinkTarget::Internal(format!("{doc}#{}", headline.anchor)),
+            None => LinkTarget::Broken,
+        }
+    }
+}
+
+/// Turns the links of one document into URLs of the server.
+pub struct LinkResolver<'a> {
+    index: &'a LinkIndex,
+    doc: &'a str,
+}
+
+impl LinkResolver<'_> {
+    /// Resolves the link path, like `file:projects.org::*Heading` or `id:…`.
+    pub fn resolve(&self, link: &str) -> LinkTarget {
+        self.index.index.lock().unwrap().resolve(self.doc, link)
+    }
+}
+
+fn parse_link(link: &str) -> Option<OrgLink<'_>> {
+    if let Some(id) = link.strip_prefix("id:") {
+        return Some(OrgLink::Id(id.trim()));
+    }
+    if link.starts_with(['*', '#']) {
+        return Some(OrgLink::Search(link));
+    }
+    let (path, search) = match link.strip_prefix("file:") {
+        Some(file) => file.split_once("::").map_or((file, None), |(path, search)| (path, Some(search))),
+        None if has_scheme(link) => return None,
+        None => (link, None),
+    };
+    if !path.to_ascii_lowercase().ends_with(".org") {
+        return None;
+    }
+    Some(OrgLink::File { path, search })
+}
+
+/// Line and path of the `[[…]]` links in `content`, leaving out source and example blocks.
+fn doc_links(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut in_block = false;
+    content.lines().enumerate().flat_map(move |(i, line)| {
+        let trimmed = line.trim_start().to_ascii_lowercase();
+        if trimmed.starts_with("#+begin_src") || trimmed.starts_with("#+begin_example") {
+            in_block = true;
+        } else if trimmed.starts_with("#+end_src") || trimmed.starts_with("#+end_example") {
+            in_block = false;
+        }
+        let links = if in_block || trimmed.starts_with('#') { "" } else { line };
+        links.split("[[").skip(1)
+            .filter_map(|rest| rest.split_once(']').map(|(path, _)| path))
+            .filter(|path| !path.is_empty())
+            .map(move |path| (i + 1, path))
+    })
+}
+
+/// Whether the link starts with a type like `https:` or `mailto:`.
+fn has_scheme(link: &str) -> bool {
+    link.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// Path of the document `path` links to from `doc`, `None` if it's outside of
+/// the source. Absolute paths are looked up below the source's `root`.
+fn relative_doc(doc: &str, path: &str, root: Option<&Path>) -> Option<String> {
+    let (mut segments, path): (Vec<&str>, &str) = if path.starts_with('/') {
+        (Vec::new(), Path::new(path).strip_prefix(root?).ok()?.to_str()?)
+    } else if path.starts_with('~') {
+        return None;
+    } else {
+        let mut segments: Vec<&str> = doc.split('/').filter(|segment| !segment.is_empty()).collect();
+        segments.pop();
+        (segments, path)
+    };
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop()?;
+            },
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{StaticOrgDoc, StaticOrgSource};
+
+    fn index(docs: &[(&str, &str)]) -> LinkIndex {
+        let links = LinkIndex::default();
+        for (path, content) in docs {
+            links.index.lock().unwrap().update(path, None, &StaticOrgDoc::new(content), &ParserConfig::default());
+        }
+        links
+    }
+
+    #[test]
+    fn test_parse_link() {
+        assert_eq!(parse_link("id:1f2e "), Some(OrgLink::Id("1f2e")));
+        assert_eq!(parse_link("file:a.org::*Heading"), Some(OrgLink::File { path: "a.org", search: Some("*Heading") }));
+        assert_eq!(parse_link("../a.org"), Some(OrgLink::File { path: "../a.org", search: None }));
+        assert_eq!(parse_link("#custom"), Some(OrgLink::Search("#custom")));
+        assert_eq!(parse_link("file:image.png"), None);
+        assert_eq!(parse_link("https://example.org/a.org"), None);
+        assert_eq!(parse_link("Some target"), None);
+    }
+
+    #[test]
+    fn test_doc_links() {
+        let content = "[[a.org][A]] and [[id:b]]\n#+BEGIN_SRC org\n[[c.org]]\n#+END_SRC\n# [[d.org]]\n* [[e.org]]\n";
+        assert_eq!(doc_links(content).collect::<Vec<_>>(), [(1, "a.org"), (1, "id:b"), (6, "e.org")]);
+    }
+
+    #[test]
+    fn test_relative_doc() {
+        let root = Some(Path::new("/home/me/org"));
+        assert_eq!(relative_doc("/a.org", "b.org", root).as_deref(), Some("/b.org"));
+        assert_eq!(relative_doc("/dir/a.org", "./sub/b.org", root).as_deref(), Some("/dir/sub/b.org"));
+        assert_eq!(relative_doc("/dir/a.org", "../b.org", root).as_deref(), Some("/b.org"));
+        assert_eq!(relative_doc("/dir/a.org", "../../b.org", root), None);
+        assert_eq!(relative_doc("/dir/a.org", "/home/me/org/b.org", root).as_deref(), Some("/b.org"));
+        assert_eq!(relative_doc("/dir/a.org", "/home/me/org/sub/../b.org", root).as_deref(), Some("/b.org"));
+        assert_eq!(relative_doc("/dir/a.org", "/home/me/org/../b.org", root), None);
+        assert_eq!(relative_doc("/dir/a.org", "/home/me/b.org", root), None);
+        assert_eq!(relative_doc("/dir/a.org", "/home/me/org/b.org", None), None);
+        assert_eq!(relative_doc("/dir/a.org", "~/b.org", root), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let links = index(&[
+            ("/projects.org", ":PROPERTIES:\n:ID: doc-id\n:END:\n* Garden\n:PROPERTIES:\n:ID: garden-id\n:END:\n* Roof\n:PROPERTIES:\n:CUSTOM_ID: roof\n:END:\n"),
+            ("/notes/daily.org", "* Today\n"),
+        ]);
+        let resolver = links.resolver("/notes/daily.org");
+        assert_eq!(resolver.resolve("id:doc-id"), LinkTarget::Internal("/projects.org".to_string()));
+        assert_eq!(resolver.resolve("id:garden-id"), LinkTarget::Internal("/projects.org#garden".to_string()));
+        assert_eq!(resolver.resolve("id:unknown"), LinkTarget::Broken);
+        assert_eq!(resolver.resolve("file:../projects.org::*garden"), LinkTarget::Internal("/projects.org#garden".to_string()));
+        assert_eq!(resolver.resolve("file:../projects.org::#roof"), LinkTarget::Internal("/projects.org#roof".to_string()));
+        assert_eq!(resolver.resolve("file:../projects.org::42"), LinkTarget::Internal("/projects.org".to_string()));
+        assert_eq!(resolver.resolve("file:../projects.org::*Cellar"), LinkTarget::Broken);
+        assert_eq!(resolver.resolve("file:projects.org"), LinkTarget::Broken);
+        assert_eq!(resolver.resolve("file:/home/me/org/projects.org"), LinkTarget::Broken);
+        links.index.lock().unwrap().root = Some(PathBuf::from("/home/me/org"));
+        assert_eq!(resolver.resolve("file:/home/me/org/projects.org::#roof"), LinkTarget::Internal("/projects.org#roof".to_string()));
+        assert_eq!(resolver.resolve("file:/home/me/projects.org"), LinkTarget::Broken);
+        assert_eq!(resolver.resolve("*Today"), LinkTarget::Internal("#today".to_string()));
+        assert_eq!(resolver.resolve("#today"), LinkTarget::Internal("#today".to_string()));
+        assert_eq!(resolver.resolve("#tomorrow"), LinkTarget::Broken);
+        assert_eq!(resolver.resolve("https://orgmode.org"), LinkTarget::External);
+    }
+
+    #[test]
+    fn test_backlinks() {
+        let links = index(&[
+            ("/projects.org", "[[#roof][Roof]]\n* Garden\n* Roof\n:PROPERTIES:\n:ID: roof-id\n:END:\n"),
+            ("/notes/daily.org", "See [[file:../projects.org]]\n* Today\nFix [[id:roof-id]] and [[file:../cellar.org]]\n"),
+        ]);
+        assert_eq!(links.backlinks("/projects.org"), [
+            DocLink {
+                from: "/notes/daily.org".to_string(), line: 1, heading: None, anchor: None,
+                link: "file:../projects.org".to_string(), to: "/projects.org".to_string(), target: None,
+            },
+            DocLink {
+                from: "/notes/daily.org".to_string(), line: 3, heading: Some("Today".to_string()), anchor: Some("today".to_string()),
+                link: "id:roof-id".to_string(), to: "/projects.org".to_string(),
+                target: Some(("roof".to_string(), "Roof".to_string())),
+            },
+        ]);
+        assert_eq!(links.backlinks("/projects.org")[1].url(), "/notes/daily.org#today");
+        assert_eq!(links.backlinks("/notes/daily.org"), []);
+        assert_eq!(links.links().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_interval() {
+        let config = ParserConfig::default();
+        let mut source = StaticOrgSource::default();
+        source.add_doc("a.org", "* A\n:PROPERTIES:\n:ID: a\n:END:\n");
+        let links = LinkIndex::new(Duration::from_secs(60));
+        links.refresh(&source, &config).await.unwrap();
+        assert_eq!(links.resolver("/b.org").resolve("id:a"), LinkTarget::Internal("/a.org#a".to_string()));
+
+        // Without a generation, the source is only looked at again after the interval
+        source.add_doc("b.org", "* B\n:PROPERTIES:\n:ID: b\n:END:\n");
+        links.refresh(&source, &config).await.unwrap();
+        assert_eq!(links.resolver("/a.org").resolve("id:b"), LinkTarget::Broken);
+        *links.refreshed.lock().unwrap() = Some((None, Instant::now() - Duration::from_secs(61)));
+        links.refresh(&source, &config).await.unwrap();
+        assert_eq!(links.resolver("/a.org").resolve("id:b"), LinkTarget::Internal("/b.org#b".to_string()));
+    }
+
+    #[test]
+    fn test_update_and_version() {
+        let links = index(&[("/a.org", "* A\n:PROPERTIES:\n:ID: a\n:END:\n"), ("/b.org", "* B\n:PROPERTIES:\n:ID: a\n:END:\n")]);
+        let version = links.version();
+        assert_eq!(links.resolver("/c.org").resolve("id:a"), LinkTarget::Internal("/a.org#a".to_string()));
+
+        links.index.lock().unwrap().retain(&["/b.org".to_string()]);
+        assert_ne!(links.version(), version);
+        assert_eq!(links.resolver("/c.org").resolve("id:a"), LinkTarget::Internal("/b.org#b".to_string()));
+    }
+}
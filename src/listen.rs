@@ -0,0 +1,188 @@
+use std::{fmt, io, net::{SocketAddr, TcpListener}, path::PathBuf};
+#[cfg(unix)]
+use std::{
+    fs::{self, Permissions},
+    os::{fd::{FromRawFd, OwnedFd}, unix::{fs::{FileTypeExt, PermissionsExt}, net::{UnixListener, UnixStream}}},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use hyper::server::accept::Accept;
+
+/// First file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// A socket for [`crate::server::Server`] to accept connections on, instead of
+/// binding its `address` and `port`.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+#[cfg(unix)]
+impl Listener {
+    /// Creates a Unix domain socket at `path`, replacing a socket left behind by
+    /// an earlier run, and sets its permissions to `mode`, e.g. `0o660`.
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            // Left behind only if nobody listens on it anymore
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                                   format!("another server is listening on {}", path.display()))),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                Err(_) => {},
+            }
+        }
+        let Some(mode) = mode else {
+            return Ok(Listener::Unix(UnixListener::bind(path)?));
+        };
+        // The socket is created with its permissions, so nobody can connect
+        // before they are set. The umask is the process's, this is meant for
+        // startup only.
+        // SAFETY: umask can't fail and only changes the mask of this process
+        let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+        let listener = UnixListener::bind(path);
+        // SAFETY: as above
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(Listener::Unix(listener))
+    }
+
+    /// The socket systemd passed in socket activation, if the process was started
+    /// that way, see `sd_listen_fds(3)`. It unsets the variables systemd passes,
+    /// so it must be called before any other threads are started.
+    pub fn from_systemd() -> io::Result<Option<Self>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+        let Some(count) = systemd_fds(pid.as_deref(), fds.as_deref(), std::process::id())? else {
+            return Ok(None);
+        };
+        if count != 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("systemd passed {count} sockets, only one is supported")));
+        }
+        // Not for child processes
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        // SAFETY: systemd hands the descriptor over to this process, nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+        let listener = UnixListener::from(fd);
+        if listener.local_addr().is_ok() {
+            return Ok(Some(Listener::Unix(listener)));
+        }
+        let listener = TcpListener::from(OwnedFd::from(listener));
+        listener.local_addr()?;
+        Ok(Some(Listener::Tcp(listener)))
+    }
+}
+
+/// The number of sockets passed by systemd according to `LISTEN_PID` and
+/// `LISTEN_FDS`, if they were passed to this process.
+#[cfg(unix)]
+fn systemd_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<Option<u32>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    if pid.trim().parse::<u32>().ok() != Some(own_pid) {
+        return Ok(None);
+    }
+    let count = fds.trim().parse::<u32>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid LISTEN_FDS {fds:?}")))?;
+    Ok((count > 0).then_some(count))
+}
+
+/// Where a server is listening.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, with its path unless it's unnamed or abstract
+    Unix(Option<PathBuf>),
+}
+
+impl ListenAddr {
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Unix(None) => write!(f, "unix socket"),
+        }
+    }
+}
+
+/// Connections to a Unix domain socket, for [`axum::Server::builder`].
+#[cfg(unix)]
+pub(crate) struct UnixIncoming(pub(crate) tokio::net::UnixListener);
+
+#[cfg(unix)]
+impl Accept for UnixIncoming {
+    type Conn = tokio::net::UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0.poll_accept(cx).map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_systemd_fds() {
+        assert_eq!(systemd_fds(None, None, 42).unwrap(), None);
+        assert_eq!(systemd_fds(Some("42"), None, 42).unwrap(), None);
+        assert_eq!(systemd_fds(Some("41"), Some("1"), 42).unwrap(), None);
+        assert_eq!(systemd_fds(Some("42"), Some("0"), 42).unwrap(), None);
+        assert_eq!(systemd_fds(Some("42"), Some("1"), 42).unwrap(), Some(1));
+        assert_eq!(systemd_fds(Some("42"), Some("2"), 42).unwrap(), Some(2));
+        assert!(systemd_fds(Some("42"), Some("many"), 42).is_err());
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("org-server.sock");
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A socket in use is kept, one left behind is replaced, other files are not
+        let error = Listener::bind_unix(&path, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        Listener::bind_unix(&path, None).unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(Listener::bind_unix(&file, None).is_err());
+    }
+}
@@ -1,27 +1,201 @@
-mod doc;
-mod empty_doc;
-mod fs_doc;
-mod render;
-mod server;
-mod parser;
-mod page;
-
-use std::path::Path;
-
-use fs_doc::FilesystemSource;
-use parser::ParserConfig;
-use server::Server;
-
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let org_files_path = Box::leak(Box::new(Path::new("examples/org").canonicalize()?));
-    let source = FilesystemSource::new(org_files_path.as_path());
-
-    let server = Server{
-        port: 8080,
-        parser_config: ParserConfig::with_keywords(&["NEW", "NEXT"], &["DONE"]),
+use std::{net::IpAddr, path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use org_server::{
+    auth,
+    cache::CachedSource,
+    doc::{OrgSource, WritableOrgSource},
+    fs_doc::FilesystemSource,
+    listen::{ListenAddr, Listener},
+    server::{Server, ServerHandle, shutdown_signal},
+    settings::{CacheSettings, Settings, SourceSettings},
+    webdav::WebDavSource,
+};
+use reqwest::Url;
+
+/// Serves a directory of org files as HTML.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Config file, `org-server.toml` in the working directory by default
+    #[arg(short, long, env = "ORG_SERVER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long)]
+    address: Option<IpAddr>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Serve org files from this directory
+    #[arg(long)]
+    source_path: Option<PathBuf>,
+
+    /// Title shown on every page
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+
+    /// Read a password or token from stdin, print its hash for the `[auth]` settings and exit
+    #[arg(long)]
+    hash_password: bool,
+}
+
+impl Cli {
+    fn overrides(&self) -> Vec<(&'static str, String)> {
+        let mut overrides = Vec::new();
+        if let Some(address) = self.address {
+            overrides.push(("listen.address", address.to_string()));
+        }
+        if let Some(port) = self.port {
+            overrides.push(("listen.port", port.to_string()));
+        }
+        if let Some(path) = &self.source_path {
+            overrides.push(("source.type", "filesystem".to_string()));
+            overrides.push(("source.path", path.display().to_string()));
+        }
+        if let Some(title) = &self.title {
+            overrides.push(("title", title.clone()));
+        }
+        overrides
+    }
+}
+
+pub fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.hash_password {
+        return print_password_hash();
+    }
+
+    let settings = match Settings::load(cli.config.as_deref(), &cli.overrides()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    if let Err(e) = settings.validate() {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    if cli.check_config {
+        println!("Configuration is valid");
+        return ExitCode::SUCCESS;
+    }
+
+    // Before the runtime starts its threads: taking the socket from systemd
+    // clears its environment variables and binding one sets the umask
+    let listener = match listener(&settings) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Cannot start the runtime: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    match runtime.block_on(run(settings, listener)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn print_password_hash() -> ExitCode {
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Cannot read the password: {e}");
+        return ExitCode::FAILURE;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("The password is empty");
+        return ExitCode::FAILURE;
+    }
+    println!("{}", auth::hash_password(password));
+    ExitCode::SUCCESS
+}
+
+async fn run(settings: Settings, listener: Option<Listener>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut server = Server::from_settings(&settings)?;
+    server.listener = listener;
+    let handle = match &settings.source {
+        SourceSettings::Filesystem { path, max_depth, ignore } => {
+            let source = FilesystemSource::new(path.canonicalize()?)
+                .with_max_depth(*max_depth)
+                .with_ignore(ignore)?;
+            if settings.read_only {
+                serve(server, source, &settings.cache).await?
+            } else {
+                serve_writable(server, source, &settings.cache).await?
+            }
+        },
+        SourceSettings::WebDav { base_url, username, password, collection } => {
+            let source = WebDavSource::new(Url::parse(base_url)?, username, password, collection);
+            serve(server, source, &settings.cache).await?
+        },
     };
-    server.start(source).await?;
 
+    if let ListenAddr::Tcp(addr) = handle.local_addr() {
+        if !addr.ip().is_loopback() && settings.auth.is_none() {
+            eprintln!("Listening on {} without authentication, anyone who can connect can read the documents", addr.ip());
+        }
+    }
+    match handle.local_addr() {
+        ListenAddr::Tcp(addr) if settings.tls.is_some() => println!("Listening on https://{addr}"),
+        ListenAddr::Tcp(addr) => println!("Listening on http://{addr}"),
+        unix => println!("Listening on {unix}"),
+    }
+    handle.shutdown_on(shutdown_signal());
+    handle.wait().await?;
     Ok(())
 }
+
+/// The socket passed by systemd, or the configured Unix domain socket.
+#[cfg(unix)]
+fn listener(settings: &Settings) -> std::io::Result<Option<Listener>> {
+    if let Some(listener) = Listener::from_systemd()? {
+        return Ok(Some(listener));
+    }
+    settings.listen.socket.as_deref()
+        .map(|path| Listener::bind_unix(path, settings.listen.socket_mode))
+        .transpose()
+}
+
+#[cfg(not(unix))]
+fn listener(_settings: &Settings) -> std::io::Result<Option<Listener>> {
+    Ok(None)
+}
+
+async fn serve<S>(server: Server, source: S, cache: &CacheSettings) -> Result<ServerHandle, Box<dyn std::error::Error>>
+where S: OrgSource + 'static
+{
+    if cache.enabled {
+        server.start(CachedSource::new(source, cache.poll_interval())).await
+    } else {
+        server.start(source).await
+    }
+}
+
+async fn serve_writable<S>(server: Server, source: S, cache: &CacheSettings) -> Result<ServerHandle, Box<dyn std::error::Error>>
+where S: WritableOrgSource + 'static
+{
+    if cache.enabled {
+        server.start_writable(CachedSource::new(source, cache.poll_interval())).await
+    } else {
+        server.start_writable(source).await
+    }
+}
@@ -1,52 +1,272 @@
+use std::{fmt, fs, io, path::{Path, PathBuf}};
+
 use maud::{Markup, html, DOCTYPE, PreEscaped, Render};
 
-#[derive(Default)]
-pub struct Page;
+/// A small circle, so browsers don't ask for `/favicon.ico` when none is configured.
+pub const DEFAULT_FAVICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 16 16'%3E\
+%3Ccircle cx='8' cy='8' r='7' fill='%2377aa77'/%3E%3C/svg%3E";
+
+/// What all pages of the server share.
+#[derive(Debug, Default)]
+pub struct Layout {
+    /// Name of the site, added to every page title
+    pub title: Option<String>,
+    /// URLs of the stylesheets to link
+    pub stylesheets: Vec<String>,
+    /// URL of the icon, [`DEFAULT_FAVICON`] if not set
+    pub favicon: Option<String>,
+    /// Links of the navigation header, as URL and label
+    pub nav: Vec<(String, String)>,
+    pub template: Option<Template>,
+}
+
+pub struct Page<'a> {
+    layout: &'a Layout,
+    title: Option<String>,
+}
+
+impl<'a> Page<'a> {
+    pub fn new(layout: &'a Layout) -> Self {
+        Page { layout, title: None }
+    }
+
+    /// Sets the title of this page, shown before the site's.
+    pub fn titled(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// The `<title>`, like `tasks.org – My site`.
+    pub fn full_title(&self) -> Option<String> {
+        match (&self.title, &self.layout.title) {
+            (Some(title), Some(site)) => Some(format!("{title} – {site}")),
+            (Some(title), None) => Some(title.clone()),
+            (None, site) => site.clone(),
+        }
+    }
 
-impl Page {
     pub fn render(&self, inner: impl Render) -> Markup {
+        let title = self.full_title();
+        let head = html! {
+            meta charset="utf-8";
+            meta name="viewport" content="width=device-width, initial-scale=1";
+            @if let Some(title) = &title {
+                title { (title) }
+            }
+            @for stylesheet in &self.layout.stylesheets {
+                link rel="stylesheet" href=(stylesheet);
+            }
+            link rel="icon" href=(self.layout.favicon.as_deref().unwrap_or(DEFAULT_FAVICON));
+        };
+        let nav = html! {
+            @if !self.layout.nav.is_empty() {
+                nav.site-nav {
+                    ul {
+                        @for (url, label) in &self.layout.nav {
+                            li { a href=(url) { (label) } }
+                        }
+                    }
+                }
+            }
+        };
+        let content = inner.render();
+
+        if let Some(template) = &self.layout.template {
+            return template.render(title.as_deref().unwrap_or_default(), &head, &nav, &content);
+        }
         html! {
             (DOCTYPE)
             html {
-                head { (inner) }
-                body {}
+                head { (head) }
+                body {
+                    header { (nav) }
+                    main { (content) }
+                }
             }
         }
     }
 }
 
+/// A user supplied page layout, an HTML file with the placeholders `{{ title }}`,
+/// `{{ head }}`, `{{ nav }}` and `{{ content }}`, which is required.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Title,
+    Head,
+    Nav,
+    Content,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Read(PathBuf, io::Error),
+    Unclosed,
+    UnknownPlaceholder(String),
+    NoContent,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Read(path, e) => write!(f, "Cannot read {}: {e}", path.display()),
+            TemplateError::Unclosed => write!(f, "Placeholder without closing }}}}"),
+            TemplateError::UnknownPlaceholder(name) => write!(f, "Unknown placeholder {{{{ {name} }}}}"),
+            TemplateError::NoContent => write!(f, "No {{{{ content }}}} placeholder"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl Template {
+    /// File in the template directory laying out every page.
+    pub const PAGE_FILE: &'static str = "page.html";
+
+    /// Loads [`Template::PAGE_FILE`] from `dir`.
+    pub fn load(dir: &Path) -> Result<Self, TemplateError> {
+        let path = dir.join(Self::PAGE_FILE);
+        let source = fs::read_to_string(&path).map_err(|e| TemplateError::Read(path, e))?;
+        source.parse()
+    }
+
+    fn render(&self, title: &str, head: &Markup, nav: &Markup, content: &Markup) -> Markup {
+        let title = html! { (title) };
+        let mut output = String::new();
+        for part in &self.parts {
+            output.push_str(match part {
+                Part::Text(text) => text,
+                Part::Title => &title.0,
+                Part::Head => &head.0,
+                Part::Nav => &nav.0,
+                Part::Content => &content.0,
+            });
+        }
+        PreEscaped(output)
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            parts.push(Part::Text(rest[..start].to_string()));
+            let (name, after) = rest[start + 2..].split_once("}}").ok_or(TemplateError::Unclosed)?;
+            parts.push(match name.trim() {
+                "title" => Part::Title,
+                "head" => Part::Head,
+                "nav" => Part::Nav,
+                "content" => Part::Content,
+                name => return Err(TemplateError::UnknownPlaceholder(name.to_string())),
+            });
+            rest = after;
+        }
+        parts.push(Part::Text(rest.to_string()));
+
+        if !parts.contains(&Part::Content) {
+            return Err(TemplateError::NoContent);
+        }
+        Ok(Template { parts })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use scraper::{Html, Selector};
 
     use super::*;
 
+    fn select(output: &str, selector: &str) -> Vec<String> {
+        let html = Html::parse_document(output);
+        html.select(&Selector::parse(selector).unwrap()).map(|element| element.html()).collect()
+    }
+
     #[test]
     fn test_empty_page() {
-        let page = Page::default();
+        let layout = Layout::default();
+        let output = Page::new(&layout).render("").into_string();
+
+        assert!(output.starts_with("<!DOCTYPE html><html><head><meta charset=\"utf-8\">"));
+        assert!(output.ends_with("</head><body><header></header><main></main></body></html>"));
+        assert_eq!(select(&output, "head > title").len(), 0);
+        assert_eq!(select(&output, "head > link[rel=icon]").len(), 1);
+    }
 
-        assert_eq!("<!DOCTYPE html><html><head></head><body></body></html>", page.render("").into_string());
+    #[test]
+    fn test_page_title() {
+        let layout = Layout { title: Some("My <tasks>".to_string()), ..Layout::default() };
+
+        let output = Page::new(&layout).render("").into_string();
+        assert!(output.contains("<title>My &lt;tasks&gt;</title>"));
+        let output = Page::new(&layout).titled("tasks.org").render("").into_string();
+        assert!(output.contains("<title>tasks.org – My &lt;tasks&gt;</title>"));
     }
 
     #[test]
     fn test_page_with_string_content() {
-        let page = Page::default();
+        let layout = Layout::default();
 
-        let output = page.render(PreEscaped("<h1>heading</h1>")).into_string();
+        let output = Page::new(&layout).render(PreEscaped("<h1>heading</h1>")).into_string();
 
-        let html = Html::parse_document(&output);
-        let selector = Selector::parse("body > h1").unwrap();
-        assert_eq!(1, html.select(&selector).count());
+        assert!(output.contains("<main><h1>heading</h1></main>"));
     }
 
     #[test]
     fn test_page_with_markup_content() {
-        let page = Page::default();
+        let layout = Layout::default();
+
+        let output = Page::new(&layout).render(html! { h1 { "heading" } }).into_string();
+
+        assert_eq!(select(&output, "body > main > h1").len(), 1);
+    }
+
+    #[test]
+    fn test_head_and_nav() {
+        let layout = Layout {
+            stylesheets: vec!["/style.css".to_string()],
+            favicon: Some("/icon.png".to_string()),
+            nav: vec![("/".to_string(), "Index".to_string()), ("/agenda".to_string(), "Agenda".to_string())],
+            ..Layout::default()
+        };
 
-        let output = page.render(html! { h1 { "heading" } }).into_string();
+        let output = Page::new(&layout).render("").into_string();
+        assert!(output.contains(r#"<link rel="stylesheet" href="/style.css">"#));
+        assert!(output.contains(r#"<link rel="icon" href="/icon.png">"#));
+        assert_eq!(select(&output, "head > link").len(), 2);
+        assert_eq!(select(&output, "body > header > nav a").len(), 2);
+    }
+
+    #[test]
+    fn test_template() {
+        let template: Template = "<html><head>{{head}}</head><body><h1>{{ title }}</h1>{{ nav }}<div>{{ content }}</div></body></html>"
+            .parse().unwrap();
+        let layout = Layout { title: Some("<Site>".to_string()), template: Some(template), ..Layout::default() };
+
+        let output = Page::new(&layout).render(html! { p { "Hi" } }).into_string();
+        assert!(output.contains("<title>&lt;Site&gt;</title>"));
+        assert!(output.contains("<h1>&lt;Site&gt;</h1>"));
+        assert!(output.ends_with("<div><p>Hi</p></div></body></html>"));
+
+        assert!(matches!("<p>{{ content".parse::<Template>(), Err(TemplateError::Unclosed)));
+        assert!(matches!("{{ content }}{{ footer }}".parse::<Template>(), Err(TemplateError::UnknownPlaceholder(name)) if name == "footer"));
+        assert!(matches!("<p>{{ title }}</p>".parse::<Template>(), Err(TemplateError::NoContent)));
+    }
+
+    #[test]
+    fn test_load_template() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(Template::load(dir.path()), Err(TemplateError::Read(..))));
 
-        let html = Html::parse_document(&output);
-        let selector = Selector::parse("body > h1").unwrap();
-        assert_eq!(1, html.select(&selector).count());
+        fs::write(dir.path().join(Template::PAGE_FILE), "<main>{{content}}</main>").unwrap();
+        let layout = Layout { template: Some(Template::load(dir.path()).unwrap()), ..Layout::default() };
+        assert_eq!(Page::new(&layout).render("x").into_string(), "<main>x</main>");
     }
 }
@@ -1,40 +1,150 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, sync::Arc};
 
-use orgize::Org;
+use crate::timestamp::{Planning, Timestamp, find_active_timestamps};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TodoItem<'a> {
+    line: usize,
+    anchor: String,
     level: usize,
-    keyword: Arc<str>,
-    heading: &'a str,
+    keyword: Option<Arc<str>>,
+    heading: Cow<'a, str>,
+    done: bool,
+    priority: Option<char>,
+    tags: Vec<Cow<'a, str>>,
+    inherited_tags: Vec<Cow<'a, str>>,
+    planning: Planning,
+    properties: Vec<(Cow<'a, str>, String)>,
+    category: Option<String>,
+    path: Vec<Cow<'a, str>>,
+    timestamps: Vec<Timestamp>,
 }
 
 impl<'a> TodoItem<'a> {
-    pub fn keyword(&self) -> &str {
-        self.keyword.as_ref()
+    /// The TODO keyword, `None` for plain headlines from [`doc_to_headlines`].
+    pub fn keyword(&self) -> Option<&str> {
+        self.keyword.as_deref()
     }
 
     pub fn heading(&self) -> &str {
-        self.heading
+        &self.heading
     }
 
-    fn level(&self) -> usize {
+    pub fn level(&self) -> usize {
         self.level
     }
+
+    /// Line number of the headline in the document, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// `id` of the headline in the rendered document: its `CUSTOM_ID` property or
+    /// a slug of the heading, unique within the document.
+    pub fn anchor(&self) -> &str {
+        &self.anchor
+    }
+
+    /// Whether the keyword belongs to the done states of the sequence.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn priority(&self) -> Option<char> {
+        self.priority
+    }
+
+    /// All tags of the item: the inherited ones (including `#+FILETAGS`) followed by its own.
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.inherited_tags.iter().chain(self.tags.iter()).map(AsRef::as_ref)
+    }
+
+    pub fn own_tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(AsRef::as_ref)
+    }
+
+    pub fn scheduled(&self) -> Option<&Timestamp> {
+        self.planning.scheduled.as_ref()
+    }
+
+    pub fn deadline(&self) -> Option<&Timestamp> {
+        self.planning.deadline.as_ref()
+    }
+
+    pub fn closed(&self) -> Option<&Timestamp> {
+        self.planning.closed.as_ref()
+    }
+
+    /// Properties from the item's own property drawer, in the order they were written.
+    pub fn properties(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.properties.iter().map(|(key, value)| (key.as_ref(), value.as_str()))
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v)
+    }
+
+    /// The `CATEGORY` property of the item or its closest ancestor, or the file's `#+CATEGORY`.
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    /// Active timestamps in the heading and the body, not counting the planning line.
+    pub fn timestamps(&self) -> &[Timestamp] {
+        &self.timestamps
+    }
+
+    /// Headings of the parent headlines, outermost first.
+    pub fn path(&self) -> &[Cow<'a, str>] {
+        &self.path
+    }
+
+    /// Copies the borrowed parts of the document, so the item can outlive it.
+    pub fn into_owned(self) -> TodoItem<'static> {
+        fn own(s: Cow<str>) -> Cow<'static, str> {
+            Cow::Owned(s.into_owned())
+        }
+
+        TodoItem {
+            line: self.line,
+            anchor: self.anchor,
+            level: self.level,
+            keyword: self.keyword,
+            heading: own(self.heading),
+            done: self.done,
+            priority: self.priority,
+            tags: self.tags.into_iter().map(own).collect(),
+            inherited_tags: self.inherited_tags.into_iter().map(own).collect(),
+            planning: self.planning,
+            properties: self.properties.into_iter().map(|(key, value)| (own(key), value)).collect(),
+            category: self.category,
+            path: self.path.into_iter().map(own).collect(),
+            timestamps: self.timestamps,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 enum KeywordState {
     Todo,
     Completed,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ParserConfig {
     keywords: HashMap<Arc<str>, KeywordState>,
+    /// Keywords of each sequence in cycling order, todo ones first
+    sequences: Vec<Vec<Arc<str>>>,
     delegate: orgize::ParseConfig,
 }
 
+impl PartialEq for ParserConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // The orgize config is derived from the keywords
+        self.sequences == other.sequences && self.keywords == other.keywords
+    }
+}
+
 impl Default for ParserConfig {
     fn default() -> Self {
         Self::with_keywords(&["TODO"], &["DONE"])
@@ -43,38 +153,385 @@ impl Default for ParserConfig {
 
 impl ParserConfig {
     pub fn with_keywords(todo: &[&str], done: &[&str]) -> Self {
-        let delegate = orgize::ParseConfig{ todo_keywords: (todo.iter().map(|s| String::from(*s)).collect(),
-                                                            done.iter().map(|s| String::from(*s)).collect()) };
+        Self::with_sequences(&[(todo.to_vec(), done.to_vec())])
+    }
+
+    fn with_sequences(sequences: &[(Vec<&str>, Vec<&str>)]) -> Self {
+        let all_todo = sequences.iter().flat_map(|(todo, _)| todo);
+        let all_done = sequences.iter().flat_map(|(_, done)| done);
+        let delegate = orgize::ParseConfig{ todo_keywords: (all_todo.clone().map(|s| String::from(*s)).collect(),
+                                                            all_done.clone().map(|s| String::from(*s)).collect()) };
+
+        let mut keywords: HashMap<Arc<str>, KeywordState> = HashMap::new();
+        keywords.extend(all_todo.map(|s| (Arc::from(*s), KeywordState::Todo)));
+        keywords.extend(all_done.map(|s| (Arc::from(*s), KeywordState::Completed)));
+
+        let sequences = sequences.iter()
+            .map(|(todo, done)| todo.iter().chain(done).map(|s| Arc::clone(keywords.get_key_value(*s).unwrap().0)).collect())
+            .collect();
+
+        ParserConfig{ delegate, keywords, sequences }
+    }
+
+    /// Builds the config from keyword sequences written like `#+TODO:` lines,
+    /// e.g. `["NEW NEXT WAIT(w) | DONE CLND"]`.
+    pub fn from_sequences(sequences: &[impl AsRef<str>]) -> Result<Self, String> {
+        let sequences = sequences.iter()
+            .map(|sequence| parse_sequence(sequence.as_ref())
+                 .ok_or_else(|| format!("invalid TODO keyword sequence: {:?}", sequence.as_ref())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if sequences.is_empty() {
+            return Err("no TODO keywords defined".to_string());
+        }
 
-        let mut keywords = HashMap::new();
-        keywords.extend(todo.iter().map(|s| (Arc::from(*s), KeywordState::Todo)));
-        keywords.extend(done.iter().map(|s| (Arc::from(*s), KeywordState::Completed)));
+        Ok(Self::with_sequences(&sequences))
+    }
+
+    /// The keywords in effect for `doc`: its `#+TODO:`, `#+SEQ_TODO:` and
+    /// `#+TYP_TODO:` lines replace the configured ones, like in Emacs.
+    pub fn for_doc(&self, doc: &str) -> Cow<'_, ParserConfig> {
+        let mut sequences = Vec::new();
+        for (key, value) in doc.lines().filter_map(parse_keyword_line) {
+            let Some((todo, done)) = parse_sequence(value) else {
+                continue;
+            };
+            if key.eq_ignore_ascii_case("TODO") || key.eq_ignore_ascii_case("SEQ_TODO") {
+                sequences.push((todo, done));
+            } else if key.eq_ignore_ascii_case("TYP_TODO") {
+                // Each type goes straight to done
+                sequences.extend(todo.into_iter().map(|keyword| (vec![keyword], done.clone())));
+            }
+        }
+
+        if sequences.is_empty() {
+            Cow::Borrowed(self)
+        } else {
+            Cow::Owned(Self::with_sequences(&sequences))
+        }
+    }
 
-        ParserConfig{ delegate, keywords }
+    /// The keywords that aren't done states, in the order they were configured.
+    pub fn todo_keywords(&self) -> impl Iterator<Item = &str> {
+        self.sequences.iter().flatten()
+            .filter(|keyword| !self.is_done(keyword))
+            .map(|keyword| keyword.as_ref())
     }
 
-    fn as_org_config(&self) -> &orgize::ParseConfig {
+    pub fn is_keyword(&self, word: &str) -> bool {
+        self.keywords.contains_key(word)
+    }
+
+    /// The keyword following `current` in its sequence, like `C-c C-t` in
+    /// Emacs: no keyword starts the first sequence, the last keyword of a
+    /// sequence removes it.
+    pub fn next_keyword(&self, current: Option<&str>) -> Option<Arc<str>> {
+        let Some(current) = current else {
+            return self.sequences.first().and_then(|sequence| sequence.first()).cloned();
+        };
+        let sequence = self.sequences.iter().find(|sequence| sequence.iter().any(|k| k.as_ref() == current))?;
+        let position = sequence.iter().position(|k| k.as_ref() == current)?;
+        sequence.get(position + 1).cloned()
+    }
+
+    pub(crate) fn as_org_config(&self) -> &orgize::ParseConfig {
         &self.delegate
     }
 
+    pub(crate) fn is_done(&self, keyword: &str) -> bool {
+        matches!(self.keywords.get(keyword), Some(KeywordState::Completed))
+    }
+
     fn intern_keyword(&self, keyword: &str) -> Option<Arc<str>> {
         self.keywords.get_key_value(keyword).map(|(k, _)| k).map(Arc::clone)
     }
 }
 
+/// Splits a sequence like `TODO WAIT(w@/!) | DONE(d!)` into its todo and done
+/// keywords, dropping the fast-access keys. Without `|` the last keyword is the done one.
+fn parse_sequence(sequence: &str) -> Option<(Vec<&str>, Vec<&str>)> {
+    let words: Vec<&str> = sequence.split_whitespace().collect();
+    let mut parts = words.split(|word| *word == "|");
+    let todo = parts.next()?.iter().map(|w| sequence_keyword(w)).collect::<Option<Vec<_>>>()?;
+    let done = match parts.next() {
+        Some(done) => Some(done.iter().map(|w| sequence_keyword(w)).collect::<Option<Vec<_>>>()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    match done {
+        Some(done) if !todo.is_empty() || !done.is_empty() => Some((todo, done)),
+        None if !todo.is_empty() => {
+            let mut todo = todo;
+            let done = todo.pop().into_iter().collect();
+            Some((todo, done))
+        },
+        _ => None,
+    }
+}
+
+fn sequence_keyword(word: &str) -> Option<&str> {
+    let name = match word.split_once('(') {
+        Some((name, rest)) => rest.ends_with(')').then_some(name)?,
+        None => word,
+    };
+    (!name.is_empty() && !name.contains(['|', ')'])).then_some(name)
+}
+
+/// An outline node the following headlines can inherit from.
+struct Ancestor<'a> {
+    level: usize,
+    heading: &'a str,
+    tags: Vec<&'a str>,
+    category: Option<String>,
+}
+
+struct Headline<'a> {
+    level: usize,
+    keyword: Option<&'a str>,
+    priority: Option<char>,
+    heading: &'a str,
+    tags: Vec<&'a str>,
+}
+
+/// Calls `consumer` with every headline that has a TODO keyword.
 pub fn doc_to_items(doc: &str, config: &ParserConfig, mut consumer: impl FnMut(TodoItem)) {
-    let parsed = Org::parse_custom(doc, config.as_org_config());
-    for headline in parsed.headlines() {
-        let title = headline.title(&parsed);
-        if let Some(keyword) = title.keyword.as_ref().and_then(|keyword| config.intern_keyword(keyword)) {
-            consumer(TodoItem{
-                level: headline.level(),
-                keyword,
-                heading: title.raw.as_ref(),
-            });
+    doc_to_headlines(doc, config, |item| if item.keyword.is_some() {
+        consumer(item)
+    });
+}
+
+/// Calls `consumer` with every headline of the document, with or without a TODO keyword.
+pub fn doc_to_headlines(doc: &str, config: &ParserConfig, mut consumer: impl FnMut(TodoItem)) {
+    let config = &*config.for_doc(doc);
+    let (file_tags, file_category) = file_keywords(doc);
+    let mut ancestors: Vec<Ancestor> = Vec::new();
+    let mut lines = doc.lines().enumerate().peekable();
+    let mut pending: Option<TodoItem> = None;
+    let mut anchors = HashSet::new();
+
+    while let Some((index, line)) = lines.next() {
+        let Some(headline) = parse_headline(line, config) else {
+            if let Some(item) = pending.as_mut() {
+                item.timestamps.extend(find_active_timestamps(line));
+            }
+            continue;
+        };
+        if let Some(item) = pending.take() {
+            consumer(item);
+        }
+
+        let planning = lines.peek().and_then(|(_, line)| Planning::parse(line));
+        if planning.is_some() {
+            lines.next();
+        }
+        let is_drawer = lines.peek().map(|(_, line)| line.trim().eq_ignore_ascii_case(":PROPERTIES:")).unwrap_or(false)
+            && closes_drawer(lines.clone().skip(1).map(|(_, line)| line));
+        let properties = if is_drawer {
+            lines.next();
+            parse_properties(&mut lines.by_ref().map(|(_, line)| line))
+        } else {
+            Vec::new()
+        };
+
+        while ancestors.last().map(|a| a.level >= headline.level).unwrap_or(false) {
+            ancestors.pop();
+        }
+
+        let category = properties.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("CATEGORY"))
+            .map(|(_, value)| value.clone())
+            .or_else(|| ancestors.last().and_then(|a| a.category.clone()))
+            .or_else(|| file_category.map(String::from));
+
+        let keyword = headline.keyword.and_then(|keyword| config.intern_keyword(keyword));
+        let mut inherited_tags: Vec<Cow<str>> = Vec::new();
+        for tag in file_tags.iter().chain(ancestors.iter().flat_map(|a| a.tags.iter())) {
+            if !inherited_tags.iter().any(|t| t == tag) && !headline.tags.contains(tag) {
+                inherited_tags.push(Cow::Borrowed(tag));
+            }
+        }
+
+        let anchor = properties.iter()
+            .find(|(key, value)| key.eq_ignore_ascii_case("CUSTOM_ID") && !value.is_empty())
+            .map(|(_, value)| value.clone())
+            .unwrap_or_else(|| heading_slug(headline.heading));
+        let anchor = unique_anchor(&mut anchors, anchor);
+
+        pending = Some(TodoItem{
+            line: index + 1,
+            anchor,
+            level: headline.level,
+            done: keyword.as_deref().map(|keyword| config.is_done(keyword)).unwrap_or(false),
+            keyword,
+            heading: Cow::Borrowed(headline.heading),
+            priority: headline.priority,
+            tags: headline.tags.iter().copied().map(Cow::Borrowed).collect(),
+            inherited_tags,
+            planning: planning.unwrap_or_default(),
+            properties: properties.into_iter().map(|(key, value)| (Cow::Borrowed(key), value)).collect(),
+            category: category.clone(),
+            path: ancestors.iter().map(|a| Cow::Borrowed(a.heading)).collect(),
+            timestamps: find_active_timestamps(headline.heading),
+        });
+
+        ancestors.push(Ancestor{
+            level: headline.level,
+            heading: headline.heading,
+            tags: headline.tags,
+            category,
+        });
+    }
+
+    if let Some(item) = pending {
+        consumer(item);
+    }
+}
+
+/// Lowercase words of the heading joined by dashes, e.g. `weekly-review` for `Weekly review!`.
+pub fn heading_slug(heading: &str) -> String {
+    let slug = heading.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() { "heading".to_string() } else { slug }
+}
+
+/// Appends `-1`, `-2`, ... to anchors already used in the document.
+fn unique_anchor(used: &mut HashSet<String>, anchor: String) -> String {
+    let mut unique = anchor.clone();
+    let mut n = 0;
+    while used.contains(&unique) {
+        n += 1;
+        unique = format!("{anchor}-{n}");
+    }
+    used.insert(unique.clone());
+    unique
+}
+
+fn file_keywords(doc: &str) -> (Vec<&str>, Option<&str>) {
+    let mut tags = Vec::new();
+    let mut category = None;
+    for line in doc.lines() {
+        if let Some((key, value)) = parse_keyword_line(line) {
+            if key.eq_ignore_ascii_case("FILETAGS") {
+                tags.extend(value.split(':').map(str::trim).filter(|s| !s.is_empty()));
+            } else if key.eq_ignore_ascii_case("CATEGORY") && !value.is_empty() {
+                category = Some(value);
+            }
+        }
+    }
+
+    (tags, category)
+}
+
+/// The `#+TITLE:` of a document, the first one if there are several.
+pub fn doc_title(doc: &str) -> Option<&str> {
+    doc.lines()
+        .filter_map(parse_keyword_line)
+        .find(|(key, value)| key.eq_ignore_ascii_case("TITLE") && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+/// Properties of the document itself, from a drawer before the first headline,
+/// like the `ID` org-roam gives every file.
+pub fn doc_properties(doc: &str) -> Vec<(&str, String)> {
+    let mut lines = doc.lines().map(str::trim)
+        .skip_while(|line| line.is_empty() || line.starts_with('#'));
+    match lines.next() {
+        Some(line) if line.eq_ignore_ascii_case(":PROPERTIES:") && closes_drawer(lines.clone()) => parse_properties(&mut lines),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses an in-buffer setting like `#+CATEGORY: Shopping` into its key and value.
+fn parse_keyword_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.trim_start().strip_prefix("#+")?.split_once(':')?;
+    Some((key, value.trim()))
+}
+
+fn parse_headline<'a>(line: &'a str, config: &ParserConfig) -> Option<Headline<'a>> {
+    let level = line.find(|c| c != '*').unwrap_or(line.len());
+    if level == 0 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let mut rest = rest.trim();
+
+    let mut keyword = None;
+    let first_word = rest.split([' ', '\t']).next().unwrap_or_default();
+    if config.intern_keyword(first_word).is_some() {
+        keyword = Some(first_word);
+        rest = rest[first_word.len()..].trim_start();
+    }
+
+    let mut priority = None;
+    if let Some(cookie) = rest.strip_prefix("[#").and_then(|r| r.chars().next()).filter(|c| c.is_ascii_alphanumeric()) {
+        if rest[3..].starts_with(']') && rest[4..].chars().next().map(char::is_whitespace).unwrap_or(true) {
+            priority = Some(cookie);
+            rest = rest[4..].trim_start();
+        }
+    }
+
+    let mut tags = Vec::new();
+    if let Some(idx) = rest.rfind([' ', '\t']).map(|i| i + 1).or(Some(0)) {
+        let candidate = &rest[idx..];
+        if is_tag_group(candidate) {
+            tags = candidate.split(':').filter(|s| !s.is_empty()).collect();
+            rest = rest[..idx].trim_end();
         }
+    }
+
+    Some(Headline{ level, keyword, priority, heading: rest, tags })
+}
+
+fn is_tag_group(s: &str) -> bool {
+    s.len() > 2 && s.starts_with(':') && s.ends_with(':')
+        && s.chars().all(|c| c.is_alphanumeric() || "_@#%:".contains(c))
+}
+
+/// Whether `lines` close a drawer before the next headline. Without `:END:`
+/// it's no drawer, and mustn't swallow the rest of the document.
+fn closes_drawer<'a>(lines: impl Iterator<Item = &'a str>) -> bool {
+    let is_headline = |line: &str| {
+        let rest = line.trim_start_matches('*');
+        rest.len() < line.len() && (rest.is_empty() || rest.starts_with([' ', '\t']))
+    };
+    lines.take_while(|line| !is_headline(line))
+        .any(|line| line.trim().eq_ignore_ascii_case(":END:"))
+}
 
+fn parse_properties<'a>(lines: &mut impl Iterator<Item = &'a str>) -> Vec<(&'a str, String)> {
+    let mut properties: Vec<(&str, String)> = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.eq_ignore_ascii_case(":END:") {
+            break;
+        }
+        let Some((key, value)) = line.strip_prefix(':').and_then(|l| l.split_once(':')) else {
+            continue;
+        };
+        let value = value.trim();
+        if let Some(key) = key.strip_suffix('+') {
+            match properties.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                Some((_, existing)) => {
+                    existing.push(' ');
+                    existing.push_str(value);
+                },
+                None => properties.push((key, value.to_string())),
+            }
+        } else if !key.is_empty() {
+            properties.push((key, value.to_string()));
+        }
     }
+
+    properties
 }
 
 #[cfg(test)]
@@ -101,7 +558,7 @@ mod tests {
 * TODO Second task";
 
         let mut items = Vec::new();
-        doc_to_items(doc, &Default::default(), |item| items.push((item.level, item.keyword.clone(), item.heading.to_string())));
+        doc_to_items(doc, &Default::default(), |item| items.push((item.level, item.keyword.clone().unwrap(), item.heading.to_string())));
 
         assert_eq!(items.len(), 2);
 
@@ -122,11 +579,218 @@ mod tests {
         let config = ParserConfig::with_keywords(&["NEW", "NEXT"], &[]);
 
         let mut items = Vec::new();
-        doc_to_items(doc, &config, |item| items.push(item.keyword.clone()));
+        doc_to_items(doc, &config, |item| items.push(item.keyword.clone().unwrap()));
 
         assert_eq!(items.len(), 2);
         assert_eq!(items[0].as_ref(), "NEW");
         assert_eq!(items[1].as_ref(), "NEXT");
         
     }
+
+    #[test]
+    fn test_keyword_sequences() {
+        assert_eq!(parse_sequence("NEW NEXT | DONE"), Some((vec!["NEW", "NEXT"], vec!["DONE"])));
+        assert_eq!(parse_sequence("TODO WAIT(w@/!) | DONE(d!) CLND(c)"), Some((vec!["TODO", "WAIT"], vec!["DONE", "CLND"])));
+        assert_eq!(parse_sequence("TODO NEXT DONE"), Some((vec!["TODO", "NEXT"], vec!["DONE"])));
+        assert_eq!(parse_sequence("| DONE"), Some((vec![], vec!["DONE"])));
+        assert_eq!(parse_sequence("TODO | DONE | CLND"), None);
+        assert_eq!(parse_sequence("TODO(t | DONE"), None);
+        assert_eq!(parse_sequence(""), None);
+
+        let config = ParserConfig::from_sequences(&["NEW NEXT | DONE", "WAIT | CLND"]).unwrap();
+        assert!(config.intern_keyword("WAIT").is_some());
+        assert!(config.is_done("CLND"));
+        assert!(!config.is_done("NEXT"));
+        assert!(ParserConfig::from_sequences(&["|"]).is_err());
+    }
+
+    #[test]
+    fn test_next_keyword() {
+        let config = ParserConfig::from_sequences(&["NEW NEXT | DONE", "WAIT | CLND"]).unwrap();
+        let next = |current| config.next_keyword(current).map(|k| k.to_string());
+
+        assert_eq!(next(None).as_deref(), Some("NEW"));
+        assert_eq!(next(Some("NEW")).as_deref(), Some("NEXT"));
+        assert_eq!(next(Some("NEXT")).as_deref(), Some("DONE"));
+        assert_eq!(next(Some("DONE")), None);
+        assert_eq!(next(Some("WAIT")).as_deref(), Some("CLND"));
+        assert_eq!(next(Some("OTHER")), None);
+    }
+
+    #[test]
+    fn test_in_buffer_keywords() {
+        let doc = "#+TODO: NEW NEXT WAIT(w@/!) | DONE(d!) CLND
+#+TYP_TODO: Fred Sara | FIXED
+* NEW First
+* WAIT Second
+* CLND Third
+* TODO Not a keyword here
+* Sara Fix it";
+        let config = ParserConfig::with_keywords(&["TODO"], &["DONE"]);
+
+        let mut items = Vec::new();
+        doc_to_items(doc, &config, |item| items.push((item.keyword().unwrap().to_string(), item.is_done())));
+        assert_eq!(items, [("NEW".to_string(), false), ("WAIT".to_string(), false),
+                           ("CLND".to_string(), true), ("Sara".to_string(), false)]);
+
+        let doc_config = config.for_doc(doc);
+        assert_eq!(doc_config.next_keyword(Some("WAIT")).as_deref(), Some("DONE"));
+        assert_eq!(doc_config.next_keyword(Some("Sara")).as_deref(), Some("FIXED"));
+        assert!(!doc_config.is_keyword("TODO"));
+        assert!(matches!(config.for_doc("* TODO Task"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_doc_title_and_keywords() {
+        assert_eq!(doc_title("#+title: Household\n* TODO Task\n#+TITLE: Other"), Some("Household"));
+        assert_eq!(doc_title("#+TITLE:\n* Task"), None);
+
+        let config = ParserConfig::from_sequences(&["NEW NEXT | DONE", "BUG | FIXED"]).unwrap();
+        assert_eq!(config.todo_keywords().collect::<Vec<_>>(), ["NEW", "NEXT", "BUG"]);
+    }
+
+    #[test]
+    fn test_doc_properties() {
+        let doc = "\n:PROPERTIES:\n:ID: 1f2e\n:END:\n#+title: Notes\n* Heading\n";
+        assert_eq!(doc_properties(doc), [("ID", "1f2e".to_string())]);
+        assert!(doc_properties("Text\n:PROPERTIES:\n:ID: 1f2e\n:END:\n").is_empty());
+        assert!(doc_properties("* Heading\n:PROPERTIES:\n:ID: 1f2e\n:END:\n").is_empty());
+        assert!(doc_properties(":PROPERTIES:\n:ID: 1f2e\n* Heading\n:PROPERTIES:\n:ID: 2a3b\n:END:\n").is_empty());
+    }
+
+    #[test]
+    fn test_headline_lines() {
+        let doc = "#+TITLE: Tasks
+* TODO First
+SCHEDULED: <2024-01-29 Mon>
+:PROPERTIES:
+:ID: 1
+:END:
+Text
+** Second";
+        let mut lines = Vec::new();
+        doc_to_headlines(doc, &Default::default(), |item| lines.push(item.line()));
+
+        assert_eq!(lines, [2, 8]);
+    }
+
+    #[test]
+    fn test_anchors() {
+        let doc = "* TODO [#A] Weekly review!  :work:
+* Weekly review
+* Custom
+:PROPERTIES:
+:CUSTOM_ID: my-anchor
+:END:
+* Weekly review
+* ?!";
+        let mut anchors = Vec::new();
+        doc_to_headlines(doc, &Default::default(), |item| anchors.push(item.anchor().to_string()));
+
+        assert_eq!(anchors, ["weekly-review", "weekly-review-1", "my-anchor", "weekly-review-2", "heading"]);
+    }
+
+    #[test]
+    fn test_tags_and_category_inheritance() {
+        let doc = "#+FILETAGS: :home:
+* Shopping                                                          :errand:
+:PROPERTIES:
+:CATEGORY: Shopping
+:END:
+** NEXT Buy groceries                                                   :buy:
+** DONE Order soy sauce                                    :cooking:errand:
+* NEXT Unrelated";
+        let config = ParserConfig::with_keywords(&["NEXT"], &["DONE"]);
+
+        let mut items = Vec::new();
+        doc_to_items(doc, &config, |item| items.push((
+            item.heading().to_string(),
+            item.tags().map(String::from).collect::<Vec<_>>(),
+            item.category().map(String::from),
+            item.path().iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            item.is_done(),
+        )));
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].0, "Buy groceries");
+        assert_eq!(items[0].1, ["home", "errand", "buy"]);
+        assert_eq!(items[0].2.as_deref(), Some("Shopping"));
+        assert_eq!(items[0].3, ["Shopping"]);
+        assert!(!items[0].4);
+
+        assert_eq!(items[1].1, ["home", "cooking", "errand"]);
+        assert!(items[1].4);
+
+        assert_eq!(items[2].1, ["home"]);
+        assert_eq!(items[2].2, None);
+        assert!(items[2].3.is_empty());
+    }
+
+    #[test]
+    fn test_priority_planning_and_properties() {
+        let doc = "
+* TODO [#A] Pay rent
+DEADLINE: <2024-02-01 Thu -3d> SCHEDULED: <2024-01-29 Mon +1m>
+:PROPERTIES:
+:ID: 1234
+:NOTE: first
+:NOTE+: second
+:END:
+Body text
+* TODO [#B]Not a priority";
+
+        let mut items = Vec::new();
+        doc_to_items(doc, &Default::default(), |item| items.push((
+            item.heading().to_string(),
+            item.priority(),
+            item.scheduled().map(|ts| ts.to_string()),
+            item.deadline().map(|ts| ts.to_string()),
+            item.properties().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>(),
+        )));
+
+        assert_eq!(items[0].0, "Pay rent");
+        assert_eq!(items[0].1, Some('A'));
+        assert_eq!(items[0].2.as_deref(), Some("<2024-01-29 Mon +1m>"));
+        assert_eq!(items[0].3.as_deref(), Some("<2024-02-01 Thu -3d>"));
+        assert_eq!(items[0].4, ["ID=1234", "NOTE=first second"]);
+
+        assert_eq!(items[1].0, "[#B]Not a priority");
+        assert_eq!(items[1].1, None);
+        assert_eq!(items[1].2, None);
+    }
+
+    #[test]
+    fn test_unclosed_properties() {
+        let doc = "
+* TODO First
+:PROPERTIES:
+:ID: 1
+SCHEDULED: <2024-01-29 Mon>
+* TODO Second
+:PROPERTIES:
+:ID: 2
+:END:
+* TODO Third";
+
+        let mut items = Vec::new();
+        doc_to_items(doc, &Default::default(), |item| items.push((
+            item.heading().to_string(),
+            item.properties().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>(),
+            item.line(),
+        )));
+
+        assert_eq!(items, [
+            ("First".to_string(), vec![], 2),
+            ("Second".to_string(), vec!["ID=2".to_string()], 6),
+            ("Third".to_string(), vec![], 10),
+        ]);
+    }
+
+    #[test]
+    fn test_not_a_headline() {
+        let doc = "*bold* text\n*TODO not a heading\n * TODO indented";
+        let mut items = Vec::new();
+        doc_to_items(doc, &Default::default(), |_| items.push(true));
+        assert!(items.is_empty());
+    }
 }
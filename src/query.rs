@@ -0,0 +1,544 @@
+use std::{cmp::Ordering, fmt};
+
+use chrono::{Duration, Months, NaiveDate, NaiveTime};
+
+use crate::{
+    parser::TodoItem,
+    timestamp::{TimeUnit, Timestamp},
+};
+
+/// A filter over headlines in the spirit of org-ql and org's tag matches, e.g.
+/// `todo:NEXT tags:buy -tags:someday priority>=B deadline<+7d category:Shopping`.
+///
+/// Terms are separated by spaces and all have to match, a leading `-` negates
+/// one. Values can be lists (`todo:NEXT,WAIT` matches either) and be quoted to
+/// contain spaces. Words without a field match the heading. `sort:` orders the
+/// results by `priority`, `deadline`, `scheduled` or `file`, `group:` groups them
+/// by `category`, `file` or `tag`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    terms: Vec<Term>,
+    sort: Option<SortKey>,
+    group: Option<GroupKey>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Term {
+    negated: bool,
+    predicate: Predicate,
+}
+
+#[derive(Debug, PartialEq)]
+enum Predicate {
+    Keyword(Vec<String>),
+    Done(bool),
+    Tags(Vec<String>),
+    Category(Vec<String>),
+    Priority(Comparison, char),
+    Date(DateField, Comparison, DateValue),
+    Property(String, Option<String>),
+    File(String),
+    Heading(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateField {
+    Deadline,
+    Scheduled,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DateValue {
+    Absolute(NaiveDate),
+    /// Days, weeks, months or years from today, negative ones before it
+    Relative(i64, TimeUnit),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Priority,
+    Deadline,
+    Scheduled,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupKey {
+    Category,
+    File,
+    Tag,
+}
+
+/// Why a query can't be parsed, and where.
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    /// Character offset of the problem in the query, starting at 0
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A headline matching a query.
+#[derive(Debug, Clone)]
+pub struct QueryMatch {
+    pub doc: String,
+    /// See [`crate::edit::content_version`]
+    pub version: String,
+    pub item: TodoItem<'static>,
+}
+
+/// Matches sharing the value [`Query::group`] groups them by, `None` for the
+/// matches without one or when the query isn't grouped.
+#[derive(Debug)]
+pub struct QueryGroup {
+    pub name: Option<String>,
+    pub matches: Vec<QueryMatch>,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, QueryError> {
+        let mut query = Query::default();
+        for token in tokenize(input)? {
+            let error = |offset: usize, message: String| QueryError {
+                position: input[..token.input_offset(offset)].chars().count(),
+                message,
+            };
+
+            let (negated, token) = match token.text.strip_prefix('-') {
+                Some("") => return Err(error(0, "Expected a term after `-`".to_string())),
+                Some(rest) => (true, rest),
+                None => (false, token.text.as_str()),
+            };
+            let offset = usize::from(negated);
+
+            let Some((field, comparison, value)) = split_term(token) else {
+                query.terms.push(Term { negated, predicate: Predicate::Heading(token.to_lowercase()) });
+                continue;
+            };
+            let value_offset = offset + token.len() - value.len();
+            if value.is_empty() {
+                return Err(error(value_offset, format!("Missing value for `{field}`")));
+            }
+            let ordered = matches!(field, "priority" | "deadline" | "scheduled" | "closed");
+            if comparison != Comparison::Eq && !ordered {
+                return Err(error(offset + field.len(), format!("`{field}` can only be compared with `:`")));
+            }
+            if negated && matches!(field, "sort" | "group") {
+                return Err(error(0, format!("`{field}` can't be negated")));
+            }
+
+            let invalid = |expected: &str| error(value_offset, format!("Invalid value `{value}` for `{field}`, expected {expected}"));
+            let predicate = match field {
+                "todo" => Predicate::Keyword(list(value)),
+                "done" => match value.to_lowercase().as_str() {
+                    "yes" | "true" => Predicate::Done(true),
+                    "no" | "false" => Predicate::Done(false),
+                    _ => return Err(invalid("`yes` or `no`")),
+                },
+                "tags" | "tag" => Predicate::Tags(list(value)),
+                "category" => Predicate::Category(list(value)),
+                "priority" => Predicate::Priority(comparison, parse_priority(value).ok_or_else(|| invalid("a letter like `A`"))?),
+                "deadline" | "scheduled" | "closed" => {
+                    let date_field = match field {
+                        "deadline" => DateField::Deadline,
+                        "scheduled" => DateField::Scheduled,
+                        _ => DateField::Closed,
+                    };
+                    let date = parse_date(value).ok_or_else(|| invalid("a date like `2024-01-31`, `today` or `+7d`"))?;
+                    Predicate::Date(date_field, comparison, date)
+                },
+                "property" | "prop" => match value.split_once('=') {
+                    Some((key, value)) => Predicate::Property(key.to_string(), Some(value.to_string())),
+                    None => Predicate::Property(value.to_string(), None),
+                },
+                "file" => Predicate::File(value.to_lowercase()),
+                "heading" => Predicate::Heading(value.to_lowercase()),
+                "sort" => {
+                    query.sort = Some(match value {
+                        "priority" => SortKey::Priority,
+                        "deadline" => SortKey::Deadline,
+                        "scheduled" => SortKey::Scheduled,
+                        "file" => SortKey::File,
+                        _ => return Err(invalid("`priority`, `deadline`, `scheduled` or `file`")),
+                    });
+                    continue;
+                },
+                "group" => {
+                    query.group = Some(match value {
+                        "category" => GroupKey::Category,
+                        "file" => GroupKey::File,
+                        "tag" | "tags" => GroupKey::Tag,
+                        _ => return Err(invalid("`category`, `file` or `tag`")),
+                    });
+                    continue;
+                },
+                _ => return Err(error(offset, format!("Unknown field `{field}`"))),
+            };
+            query.terms.push(Term { negated, predicate });
+        }
+
+        Ok(query)
+    }
+
+    pub fn sort(&self) -> Option<SortKey> {
+        self.sort
+    }
+
+    pub fn group(&self) -> Option<GroupKey> {
+        self.group
+    }
+
+    /// Whether the headline `item` of the document `doc` matches all terms, with
+    /// relative dates counted from `today`.
+    pub fn matches(&self, doc: &str, item: &TodoItem, today: NaiveDate) -> bool {
+        self.terms.iter().all(|term| term.predicate.matches(doc, item, today) != term.negated)
+    }
+
+    /// Sorts and groups the matches as the query asks for. Groups are sorted by
+    /// name, a match with several tags shows up in the group of each.
+    pub fn arrange(&self, mut matches: Vec<QueryMatch>) -> Vec<QueryGroup> {
+        match self.sort {
+            Some(SortKey::Priority) => matches.sort_by(|a, b| missing_last(a.item.priority(), b.item.priority())),
+            Some(SortKey::Deadline) => matches.sort_by(|a, b| missing_last(sort_date(a.item.deadline()), sort_date(b.item.deadline()))),
+            Some(SortKey::Scheduled) => matches.sort_by(|a, b| missing_last(sort_date(a.item.scheduled()), sort_date(b.item.scheduled()))),
+            Some(SortKey::File) => matches.sort_by(|a, b| a.doc.cmp(&b.doc).then(a.item.line().cmp(&b.item.line()))),
+            None => {},
+        }
+
+        let Some(group) = self.group else {
+            return vec![QueryGroup { name: None, matches }];
+        };
+        let mut groups: Vec<QueryGroup> = Vec::new();
+        for found in matches {
+            let names: Vec<Option<String>> = match group {
+                GroupKey::Category => vec![found.item.category().map(str::to_string)],
+                GroupKey::File => vec![Some(found.doc.clone())],
+                GroupKey::Tag => {
+                    let tags: Vec<Option<String>> = found.item.tags().map(|tag| Some(tag.to_string())).collect();
+                    if tags.is_empty() { vec![None] } else { tags }
+                },
+            };
+            for name in names {
+                match groups.iter_mut().find(|group| group.name == name) {
+                    Some(group) => group.matches.push(found.clone()),
+                    None => groups.push(QueryGroup { name, matches: vec![found.clone()] }),
+                }
+            }
+        }
+        groups.sort_by(|a, b| missing_last(a.name.as_deref(), b.name.as_deref()));
+        groups
+    }
+}
+
+impl Predicate {
+    fn matches(&self, doc: &str, item: &TodoItem, today: NaiveDate) -> bool {
+        match self {
+            Predicate::Keyword(keywords) => item.keyword().is_some_and(|keyword| keywords.iter().any(|k| k == keyword)),
+            Predicate::Done(done) => item.keyword().is_some() && item.is_done() == *done,
+            Predicate::Tags(tags) => item.tags().any(|tag| tags.iter().any(|t| t.eq_ignore_ascii_case(tag))),
+            Predicate::Category(categories) => item.category()
+                .is_some_and(|category| categories.iter().any(|c| c.eq_ignore_ascii_case(category))),
+            // Priorities compare like in org: `A` is the highest
+            Predicate::Priority(comparison, priority) => item.priority()
+                .is_some_and(|p| comparison.holds(priority.cmp(&p.to_ascii_uppercase()))),
+            Predicate::Date(field, comparison, value) => {
+                let timestamp = match field {
+                    DateField::Deadline => item.deadline(),
+                    DateField::Scheduled => item.scheduled(),
+                    DateField::Closed => item.closed(),
+                };
+                match (timestamp, value.resolve(today)) {
+                    (Some(timestamp), Some(date)) => comparison.holds(timestamp.date.cmp(&date)),
+                    _ => false,
+                }
+            },
+            Predicate::Property(key, value) => match (item.property(key), value) {
+                (Some(actual), Some(value)) => actual.eq_ignore_ascii_case(value),
+                (actual, None) => actual.is_some(),
+                (None, Some(_)) => false,
+            },
+            Predicate::File(file) => doc.to_lowercase().contains(file.as_str()),
+            Predicate::Heading(text) => item.heading().to_lowercase().contains(text.as_str()),
+        }
+    }
+}
+
+impl Comparison {
+    /// Whether `ordering`, of the item's value compared to the query's, satisfies the comparison.
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering == Ordering::Equal,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::Le => ordering != Ordering::Greater,
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl DateValue {
+    fn resolve(self, today: NaiveDate) -> Option<NaiveDate> {
+        let (value, unit) = match self {
+            DateValue::Absolute(date) => return Some(date),
+            DateValue::Relative(value, unit) => (value, unit),
+        };
+        match unit {
+            TimeUnit::Day => today.checked_add_signed(Duration::days(value)),
+            TimeUnit::Week => today.checked_add_signed(Duration::weeks(value)),
+            TimeUnit::Month | TimeUnit::Year => {
+                let months = if unit == TimeUnit::Year { value.checked_mul(12)? } else { value };
+                let shift = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+                if months < 0 { today.checked_sub_months(shift) } else { today.checked_add_months(shift) }
+            },
+            TimeUnit::Hour => None,
+        }
+    }
+}
+
+/// A term of the query without its quotes, which remembers where it came from
+/// so that errors point into the query as it was typed.
+struct Token {
+    start: usize,
+    text: String,
+    /// Byte offset in the query of every byte of `text`
+    offsets: Vec<usize>,
+}
+
+impl Token {
+    fn new(start: usize) -> Self {
+        Token { start, text: String::new(), offsets: Vec::new() }
+    }
+
+    /// Byte offset in the query of the byte at `offset` in `text`, or of the
+    /// end of the text.
+    fn input_offset(&self, offset: usize) -> usize {
+        self.offsets.get(offset).copied()
+            .unwrap_or_else(|| self.offsets.last().map_or(self.start, |last| last + 1))
+    }
+}
+
+/// Splits the query at spaces outside of double quotes.
+fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut current: Option<Token> = None;
+    let mut quote_start = None;
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => {
+                current.get_or_insert_with(|| Token::new(i));
+                quote_start = if quote_start.is_some() { None } else { Some(i) };
+            },
+            c if c.is_whitespace() && quote_start.is_none() => tokens.extend(current.take()),
+            c => {
+                let token = current.get_or_insert_with(|| Token::new(i));
+                token.text.push(c);
+                token.offsets.extend(i..i + c.len_utf8());
+            },
+        }
+    }
+    if let Some(start) = quote_start {
+        return Err(QueryError { position: input[..start].chars().count(), message: "Unclosed quote".to_string() });
+    }
+    tokens.extend(current);
+
+    Ok(tokens)
+}
+
+/// Splits `field:value` or `field>=value` into its parts.
+fn split_term(token: &str) -> Option<(&str, Comparison, &str)> {
+    let field_end = token.find(|c: char| !c.is_ascii_lowercase() && c != '_')?;
+    let (field, rest) = token.split_at(field_end);
+    if field.is_empty() {
+        return None;
+    }
+    let operators = [(">=", Comparison::Ge), ("<=", Comparison::Le), (":", Comparison::Eq),
+                     ("=", Comparison::Eq), ("<", Comparison::Lt), (">", Comparison::Gt)];
+    operators.into_iter()
+        .find_map(|(operator, comparison)| rest.strip_prefix(operator).map(|value| (field, comparison, value)))
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+fn parse_priority(value: &str) -> Option<char> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphanumeric() => Some(c.to_ascii_uppercase()),
+        _ => None,
+    }
+}
+
+/// `2024-01-31`, `today`, `tomorrow`, `yesterday` or an offset like `+7d` or `-2w`.
+fn parse_date(value: &str) -> Option<DateValue> {
+    match value.to_lowercase().as_str() {
+        "today" => return Some(DateValue::Relative(0, TimeUnit::Day)),
+        "tomorrow" => return Some(DateValue::Relative(1, TimeUnit::Day)),
+        "yesterday" => return Some(DateValue::Relative(-1, TimeUnit::Day)),
+        _ => {},
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(DateValue::Absolute(date));
+    }
+
+    let (sign, rest) = match value.as_bytes().first()? {
+        b'+' => (1, &value[1..]),
+        b'-' => (-1, &value[1..]),
+        _ => return None,
+    };
+    let unit = match rest.chars().last()? {
+        'd' => TimeUnit::Day,
+        'w' => TimeUnit::Week,
+        'm' => TimeUnit::Month,
+        'y' => TimeUnit::Year,
+        _ => return None,
+    };
+    let value: u32 = rest[..rest.len() - 1].parse().ok()?;
+    Some(DateValue::Relative(sign * i64::from(value), unit))
+}
+
+fn sort_date(timestamp: Option<&Timestamp>) -> Option<(NaiveDate, Option<NaiveTime>)> {
+    timestamp.map(|ts| (ts.date, ts.time))
+}
+
+/// Orders `Some` values before `None`.
+fn missing_last<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParserConfig, doc_to_headlines};
+
+    const DOC: &str = "#+CATEGORY: Home
+* NEXT [#A] Buy milk :buy:
+DEADLINE: <2024-01-31 Wed>
+* WAIT [#C] Buy a sofa :buy:someday:
+* DONE Pay rent
+CLOSED: [2024-01-28 Sun 10:00]
+* NEXT Call mum
+SCHEDULED: <2024-01-30 Tue>
+:PROPERTIES:
+:CATEGORY: Family
+:PHONE: 555
+:END:
+* Notes
+";
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, 29).unwrap()
+    }
+
+    fn run(query: &str) -> Vec<String> {
+        let query = Query::parse(query).unwrap();
+        let config = ParserConfig::from_sequences(&["NEXT WAIT | DONE"]).unwrap();
+        let mut headings = Vec::new();
+        doc_to_headlines(DOC, &config, |item| if query.matches("/home.org", &item, today()) {
+            headings.push(item.heading().to_string());
+        });
+        headings
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(run("todo:NEXT"), ["Buy milk", "Call mum"]);
+        assert_eq!(run("todo:NEXT,WAIT -tags:someday"), ["Buy milk", "Call mum"]);
+        assert_eq!(run("tags:buy"), ["Buy milk", "Buy a sofa"]);
+        assert_eq!(run("done:yes"), ["Pay rent"]);
+        assert_eq!(run("done:no"), ["Buy milk", "Buy a sofa", "Call mum"]);
+        assert_eq!(run("priority>=B"), ["Buy milk"]);
+        assert_eq!(run("priority<B"), ["Buy a sofa"]);
+        assert_eq!(run("priority:a"), ["Buy milk"]);
+        assert_eq!(run("category:Family"), ["Call mum"]);
+        assert_eq!(run("category:home,family").len(), 5);
+        assert_eq!(run("prop:PHONE=555"), ["Call mum"]);
+        assert_eq!(run("property:phone"), ["Call mum"]);
+        assert_eq!(run("buy"), ["Buy milk", "Buy a sofa"]);
+        assert_eq!(run("\"a sofa\""), ["Buy a sofa"]);
+        assert_eq!(run("heading:\"call mum\" file:home"), ["Call mum"]);
+        assert_eq!(run("file:work"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(run("deadline<+7d"), ["Buy milk"]);
+        assert_eq!(run("deadline<+2d"), Vec::<String>::new());
+        assert_eq!(run("deadline:2024-01-31"), ["Buy milk"]);
+        assert_eq!(run("closed>=yesterday"), ["Pay rent"]);
+        assert_eq!(run("closed>=today"), Vec::<String>::new());
+        assert_eq!(run("scheduled<=tomorrow"), ["Call mum"]);
+        assert_eq!(run("-deadline<+1m"), ["Buy a sofa", "Pay rent", "Call mum", "Notes"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |query: &str| Query::parse(query).unwrap_err();
+
+        assert_eq!(error("todo:NEXT colour:red"), QueryError { position: 10, message: "Unknown field `colour`".to_string() });
+        assert_eq!(error("tags:buy priority>=Z9").position, 19);
+        assert_eq!(error("deadline<soon").to_string(),
+                   "Invalid value `soon` for `deadline`, expected a date like `2024-01-31`, `today` or `+7d` at column 10");
+        assert_eq!(error("todo>NEXT").message, "`todo` can only be compared with `:`");
+        assert_eq!(error("todo: NEXT"), QueryError { position: 5, message: "Missing value for `todo`".to_string() });
+        assert_eq!(error("buy -").position, 4);
+        assert_eq!(error("heading:\"open").position, 8);
+        assert_eq!(error("-sort:file").message, "`sort` can't be negated");
+        assert_eq!(error("group:week").position, 6);
+        assert_eq!(error("é colour:red").position, 2);
+        assert_eq!(error("\"tags\":buy \"priority\">=Z9").position, 23);
+        assert_eq!(error("heading:\"a b\" \"todo\":").position, 21);
+        assert_eq!(error("\"prop\"erty:x \"dead\"line:soon").position, 24);
+    }
+
+    #[test]
+    fn test_arrange() {
+        let config = ParserConfig::from_sequences(&["NEXT WAIT | DONE"]).unwrap();
+        let mut matches = Vec::new();
+        for (doc, content) in [("/home.org", DOC), ("/work.org", "* NEXT [#B] Report :work:\nDEADLINE: <2024-01-30 Tue>")] {
+            doc_to_headlines(content, &config, |item| if item.keyword().is_some() {
+                matches.push(QueryMatch { doc: doc.to_string(), version: String::new(), item: item.into_owned() });
+            });
+        }
+        let headings = |group: &QueryGroup| group.matches.iter().map(|m| m.item.heading().to_string()).collect::<Vec<_>>();
+
+        let groups = Query::parse("sort:priority").unwrap().arrange(matches.clone());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(headings(&groups[0]), ["Buy milk", "Report", "Buy a sofa", "Pay rent", "Call mum"]);
+
+        let groups = Query::parse("sort:deadline").unwrap().arrange(matches.clone());
+        assert_eq!(headings(&groups[0])[..2], ["Report", "Buy milk"]);
+
+        let groups = Query::parse("group:category sort:file").unwrap().arrange(matches.clone());
+        let names: Vec<Option<&str>> = groups.iter().map(|group| group.name.as_deref()).collect();
+        assert_eq!(names, [Some("Family"), Some("Home"), None]);
+        assert_eq!(headings(&groups[2]), ["Report"]);
+
+        let groups = Query::parse("group:tag").unwrap().arrange(matches);
+        let names: Vec<Option<&str>> = groups.iter().map(|group| group.name.as_deref()).collect();
+        assert_eq!(names, [Some("buy"), Some("someday"), Some("work"), None]);
+        assert_eq!(headings(&groups[0]), ["Buy milk", "Buy a sofa"]);
+    }
+}
@@ -1,51 +1,445 @@
-use orgize::{Org, Event, Element};
-use std::fmt::Write;
+use orgize::{Org, Event, Element, elements::{Datetime, Table, TableCell, TableRow, Timestamp, Title}, export::HtmlEscape};
+use std::{borrow::Cow, fmt::Write};
 
-use crate::doc::{OrgDoc, StaticOrgDoc};
+use crate::{doc::OrgDoc, highlight, links::{LinkResolver, LinkTarget}, parser::{ParserConfig, doc_to_headlines}};
 
-fn render(content: impl AsRef<str>) -> String {
-    let org = Org::parse(content.as_ref());
-    let mut out = String::new();
+const IMAGE_EXTENSIONS: &[&str] = &[".png", ".jpg", ".jpeg", ".gif", ".svg", ".webp"];
+
+fn render(content: impl AsRef<str>, config: &ParserConfig, with_anchors: bool, links: Option<&LinkResolver>) -> String {
+    let config = &*config.for_doc(content.as_ref());
+    let org = Org::parse_custom(content.as_ref(), config.as_org_config());
+    let anchors = with_anchors.then(|| {
+        let mut anchors = Vec::new();
+        doc_to_headlines(content.as_ref(), config, |item| anchors.push(item.anchor().to_string()));
+        anchors.into_iter()
+    });
+    let mut renderer = HtmlRenderer{ config, out: String::new(), checkbox_pending: false, anchors, last_line: 0, links };
 
     for event in org.iter() {
         match event {
-            Event::Start(Element::Title(title)) => {
-                write!(out, "<h{level}>{}</h{level}>", title.raw, level = title.level).expect("Writing to string should never fail");
+            Event::Start(element) => renderer.start(element),
+            Event::End(element) => renderer.end(element),
+        }
+    }
+
+    renderer.out
+}
+
+pub trait DocRender: OrgDoc {
+    fn render(&self) -> String {
+        self.render_with(&ParserConfig::default())
+    }
+
+    fn render_with(&self, config: &ParserConfig) -> String {
+        render(self.content(), config, false, None)
+    }
+
+    /// Like [`DocRender::render_with`], giving every heading the `id` from [`crate::parser::TodoItem::anchor`].
+    fn render_with_anchors(&self, config: &ParserConfig) -> String {
+        render(self.content(), config, true, None)
+    }
+
+    /// Like [`DocRender::render_with_anchors`], pointing links to other documents,
+    /// headlines and IDs at their pages and marking those that are broken.
+    fn render_with_links(&self, config: &ParserConfig, links: &LinkResolver) -> String {
+        render(self.content(), config, true, Some(links))
+    }
+}
+
+impl<D: OrgDoc + ?Sized> DocRender for D {}
+
+struct HtmlRenderer<'c, 'l> {
+    config: &'c ParserConfig,
+    out: String,
+    /// Set when a list item starts, so that its first text can be checked for a `[ ]` checkbox
+    checkbox_pending: bool,
+    /// Heading ids in document order, when they are rendered
+    anchors: Option<std::vec::IntoIter<String>>,
+    /// Number of the last line of the previous numbered source block, for `+n`
+    last_line: usize,
+    links: Option<&'c LinkResolver<'l>>,
+}
+
+macro_rules! emit {
+    ($self:ident, $($arg:tt)*) => {
+        write!($self.out, $($arg)*).expect("Writing to string should never fail")
+    };
+}
+
+impl HtmlRenderer<'_, '_> {
+    fn start(&mut self, element: &Element) {
+        match element {
+            Element::Document { .. } | Element::Section | Element::Headline { .. } => {},
+            Element::Paragraph { .. } => emit!(self, "<p>"),
+            Element::Bold => emit!(self, "<b>"),
+            Element::Italic => emit!(self, "<i>"),
+            Element::Strike => emit!(self, "<s>"),
+            Element::Underline => emit!(self, "<u>"),
+            Element::Verbatim { value } | Element::Code { value } => emit!(self, "<code>{}</code>", HtmlEscape(value)),
+            Element::Text { value } => self.text(value),
+            Element::List(list) => emit!(self, "{}", if list.ordered { "<ol>" } else { "<ul>" }),
+            Element::ListItem(_) => {
+                emit!(self, "<li>");
+                self.checkbox_pending = true;
+            },
+            Element::Title(title) => self.title_start(title),
+            Element::Table(Table::Org { has_header, .. }) => {
+                emit!(self, "<table>{}", if *has_header { "<thead>" } else { "<tbody>" });
+            },
+            Element::Table(Table::TableEl { value, .. }) => emit!(self, "<pre class=\"table\">{}</pre>", HtmlEscape(value)),
+            Element::TableRow(TableRow::Header) | Element::TableRow(TableRow::Body) => emit!(self, "<tr>"),
+            Element::TableRow(TableRow::HeaderRule) => emit!(self, "</thead><tbody>"),
+            Element::TableRow(TableRow::BodyRule) => emit!(self, "</tbody><tbody>"),
+            Element::TableCell(TableCell::Header) => emit!(self, "<th>"),
+            Element::TableCell(TableCell::Body) => emit!(self, "<td>"),
+            Element::SourceBlock(block) => self.source_block(&block.language, &block.arguments, &block.contents),
+            Element::ExampleBlock(block) => emit!(self, "<pre class=\"example\">{}</pre>", HtmlEscape(&block.contents)),
+            Element::FixedWidth(fixed) => emit!(self, "<pre class=\"example\">{}</pre>", HtmlEscape(&fixed.value)),
+            Element::ExportBlock(block) => if block.data.eq_ignore_ascii_case("html") {
+                emit!(self, "{}", block.contents);
+            },
+            Element::QuoteBlock(_) => emit!(self, "<blockquote>"),
+            Element::CenterBlock(_) => emit!(self, "<div class=\"center\">"),
+            Element::VerseBlock(_) => emit!(self, "<p class=\"verse\">"),
+            Element::SpecialBlock(block) => emit!(self, "<div class=\"{}\">", HtmlEscape(block.name.to_lowercase())),
+            Element::Drawer(drawer) => {
+                emit!(self, "<details class=\"drawer\"><summary>{}</summary>", HtmlEscape(&drawer.name));
+            },
+            Element::Link(link) => self.link(&link.path, link.desc.as_deref()),
+            Element::Timestamp(timestamp) => self.timestamp(timestamp),
+            Element::Cookie(cookie) => emit!(self, "<code class=\"cookie\">{}</code>", HtmlEscape(&cookie.value)),
+            Element::InlineSrc(src) => {
+                emit!(self, "<code class=\"src src-{}\">{}</code>", HtmlEscape(&src.lang), HtmlEscape(&src.body));
+            },
+            Element::Snippet(snippet) => if snippet.name.eq_ignore_ascii_case("html") {
+                emit!(self, "{}", snippet.value);
             },
+            Element::Target(target) => emit!(self, "<span id=\"{}\"></span>", HtmlEscape(&target.target)),
+            Element::FnRef(fn_ref) => {
+                emit!(self, "<sup><a class=\"footref\" href=\"#fn.{label}\">{label}</a></sup>", label = HtmlEscape(&fn_ref.label));
+            },
+            Element::FnDef(fn_def) => {
+                emit!(self, "<div class=\"footdef\" id=\"fn.{label}\"><sup>{label}</sup> ", label = HtmlEscape(&fn_def.label));
+            },
+            Element::Rule(_) => emit!(self, "<hr>"),
+            Element::Keyword(_) | Element::BabelCall(_) | Element::Comment(_) | Element::CommentBlock(_)
+                | Element::Clock(_) | Element::DynBlock(_) | Element::InlineCall(_) | Element::Macros(_)
+                | Element::RadioTarget => {},
+        }
+    }
+
+    fn end(&mut self, element: &Element) {
+        match element {
+            Element::Paragraph { .. } => emit!(self, "</p>"),
+            Element::Bold => emit!(self, "</b>"),
+            Element::Italic => emit!(self, "</i>"),
+            Element::Strike => emit!(self, "</s>"),
+            Element::Underline => emit!(self, "</u>"),
+            Element::List(list) => emit!(self, "{}", if list.ordered { "</ol>" } else { "</ul>" }),
+            Element::ListItem(_) => emit!(self, "</li>"),
+            Element::Title(title) => self.title_end(title),
+            Element::Table(Table::Org { .. }) => emit!(self, "</tbody></table>"),
+            Element::TableRow(TableRow::Header) | Element::TableRow(TableRow::Body) => emit!(self, "</tr>"),
+            Element::TableCell(TableCell::Header) => emit!(self, "</th>"),
+            Element::TableCell(TableCell::Body) => emit!(self, "</td>"),
+            Element::QuoteBlock(_) => emit!(self, "</blockquote>"),
+            Element::CenterBlock(_) | Element::SpecialBlock(_) | Element::FnDef(_) => emit!(self, "</div>"),
+            Element::VerseBlock(_) => emit!(self, "</p>"),
+            Element::Drawer(_) => emit!(self, "</details>"),
             _ => {},
         }
     }
 
-    out
+    fn text(&mut self, value: &str) {
+        if std::mem::take(&mut self.checkbox_pending) {
+            let checkbox = ["[ ]", "[X]", "[x]", "[-]"].iter()
+                .find(|checkbox| value.starts_with(*checkbox) && value[3..].starts_with(char::is_whitespace));
+            if let Some(checkbox) = checkbox {
+                let state = match *checkbox {
+                    "[ ]" => "",
+                    "[-]" => " class=\"partial\"",
+                    _ => " checked",
+                };
+                emit!(self, "<input type=\"checkbox\" disabled{state}>{}", HtmlEscape(&value[3..]));
+                return;
+            }
+        }
+
+        emit!(self, "{}", HtmlEscape(value));
+    }
+
+    /// Highlights a source block, or escapes it for unknown languages, numbering its
+    /// lines with the `-n` and `+n` switches.
+    fn source_block(&mut self, language: &str, switches: &str, contents: &str) {
+        let code = highlight::highlight(contents, language)
+            .unwrap_or_else(|| HtmlEscape(contents).to_string());
+        emit!(self, "<pre class=\"src src-{}\"><code>", HtmlEscape(language));
+        match line_numbers(switches, self.last_line) {
+            Some(first) => {
+                let count = highlight::number_lines(&code, first, &mut self.out);
+                self.last_line = first.saturating_add(count).saturating_sub(1);
+            },
+            None => self.out.push_str(&code),
+        }
+        emit!(self, "</code></pre>");
+    }
+
+    fn title_start(&mut self, title: &Title) {
+        match self.anchors.as_mut().and_then(Iterator::next) {
+            Some(anchor) => emit!(self, "<h{} id=\"{}\">", title.level.min(6), HtmlEscape(anchor)),
+            None => emit!(self, "<h{}>", title.level.min(6)),
+        }
+        if let Some(keyword) = &title.keyword {
+            let class = if self.config.is_done(keyword) { "done" } else { "todo" };
+            emit!(self, "<span class=\"keyword {class}\">{}</span> ", HtmlEscape(keyword));
+        }
+        if let Some(priority) = title.priority {
+            emit!(self, "<span class=\"priority priority-{priority}\">[#{priority}]</span> ");
+        }
+    }
+
+    fn title_end(&mut self, title: &Title) {
+        if !title.tags.is_empty() {
+            emit!(self, " <span class=\"tags\">");
+            for tag in &title.tags {
+                emit!(self, "<span class=\"tag\">{}</span>", HtmlEscape(tag));
+            }
+            emit!(self, "</span>");
+        }
+        emit!(self, "</h{}>", title.level.min(6));
+
+        if let Some(planning) = &title.planning {
+            emit!(self, "<p class=\"planning\">");
+            let entries = [("CLOSED", &planning.closed), ("DEADLINE", &planning.deadline), ("SCHEDULED", &planning.scheduled)];
+            for (name, timestamp) in entries {
+                if let Some(timestamp) = timestamp {
+                    emit!(self, "<span class=\"planning-keyword\">{name}:</span> ");
+                    self.timestamp(timestamp);
+                    emit!(self, " ");
+                }
+            }
+            self.out.truncate(self.out.trim_end().len());
+            emit!(self, "</p>");
+        }
+    }
+
+    fn link(&mut self, path: &str, desc: Option<&str>) {
+        let text = desc.unwrap_or(path);
+        match self.links.map_or(LinkTarget::External, |links| links.resolve(path)) {
+            LinkTarget::Internal(url) => emit!(self, "<a href=\"{}\">{}</a>", HtmlEscape(url), HtmlEscape(text)),
+            LinkTarget::Broken => {
+                emit!(self, "<a class=\"broken-link\" title=\"Broken link: {}\">{}</a>", HtmlEscape(path), HtmlEscape(text));
+            },
+            LinkTarget::External => {
+                let target = path.strip_prefix("file:").unwrap_or(path);
+                let is_image = IMAGE_EXTENSIONS.iter().any(|ext| target.to_lowercase().ends_with(ext));
+                match desc {
+                    None if is_image => emit!(self, "<img src=\"{}\" alt=\"{}\">", HtmlEscape(target), HtmlEscape(target)),
+                    _ => emit!(self, "<a href=\"{}\">{}</a>", HtmlEscape(target), HtmlEscape(text)),
+                }
+            },
+        }
+    }
+
+    fn timestamp(&mut self, timestamp: &Timestamp) {
+        emit!(self, "<span class=\"timestamp\">{}</span>", HtmlEscape(format_timestamp(timestamp)));
+    }
 }
 
-pub(crate) trait DocRender: OrgDoc {
-    fn render(&self) -> String;
+/// Number of the first line of a source block with the switches `switches`: `-n 10`
+/// starts at 10 and `+n 10` skips 10 after `last_line` of the previous block.
+fn line_numbers(switches: &str, last_line: usize) -> Option<usize> {
+    let mut words = switches.split_whitespace();
+    let (switch, value) = loop {
+        let word = words.next()?;
+        if word == "-n" || word == "+n" {
+            break (word, words.next().and_then(|value| value.parse::<usize>().ok()));
+        }
+    };
+    Some(match switch {
+        "-n" => value.unwrap_or(1),
+        _ => last_line.saturating_add(value.unwrap_or(0)).saturating_add(1),
+    })
 }
 
-impl DocRender for StaticOrgDoc {
-    fn render(&self) -> String {
-        render(self.content())
+/// Formats a timestamp the way it is written in an org file, e.g. `<2024-01-31 Wed 10:00 +1w>`.
+pub fn format_timestamp(timestamp: &Timestamp) -> String {
+    fn datetime(out: &mut String, dt: &Datetime) {
+        write!(out, "{}-{:02}-{:02} {}", dt.year, dt.month, dt.day, dt.dayname).expect("Writing to string should never fail");
+        if let (Some(hour), Some(minute)) = (dt.hour, dt.minute) {
+            write!(out, " {hour:02}:{minute:02}").expect("Writing to string should never fail");
+        }
+    }
+
+    fn modifiers(out: &mut String, repeater: &Option<Cow<str>>, delay: &Option<Cow<str>>) {
+        for modifier in [repeater, delay].into_iter().flatten() {
+            out.push(' ');
+            out.push_str(modifier.as_ref());
+        }
     }
+
+    let mut out = String::new();
+    match timestamp {
+        Timestamp::Active { start, repeater, delay } | Timestamp::Inactive { start, repeater, delay } => {
+            let (open, close) = if matches!(timestamp, Timestamp::Active { .. }) { ('<', '>') } else { ('[', ']') };
+            out.push(open);
+            datetime(&mut out, start);
+            modifiers(&mut out, repeater, delay);
+            out.push(close);
+        },
+        Timestamp::ActiveRange { start, end, repeater, delay } | Timestamp::InactiveRange { start, end, repeater, delay } => {
+            let (open, close) = if matches!(timestamp, Timestamp::ActiveRange { .. }) { ('<', '>') } else { ('[', ']') };
+            out.push(open);
+            datetime(&mut out, start);
+            modifiers(&mut out, repeater, delay);
+            out.push(close);
+            out.push_str("--");
+            out.push(open);
+            datetime(&mut out, end);
+            out.push(close);
+        },
+        Timestamp::Diary { value } => write!(out, "<%%({value})>").expect("Writing to string should never fail"),
+    }
+
+    out
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::doc::StaticOrgDoc;
+    use crate::{doc::{StaticOrgDoc, StaticOrgSource}, links::LinkIndex};
 
     #[test]
     fn test_render_emtpy_doc() {
-        let doc = StaticOrgDoc("");
+        let doc = StaticOrgDoc::new("");
         assert_eq!(doc.render(), "");
     }
 
     #[test]
     fn test_render_heading() {
-        let doc_1 = StaticOrgDoc("* Main heading");
+        let doc_1 = StaticOrgDoc::new("* Main heading");
         assert_eq!(doc_1.render(), "<h1>Main heading</h1>");
 
-        let doc_2 = StaticOrgDoc("** Sub-heading");
+        let doc_2 = StaticOrgDoc::new("** Sub-heading");
         assert_eq!(doc_2.render(), "<h2>Sub-heading</h2>");
     }
+
+    #[test]
+    fn test_render_heading_anchors() {
+        let doc = StaticOrgDoc::new("* TODO Main heading :tag:\n** Sub-heading\n:PROPERTIES:\n:CUSTOM_ID: sub\n:END:\n* Main heading");
+        let output = doc.render_with_anchors(&ParserConfig::default());
+        assert!(output.starts_with("<h1 id=\"main-heading\"><span class=\"keyword todo\">TODO</span> Main heading"));
+        assert!(output.contains("<h2 id=\"sub\">Sub-heading</h2>"));
+        assert!(output.ends_with("<h1 id=\"main-heading-1\">Main heading</h1>"));
+    }
+
+    #[test]
+    fn test_render_heading_badges() {
+        let doc = StaticOrgDoc::new("* NEXT [#A] Buy groceries :buy:home:");
+        let config = ParserConfig::with_keywords(&["NEW", "NEXT"], &["DONE"]);
+        assert_eq!(doc.render_with(&config), concat!(
+            "<h1><span class=\"keyword todo\">NEXT</span> <span class=\"priority priority-A\">[#A]</span> Buy groceries ",
+            "<span class=\"tags\"><span class=\"tag\">buy</span><span class=\"tag\">home</span></span></h1>"));
+
+        let doc = StaticOrgDoc::new("* DONE Order soy sauce");
+        assert_eq!(doc.render_with(&config), "<h1><span class=\"keyword done\">DONE</span> Order soy sauce</h1>");
+
+        let doc = StaticOrgDoc::new("#+TODO: WAIT | CLND\n* CLND Order soy sauce");
+        assert!(doc.render_with(&config).contains("<h1><span class=\"keyword done\">CLND</span> Order soy sauce</h1>"));
+    }
+
+    #[test]
+    fn test_render_planning() {
+        let doc = StaticOrgDoc::new("* TODO Task\nDEADLINE: <2024-02-01 Thu> SCHEDULED: <2024-01-29 Mon 10:00>\n");
+        assert!(doc.render().contains(concat!(
+            "<p class=\"planning\"><span class=\"planning-keyword\">DEADLINE:</span> <span class=\"timestamp\">&lt;2024-02-01 Thu&gt;</span> ",
+            "<span class=\"planning-keyword\">SCHEDULED:</span> <span class=\"timestamp\">&lt;2024-01-29 Mon 10:00&gt;</span></p>")));
+    }
+
+    #[test]
+    fn test_render_paragraph_and_emphasis() {
+        let doc = StaticOrgDoc::new("Some *bold*, /italic/, ~code~ and <escaped> text");
+        assert_eq!(doc.render(), "<p>Some <b>bold</b>, <i>italic</i>, <code>code</code> and &lt;escaped&gt; text</p>");
+    }
+
+    #[test]
+    fn test_render_list_with_checkboxes() {
+        let doc = StaticOrgDoc::new("- [ ] first\n- [X] second\n- third\n");
+        let output = doc.render();
+        assert_eq!(output, concat!(
+            "<ul><li><p><input type=\"checkbox\" disabled> first</p></li>",
+            "<li><p><input type=\"checkbox\" disabled checked> second</p></li>",
+            "<li><p>third</p></li></ul>"));
+    }
+
+    #[test]
+    fn test_render_table() {
+        let doc = StaticOrgDoc::new("| a | b |\n|---+---|\n| 1 | 2 |\n");
+        assert_eq!(doc.render(), "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody><tr><td>1</td><td>2</td></tr></tbody></table>");
+    }
+
+    #[test]
+    fn test_render_blocks() {
+        let doc = StaticOrgDoc::new("#+BEGIN_SRC rust\nfn main() {}\n#+END_SRC\n");
+        let output = doc.render();
+        assert!(output.starts_with("<pre class=\"src src-rust\"><code><span class=\"hl-source hl-rust\">"));
+        assert!(output.contains("<span class=\"hl-entity hl-name hl-function hl-rust\">main</span>"));
+
+        let doc = StaticOrgDoc::new("#+BEGIN_SRC unknown\na < b\n#+END_SRC\n");
+        assert_eq!(doc.render(), "<pre class=\"src src-unknown\"><code>a &lt; b\n</code></pre>");
+
+        let doc = StaticOrgDoc::new("#+BEGIN_EXAMPLE\na < b\n#+END_EXAMPLE\n");
+        assert_eq!(doc.render(), "<pre class=\"example\">a &lt; b\n</pre>");
+
+        let doc = StaticOrgDoc::new("#+BEGIN_QUOTE\nquoted\n#+END_QUOTE\n");
+        assert_eq!(doc.render(), "<blockquote><p>quoted</p></blockquote>");
+    }
+
+    #[test]
+    fn test_render_line_numbers() {
+        let doc = StaticOrgDoc::new(concat!(
+            "#+BEGIN_SRC text -n\na\nb\n#+END_SRC\n",
+            "#+BEGIN_SRC text +n\nc\n#+END_SRC\n",
+            "#+BEGIN_SRC text -n 10 :results silent\nd\n#+END_SRC\n",
+            "#+BEGIN_SRC text +n 5\ne\n#+END_SRC\n",
+            "#+BEGIN_SRC text\nf\n#+END_SRC\n"));
+        let output = doc.render();
+        let numbers: Vec<_> = output.match_indices("<span class=\"line-number\">")
+            .map(|(i, tag)| output[i + tag.len()..].split('<').next().unwrap())
+            .collect();
+        assert_eq!(numbers, ["1", "2", "3", "10", "16"]);
+        assert_eq!(line_numbers(":results silent", 3), None);
+
+        let doc = StaticOrgDoc::new(concat!(
+            "#+BEGIN_SRC text +n 18446744073709551615\na\nb\n#+END_SRC\n",
+            "#+BEGIN_SRC text +n\nc\n#+END_SRC\n"));
+        assert_eq!(doc.render().matches("<span class=\"line-number\">18446744073709551615</span>").count(), 3);
+    }
+
+    #[test]
+    fn test_render_links() {
+        let doc = StaticOrgDoc::new("[[https://orgmode.org][Org]] and [[./image.png]]");
+        assert_eq!(doc.render(), "<p><a href=\"https://orgmode.org\">Org</a> and <img src=\"./image.png\" alt=\"./image.png\"></p>");
+    }
+
+    #[tokio::test]
+    async fn test_render_resolved_links() {
+        let mut source = StaticOrgSource::default();
+        source.add_doc("garden.org", "* Beds\n:PROPERTIES:\n:ID: beds-id\n:END:\n");
+        let links = LinkIndex::default();
+        links.refresh(&source, &ParserConfig::default()).await.unwrap();
+
+        let doc = StaticOrgDoc::new("[[id:beds-id][Beds]], [[file:garden.org]], [[file:cellar.org][Cellar]] and [[https://orgmode.org][Org]]");
+        assert_eq!(doc.render_with_links(&ParserConfig::default(), &links.resolver("/notes.org")), concat!(
+            "<p><a href=\"/garden.org#beds\">Beds</a>, <a href=\"/garden.org\">file:garden.org</a>, ",
+            "<a class=\"broken-link\" title=\"Broken link: file:cellar.org\">Cellar</a> and ",
+            "<a href=\"https://orgmode.org\">Org</a></p>"));
+    }
+
+    #[test]
+    fn test_render_drawer_collapsed() {
+        let doc = StaticOrgDoc::new("* Heading\n:LOGBOOK:\nsome note\n:END:\n");
+        assert!(doc.render().contains("<details class=\"drawer\"><summary>LOGBOOK</summary>"));
+        assert!(!doc.render().contains("<details open"));
+    }
 }
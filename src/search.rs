@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    ops::Bound,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use orgize::export::HtmlEscape;
+
+use crate::{
+    doc::{OrgDoc, OrgSource, SourceError},
+    edit::content_version,
+    parser::{ParserConfig, doc_to_headlines},
+    timestamp::Planning,
+};
+
+const HEADING_WEIGHT: u32 = 3;
+const TAG_WEIGHT: u32 = 2;
+const TEXT_WEIGHT: u32 = 1;
+
+/// Characters of body text shown around the first match.
+const SNIPPET_LENGTH: usize = 160;
+
+/// Full-text index over the headlines of all documents of a source.
+///
+/// Every headline is indexed together with its body, tags and property values,
+/// text before the first headline forms a section of its own. [`SearchIndex::refresh`]
+/// only re-indexes documents whose modification time or content changed.
+#[derive(Default)]
+pub struct SearchIndex {
+    index: Mutex<Index>,
+}
+
+/// A section of a document matching all words of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub doc: String,
+    /// Line of the headline, 1 for text before the first headline
+    pub line: usize,
+    /// `None` for text before the first headline
+    pub heading: Option<String>,
+    /// See [`crate::parser::TodoItem::anchor`]
+    pub anchor: Option<String>,
+    /// HTML excerpt of the body with the matching words in `<mark>`
+    pub snippet: String,
+    pub score: u32,
+}
+
+impl SearchHit {
+    /// Link to the headline in the rendered document.
+    pub fn url(&self) -> String {
+        match &self.anchor {
+            Some(anchor) => format!("{}#{anchor}", self.doc),
+            None => self.doc.clone(),
+        }
+    }
+}
+
+impl SearchIndex {
+    /// Brings the index up to date with the documents `source` lists.
+    /// Documents that can't be read on their own are skipped, failures of the
+    /// whole source are returned.
+    pub async fn refresh<S: OrgSource>(&self, source: &S, config: &ParserConfig) -> Result<(), SourceError> {
+        let docs = source.list().await?;
+        for path in &docs {
+            let modified = source.modified(path).await;
+            if modified.is_some() && self.index.lock().unwrap().modified(path) == modified {
+                continue;
+            }
+            let doc = match source.read(path).await {
+                Ok(doc) => doc,
+                Err(e @ (SourceError::Io(_) | SourceError::Remote(_))) => return Err(e),
+                Err(_) => continue,
+            };
+            self.index.lock().unwrap().update(path, modified, doc.content(), config);
+        }
+        self.index.lock().unwrap().retain(&docs);
+        Ok(())
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        self.index.lock().unwrap().search(query, limit)
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    docs: HashMap<Arc<str>, IndexedDoc>,
+    /// Postings of every word, sorted so words starting with a prefix are next to each other
+    terms: BTreeMap<String, Vec<Posting>>,
+}
+
+struct IndexedDoc {
+    modified: Option<SystemTime>,
+    version: String,
+    sections: Vec<Section>,
+    terms: Vec<String>,
+}
+
+struct Section {
+    line: usize,
+    heading: Option<String>,
+    anchor: Option<String>,
+    body: String,
+}
+
+struct Posting {
+    doc: Arc<str>,
+    section: usize,
+    weight: u32,
+}
+
+impl Index {
+    fn modified(&self, doc: &str) -> Option<SystemTime> {
+        self.docs.get(doc).and_then(|indexed| indexed.modified)
+    }
+
+    /// Indexes `content` unless it is the version already indexed, returns whether it was.
+    fn update(&mut self, doc: &str, modified: Option<SystemTime>, content: &str, config: &ParserConfig) -> bool {
+        let version = content_version(content);
+        if let Some(indexed) = self.docs.get_mut(doc) {
+            if indexed.version == version {
+                indexed.modified = modified;
+                return false;
+            }
+        }
+        self.remove(doc);
+
+        let doc: Arc<str> = Arc::from(doc);
+        let mut weights: HashMap<(String, usize), u32> = HashMap::new();
+        let sections = sections(content, config, |section, text, weight| {
+            for (start, end) in words(text) {
+                *weights.entry((text[start..end].to_lowercase(), section)).or_default() += weight;
+            }
+        });
+
+        let mut terms: Vec<String> = Vec::new();
+        for ((term, section), weight) in weights {
+            let postings = self.terms.entry(term.clone()).or_default();
+            if postings.last().map(|posting| posting.doc != doc).unwrap_or(true) {
+                terms.push(term);
+            }
+            postings.push(Posting { doc: Arc::clone(&doc), section, weight });
+        }
+
+        self.docs.insert(doc, IndexedDoc { modified, version, sections, terms });
+        true
+    }
+
+    fn remove(&mut self, doc: &str) {
+        let Some(indexed) = self.docs.remove(doc) else {
+            return;
+        };
+        for term in indexed.terms {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.retain(|posting| &*posting.doc != doc);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Drops the documents not in `docs`.
+    fn retain(&mut self, docs: &[String]) {
+        let removed: Vec<Arc<str>> = self.docs.keys()
+            .filter(|doc| !docs.iter().any(|d| d.as_str() == &***doc))
+            .cloned()
+            .collect();
+        for doc in removed {
+            self.remove(&doc);
+        }
+    }
+
+    /// Finds the sections containing all words of `query`, best matches first. Words
+    /// match as prefixes, so `meet` finds `meeting`, but whole words score higher.
+    fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let query = query_terms(query);
+        let mut scores: Option<HashMap<(&Arc<str>, usize), u32>> = None;
+        for term in &query {
+            let mut term_scores: HashMap<(&Arc<str>, usize), u32> = HashMap::new();
+            let words = self.terms.range::<str, _>((Bound::Included(term.as_str()), Bound::Unbounded));
+            for (word, postings) in words.take_while(|(word, _)| word.starts_with(term.as_str())) {
+                let factor = if word == term { 2 } else { 1 };
+                for posting in postings {
+                    *term_scores.entry((&posting.doc, posting.section)).or_default() += posting.weight * factor;
+                }
+            }
+            scores = Some(match scores {
+                None => term_scores,
+                Some(scores) => scores.into_iter()
+                    .filter_map(|(key, score)| term_scores.get(&key).map(|term_score| (key, score + term_score)))
+                    .collect(),
+            });
+        }
+
+        let mut hits: Vec<SearchHit> = scores.unwrap_or_default().into_iter()
+            .map(|((doc, section), score)| {
+                let section = &self.docs[doc].sections[section];
+                SearchHit {
+                    doc: doc.to_string(),
+                    line: section.line,
+                    heading: section.heading.clone(),
+                    anchor: section.anchor.clone(),
+                    snippet: snippet(&section.body, &query),
+                    score,
+                }
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.doc.cmp(&b.doc)).then(a.line.cmp(&b.line)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Splits a document into its sections, calling `index` with the section number,
+/// each piece of searchable text and its weight.
+fn sections(content: &str, config: &ParserConfig, mut index: impl FnMut(usize, &str, u32)) -> Vec<Section> {
+    let mut sections = vec![Section { line: 1, heading: None, anchor: None, body: String::new() }];
+    doc_to_headlines(content, config, |item| {
+        let section = sections.len();
+        index(section, item.heading(), HEADING_WEIGHT);
+        for tag in item.own_tags() {
+            index(section, tag, TAG_WEIGHT);
+        }
+        for (_, value) in item.properties() {
+            index(section, value, TEXT_WEIGHT);
+        }
+        sections.push(Section {
+            line: item.line(),
+            heading: Some(item.heading().to_string()),
+            anchor: Some(item.anchor().to_string()),
+            body: String::new(),
+        });
+    });
+
+    let mut section = 0;
+    let mut in_properties = false;
+    for (index, line) in content.lines().enumerate() {
+        if sections.get(section + 1).map(|next| next.line == index + 1).unwrap_or(false) {
+            section += 1;
+            // Drawers end at the next headline, even without `:END:`
+            in_properties = false;
+            continue;
+        }
+        let trimmed = line.trim();
+        if in_properties || trimmed.eq_ignore_ascii_case(":PROPERTIES:") {
+            in_properties = !trimmed.eq_ignore_ascii_case(":END:");
+            continue;
+        }
+        let text = match trimmed.strip_prefix("#+") {
+            Some(keyword) => keyword.split_once(':').map(|(_, value)| value.trim()).unwrap_or_default(),
+            None if Planning::parse(line).is_some() => "",
+            None => trimmed,
+        };
+        if !text.is_empty() {
+            let body = &mut sections[section].body;
+            if !body.is_empty() {
+                body.push(' ');
+            }
+            body.push_str(text);
+        }
+    }
+
+    for (number, section) in sections.iter().enumerate() {
+        index(number, &section.body, TEXT_WEIGHT);
+    }
+    sections
+}
+
+/// Byte ranges of the words in `text`.
+fn words(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = None;
+    text.char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+        .filter_map(move |(i, c)| match (c.is_alphanumeric(), start) {
+            (true, None) => {
+                start = Some(i);
+                None
+            },
+            (false, Some(s)) => {
+                start = None;
+                Some((s, i))
+            },
+            _ => None,
+        })
+}
+
+/// The distinct lowercase words of a query.
+fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for (start, end) in words(query) {
+        let term = query[start..end].to_lowercase();
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+fn matches_query(word: &str, query: &[String]) -> bool {
+    let word = word.to_lowercase();
+    query.iter().any(|term| word.starts_with(term.as_str()))
+}
+
+/// Escapes `text` for HTML, wrapping the words matching `query` in `<mark>`.
+pub fn highlight(text: &str, query: &str) -> String {
+    highlight_terms(text, &query_terms(query))
+}
+
+fn highlight_terms(text: &str, query: &[String]) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for (start, end) in words(text).filter(|(start, end)| matches_query(&text[*start..*end], query)) {
+        write!(out, "{}<mark>{}</mark>", HtmlEscape(&text[last..start]), HtmlEscape(&text[start..end]))
+            .expect("Writing to string should never fail");
+        last = end;
+    }
+    write!(out, "{}", HtmlEscape(&text[last..])).expect("Writing to string should never fail");
+    out
+}
+
+/// Part of `body` around the first match, highlighted.
+fn snippet(body: &str, query: &[String]) -> String {
+    let first_match = words(body).find(|(start, end)| matches_query(&body[*start..*end], query));
+    let words: Vec<(usize, usize)> = words(body).collect();
+    let Some(&(_, last_end)) = words.last() else {
+        return String::new();
+    };
+
+    // Start a few words before the match and stop at the last word that fits
+    let match_index = first_match.and_then(|m| words.iter().position(|w| *w == m)).unwrap_or(0);
+    let first = match_index.saturating_sub(5);
+    let start = if first == 0 { 0 } else { words[first].0 };
+    let end = words[first..].iter()
+        .take_while(|(_, end)| body[start..*end].chars().count() <= SNIPPET_LENGTH)
+        .last()
+        .map(|(_, end)| *end)
+        .unwrap_or(words[first].1);
+
+    // Keep the punctuation after the last word
+    let end = if end == last_end { body.len() } else { end };
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&highlight_terms(&body[start..end], query));
+    if end < body.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "#+TITLE: Household
+Notes about the house.
+* TODO Call the plumber :home:
+SCHEDULED: <2024-01-29 Mon>
+:PROPERTIES:
+:CONTACT: Mario Rossi
+:END:
+The kitchen sink is leaking.
+* Meeting notes
+** Budget meeting
+Plumbing costs were discussed.
+";
+
+    fn index(docs: &[(&str, &str)]) -> Index {
+        let mut index = Index::default();
+        for (path, content) in docs {
+            index.update(path, None, content, &ParserConfig::default());
+        }
+        index
+    }
+
+    fn found(hits: &[SearchHit]) -> Vec<(&str, usize)> {
+        hits.iter().map(|hit| (hit.doc.as_str(), hit.line)).collect()
+    }
+
+    #[test]
+    fn test_search_fields() {
+        let index = index(&[("/home.org", DOC)]);
+
+        assert_eq!(found(&index.search("kitchen", 10)), [("/home.org", 3)]);
+        assert_eq!(found(&index.search("home", 10)), [("/home.org", 3)]);
+        assert_eq!(found(&index.search("rossi", 10)), [("/home.org", 3)]);
+        assert_eq!(found(&index.search("house", 10)), [("/home.org", 1)]);
+        assert_eq!(found(&index.search("household", 10)), [("/home.org", 1)]);
+        assert!(index.search("scheduled", 10).is_empty());
+        assert!(index.search("contact", 10).is_empty());
+        assert!(index.search("", 10).is_empty());
+
+        let index = self::index(&[("/roof.org", "* Roof\n:PROPERTIES:\n:ID: 1\n* Gutter\nFull of leaves.\n")]);
+        assert_eq!(found(&index.search("leaves", 10)), [("/roof.org", 4)]);
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let index = index(&[("/home.org", DOC)]);
+
+        // Headings weigh more than the body
+        let hits = index.search("meeting", 10);
+        assert_eq!(found(&hits), [("/home.org", 9), ("/home.org", 10)]);
+        assert_eq!(hits[1].anchor.as_deref(), Some("budget-meeting"));
+        assert_eq!(hits[1].url(), "/home.org#budget-meeting");
+        assert_eq!(found(&index.search("notes", 10)), [("/home.org", 9), ("/home.org", 1)]);
+
+        // Prefixes match, all words have to
+        assert_eq!(found(&index.search("plumb", 10)), [("/home.org", 3), ("/home.org", 10)]);
+        assert_eq!(found(&index.search("plumb costs", 10)), [("/home.org", 10)]);
+        assert_eq!(found(&index.search("plumb", 1)), [("/home.org", 3)]);
+    }
+
+    #[test]
+    fn test_snippets() {
+        let index = index(&[("/home.org", DOC)]);
+        assert_eq!(index.search("sink", 10)[0].snippet, "The kitchen <mark>sink</mark> is leaking.");
+
+        let body = (0..50).map(|i| format!("word{i}")).collect::<Vec<_>>().join(" ");
+        let snippet = snippet(&body, &query_terms("word30"));
+        assert!(snippet.starts_with("…word25 "));
+        assert!(snippet.contains("<mark>word30</mark>"));
+        assert!(snippet.ends_with('…'));
+
+        assert_eq!(highlight("<b>Plumbing</b> & co", "plumb"), "&lt;b&gt;<mark>Plumbing</mark>&lt;/b&gt; &amp; co");
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut index = index(&[("/home.org", DOC), ("/work.org", "* Budget review")]);
+        assert_eq!(index.search("budget", 10).len(), 2);
+
+        assert!(!index.update("/home.org", None, DOC, &ParserConfig::default()));
+        assert!(index.update("/work.org", None, "* Quarterly review", &ParserConfig::default()));
+        assert_eq!(found(&index.search("budget", 10)), [("/home.org", 10)]);
+        assert_eq!(found(&index.search("quarterly", 10)), [("/work.org", 1)]);
+
+        index.retain(&["/work.org".to_string()]);
+        assert!(index.search("budget", 10).is_empty());
+        assert!(!index.terms.contains_key("budget"));
+    }
+}
@@ -1,106 +1,976 @@
-use axum::{Router, routing, extract, extract::State, http::StatusCode};
+use std::{collections::{BTreeMap, HashMap}, future::Future, net::{IpAddr, SocketAddr}, sync::Arc, time::SystemTime};
+#[cfg(unix)]
+use std::path::Path;
+
+use axum::{
+    Json, Router, routing, extract, middleware,
+    extract::{Query, State},
+    extract::Form,
+    headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Redirect, Response},
+};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use chrono_tz::Tz;
 use maud::{html, Markup, PreEscaped};
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
 
-use crate::{doc::{OrgDoc, OrgSource}, parser::{self, ParserConfig}, page::Page};
+use crate::{
+    assets::{self, Assets},
+    auth::{self, Auth},
+    api::{self, QueryResult, SearchParams, SearchResult, StateChange, TodoFilter},
+    agenda::{Agenda, AgendaEntry, EntryKind, Span},
+    doc::{DocMetadata, OrgDoc, OrgSource, SourceError, WritableOrgSource},
+    edit::content_version,
+    ical::{self, Calendar},
+    links::{DocLink, LinkIndex},
+    listen::{ListenAddr, Listener},
+    page::{Layout, Page, Template},
+    parser::{self, ParserConfig, TodoItem},
+    query::{Query as OrgQuery, QueryGroup},
+    render::DocRender,
+    search::{self, SearchIndex},
+    settings::{Settings, SettingsError, TlsSettings},
+    tls::{self, TlsConfig, TlsIncoming},
+};
+#[cfg(unix)]
+use crate::listen::UnixIncoming;
 
 pub struct Server {
+    pub address: IpAddr,
     pub port: u16,
     pub parser_config: ParserConfig,
+    pub title: Option<String>,
+    /// Time zone of the timestamps in the documents, for the calendar feed
+    pub timezone: Tz,
+    /// Credentials required for every request but the login page, none by default
+    pub auth: Option<Auth>,
+    /// Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// Accept connections on this listener instead of binding `address` and `port`
+    pub listener: Option<Listener>,
+    /// URLs of stylesheets to link from every page
+    pub stylesheets: Vec<String>,
+    pub favicon: Option<String>,
+    /// Replaces the built-in page layout
+    pub template: Option<Template>,
+    /// The stylesheet and files served under `/static/`
+    pub assets: Assets,
+    /// How long links between documents are trusted, for sources that can't
+    /// tell when documents change
+    pub link_refresh: std::time::Duration,
 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
+            address: IpAddr::from([127, 0, 0, 1]),
             port: 8080,
-            parser_config: ParserConfig::with_keywords(&["NEW", "NEXT"], &["DONE"])
+            parser_config: ParserConfig::with_keywords(&["NEW", "NEXT"], &["DONE"]),
+            title: None,
+            timezone: ical::local_timezone(),
+            auth: None,
+            tls: None,
+            listener: None,
+            stylesheets: Vec::new(),
+            favicon: None,
+            template: None,
+            assets: Assets::default(),
+            link_refresh: std::time::Duration::from_secs(5),
         }
     }
 }
 
 impl Server {
-    pub async fn start<D, S>(self, source: S) -> Result<(), Box<dyn std::error::Error>>
+    pub fn from_settings(settings: &Settings) -> Result<Self, SettingsError> {
+        Ok(Server {
+            address: settings.listen.address,
+            port: settings.listen.port,
+            parser_config: settings.parser_config()?,
+            title: settings.title.clone(),
+            timezone: settings.calendar.timezone()?,
+            auth: settings.auth.as_ref().map(Auth::from_settings).transpose()?,
+            tls: settings.tls.as_ref().map(TlsSettings::config),
+            listener: None,
+            stylesheets: settings.page.stylesheets.clone(),
+            favicon: settings.page.favicon.clone(),
+            template: settings.page.template()?,
+            assets: settings.page.assets()?,
+            link_refresh: settings.cache.poll_interval(),
+        })
+    }
+
+    /// Starts serving the documents of `source` in the background, see [`ServerHandle`].
+    ///
+    /// Returns once the server is listening, on the port the system picked if
+    /// `port` is 0, which [`ServerHandle::local_addr`] tells.
+    /// HTTPS is only served over TCP, not on Unix domain sockets.
+    pub async fn start<D, S>(self, source: S) -> Result<ServerHandle, Box<dyn std::error::Error>>
     where D: OrgDoc + 'static,
           S: OrgSource<Doc = D> + 'static
     {
-        let state = Box::leak(Box::new(ServerState{
-            source, parser_config: self.parser_config,
-        }));
+        self.serve(source, false, routes(api::router())).await
+    }
+
+    /// Like [`Server::start`], but also lets clients change the TODO state of headlines.
+    pub async fn start_writable<D, S>(self, source: S) -> Result<ServerHandle, Box<dyn std::error::Error>>
+    where D: OrgDoc + 'static,
+          S: WritableOrgSource<Doc = D> + 'static
+    {
+        let app = routes(api::router().merge(api::write_router()))
+            .route("/state", routing::post(post_state));
+        self.serve(source, true, app).await
+    }
+
+    async fn serve<D, S>(self, source: S, writable: bool, app: Router<Arc<ServerState<D, S>>>) -> Result<ServerHandle, Box<dyn std::error::Error>>
+    where D: OrgDoc + 'static,
+          S: OrgSource<Doc = D> + 'static
+    {
+        let mut stylesheets = vec![self.assets.stylesheet_url()];
+        stylesheets.extend(self.stylesheets);
+        let layout = Layout {
+            title: self.title,
+            stylesheets,
+            favicon: self.favicon,
+            nav: site_nav(&self.parser_config),
+            template: self.template,
+        };
+        let state = Arc::new(ServerState{
+            source, parser_config: self.parser_config, layout, assets: self.assets, timezone: self.timezone, writable,
+            search: SearchIndex::default(), links: LinkIndex::new(self.link_refresh), auth: self.auth, https: self.tls.is_some(),
+        });
+
+        let app = app
+            .route(auth::LOGIN_PATH, routing::get(auth::get_login).post(auth::post_login))
+            .route("/logout", routing::post(auth::post_logout))
+            .layer(middleware::from_fn_with_state(Arc::clone(&state), auth::require_auth))
+            .layer(middleware::from_fn_with_state(Arc::clone(&state), auth::reject_cross_site))
+            .with_state(state)
+            .into_make_service();
+
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => Listener::Tcp(std::net::TcpListener::bind((self.address, self.port))?),
+        };
+
+        let (shutdown, requested) = watch::channel(false);
+        let mut redirect_addr = None;
+        let (task, local_addr) = match (listener, self.tls) {
+            (Listener::Tcp(listener), Some(tls)) => {
+                listener.set_nonblocking(true)?;
+                let local_addr = listener.local_addr()?;
+                if let Some(http_port) = tls.redirect_http_port {
+                    let redirect_listener = std::net::TcpListener::bind((self.address, http_port))?;
+                    redirect_addr = Some(redirect_listener.local_addr()?);
+                    let redirect = axum::Server::from_tcp(redirect_listener)?
+                        .serve(tls::redirect_router(local_addr.port()).into_make_service())
+                        .with_graceful_shutdown(shutdown_requested(requested.clone()));
+                    tokio::spawn(redirect);
+                }
+                let incoming = TlsIncoming::new(TcpListener::from_std(listener)?, &tls)?;
+                let task = tokio::spawn(axum::Server::builder(incoming).serve(app)
+                                        .with_graceful_shutdown(shutdown_requested(requested)));
+                (task, ListenAddr::Tcp(local_addr))
+            },
+            (Listener::Tcp(listener), None) => {
+                listener.set_nonblocking(true)?;
+                let local_addr = listener.local_addr()?;
+                let task = tokio::spawn(axum::Server::from_tcp(listener)?.serve(app)
+                                        .with_graceful_shutdown(shutdown_requested(requested)));
+                (task, ListenAddr::Tcp(local_addr))
+            },
+            #[cfg(unix)]
+            (Listener::Unix(_), Some(_)) => return Err("HTTPS can't be served on a Unix domain socket".into()),
+            #[cfg(unix)]
+            (Listener::Unix(listener), None) => {
+                listener.set_nonblocking(true)?;
+                let local_addr = ListenAddr::Unix(listener.local_addr()?.as_pathname().map(Path::to_path_buf));
+                let incoming = UnixIncoming(tokio::net::UnixListener::from_std(listener)?);
+                let task = tokio::spawn(axum::Server::builder(incoming).serve(app)
+                                        .with_graceful_shutdown(shutdown_requested(requested)));
+                (task, local_addr)
+            },
+        };
+
+        Ok(ServerHandle { shutdown: Arc::new(shutdown), task, local_addr, redirect_addr })
+    }
+}
+
+/// A server started in the background, which is listening already.
+///
+/// Shutting it down stops accepting connections and lets the requests in flight
+/// finish. Dropping the handle shuts the server down, too.
+pub struct ServerHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    task: JoinHandle<Result<(), hyper::Error>>,
+    local_addr: ListenAddr,
+    redirect_addr: Option<SocketAddr>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> &ListenAddr {
+        &self.local_addr
+    }
+
+    /// The address plain HTTP is redirected to HTTPS from, see [`TlsConfig::redirect_http_port`].
+    pub fn redirect_addr(&self) -> Option<SocketAddr> {
+        self.redirect_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Shuts the server down once `signal` completes, e.g. [`shutdown_signal`].
+    pub fn shutdown_on(&self, signal: impl Future<Output = ()> + Send + 'static) {
+        let shutdown = Arc::clone(&self.shutdown);
+        tokio::spawn(async move {
+            signal.await;
+            shutdown.send_replace(true);
+        });
+    }
+
+    /// Waits until the server stopped, after a shutdown or because it failed.
+    pub async fn wait(self) -> Result<(), hyper::Error> {
+        let ServerHandle { shutdown: _shutdown, task, .. } = self;
+        match task.await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Shuts the server down and waits for the requests in flight.
+    pub async fn stop(self) -> Result<(), hyper::Error> {
+        self.shutdown();
+        self.wait().await
+    }
+}
+
+async fn shutdown_requested(mut requested: watch::Receiver<bool>) {
+    // An error means the handle is gone, which shuts down as well
+    let _ = requested.wait_for(|requested| *requested).await;
+}
+
+/// Completes on SIGINT, i.e. Ctrl-C, or SIGTERM.
+pub async fn shutdown_signal() {
+    let terminate = async {
+        #[cfg(unix)]
+        if let Ok(mut terminate) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            terminate.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+}
+
+/// Links to the index, the lists of the TODO keywords, the agenda and the search.
+fn site_nav(config: &ParserConfig) -> Vec<(String, String)> {
+    let mut nav = vec![("/".to_string(), "Index".to_string())];
+    nav.extend(config.todo_keywords().map(|keyword| (format!("/todo/{keyword}"), keyword.to_string())));
+    nav.push(("/agenda".to_string(), "Agenda".to_string()));
+    nav.push(("/search".to_string(), "Search".to_string()));
+    nav
+}
+
+fn routes<D, S>(api: Router<Arc<ServerState<D, S>>>) -> Router<Arc<ServerState<D, S>>>
+where D: OrgDoc + 'static,
+      S: OrgSource<Doc = D> + 'static
+{
+    Router::new()
+        .route("/", routing::get(render_index))
+        .route("/*path", routing::get(render_doc))
+        .route("/todo/:keyword", routing::get(list_todos))
+        .route("/agenda", routing::get(render_agenda))
+        .route("/search", routing::get(render_search))
+        .route("/query", routing::get(render_query))
+        .route("/calendar.ics", routing::get(render_calendar))
+        .route(&format!("{}/*path", assets::STATIC_PATH), routing::get(assets::get_static))
+        .nest("/api/v1", api)
+}
+
+pub(crate) struct ServerState<D, S>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    pub(crate) source: S,
+    pub(crate) parser_config: ParserConfig,
+    pub(crate) layout: Layout,
+    pub(crate) assets: Assets,
+    pub(crate) timezone: Tz,
+    /// Whether the source is writable and the routes changing it are served
+    pub(crate) writable: bool,
+    pub(crate) search: SearchIndex,
+    pub(crate) links: LinkIndex,
+    pub(crate) auth: Option<Auth>,
+    /// Whether the server is reached over HTTPS
+    pub(crate) https: bool,
+}
+
+impl<D, S> ServerState<D, S>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    pub(crate) fn page(&self) -> Page<'_> {
+        Page::new(&self.layout)
+    }
+
+    /// Page telling why the request failed, with its status.
+    pub(crate) fn error_page(&self, (status, message): (StatusCode, String)) -> Response {
+        let page = self.page().titled(status.to_string());
+        let markup = page.render(html! {
+            div.error {
+                h1 { (status.as_u16()) " " (status.canonical_reason().unwrap_or_default()) }
+                p { (message) }
+            }
+        });
+        (status, markup).into_response()
+    }
+
+    /// [`ServerState::error_page`] for a failure of the source.
+    fn source_error_page(&self, doc: Option<&str>, e: &SourceError) -> Response {
+        self.error_page(api::source_error(doc, e))
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexParams {
+    /// `modified` lists the documents by their last change, newest first,
+    /// instead of by directory
+    sort: Option<String>,
+}
+
+async fn render_index<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                            Query(params): Query<IndexParams>, headers: HeaderMap) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    if api::wants_json(&headers) {
+        return match api::docs(&state).await {
+            Ok(docs) => Json(docs).into_response(),
+            Err(e) => api::api_error(api::source_error(None, &e)).into_response(),
+        };
+    }
+
+    let paths = match state.source.list().await {
+        Ok(paths) => paths,
+        Err(e) => return state.source_error_page(None, &e),
+    };
+    let mut docs = Vec::new();
+    for path in paths {
+        let modified = state.source.modified(&path).await;
+        docs.push((path, modified));
+    }
+
+    let by_modified = params.sort.as_deref() == Some("modified");
+    let list = if by_modified {
+        docs.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+        html! {
+            ol.recent {
+                @for (path, modified) in &docs {
+                    li { a href=(path) { (state.source.doc_name(path)) } (render_modified(*modified)) }
+                }
+            }
+        }
+    } else {
+        let mut tree = DocTree::default();
+        for (path, modified) in &docs {
+            tree.insert(path, *modified);
+        }
+        tree.render()
+    };
+
+    let page = state.page();
+    page.render(html! {
+        nav.sort {
+            "Sort by "
+            a.current[!by_modified] href="/" { "name" }
+            " "
+            a.current[by_modified] href="/?sort=modified" { "last change" }
+        }
+        (list)
+    }).into_response()
+}
+
+fn render_modified(modified: Option<SystemTime>) -> Markup {
+    html! {
+        @if let Some(modified) = modified {
+            " " time.modified datetime=(DateTime::<Utc>::from(modified).to_rfc3339()) {
+                (DateTime::<Local>::from(modified).format("%Y-%m-%d %H:%M"))
+            }
+        }
+    }
+}
+
+/// Documents grouped by the directories in their paths.
+#[derive(Default)]
+struct DocTree<'a> {
+    dirs: BTreeMap<&'a str, DocTree<'a>>,
+    docs: Vec<(&'a str, &'a str, Option<SystemTime>)>,
+}
+
+impl<'a> DocTree<'a> {
+    fn insert(&mut self, path: &'a str, modified: Option<SystemTime>) {
+        let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let Some(name) = segments.pop() else {
+            return;
+        };
+        let mut node = self;
+        for dir in segments {
+            node = node.dirs.entry(dir).or_default();
+        }
+        node.docs.push((name, path, modified));
+    }
+
+    fn render(&self) -> Markup {
+        let mut docs = self.docs.clone();
+        docs.sort();
+
+        html! {
+            ul {
+                @for (name, tree) in &self.dirs {
+                    li.dir {
+                        span.dir-name { (name) "/" }
+                        (tree.render())
+                    }
+                }
+                @for (name, path, modified) in docs {
+                    li { a href = (path) { (name) } (render_modified(modified)) }
+                }
+            }
+        }
+    }
+}
+
+/// Validators of a document for conditional requests, see [`OrgSource::metadata`].
+struct Validators {
+    etag: Option<ETag>,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators of a representation of a document, which gets a tag of its
+    /// own from `variant`.
+    fn new(metadata: &DocMetadata, variant: &str) -> Self {
+        Validators {
+            etag: format!("\"{}-{variant}\"", metadata.etag).parse().ok(),
+            last_modified: metadata.modified,
+        }
+    }
+
+    /// Whether the client's copy is still current, judging by `If-None-Match`
+    /// or, without one, `If-Modified-Since`.
+    fn fresh(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+            return self.etag.as_ref().is_some_and(|etag| !if_none_match.precondition_passes(etag));
+        }
+        match (headers.typed_get::<IfModifiedSince>(), self.last_modified) {
+            (Some(since), Some(last_modified)) => !since.is_modified(last_modified),
+            _ => false,
+        }
+    }
 
-        let app = Router::new()
-            .route("/", routing::get(render_index))
-            .route("/:filename", routing::get(render_doc))
-            .route("/todo/:keyword", routing::get(list_todos))
-            .with_state(state);
+    fn apply(&self, mut response: Response) -> Response {
+        let headers = response.headers_mut();
+        if let Some(etag) = &self.etag {
+            headers.typed_insert(etag.clone());
+        }
+        if let Some(last_modified) = self.last_modified {
+            headers.typed_insert(LastModified::from(last_modified));
+        }
+        // Always revalidate, documents change without notice
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+        response
+    }
+}
+
+async fn render_doc<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                       extract::Path(path): extract::Path<String>, headers: HeaderMap) -> Response
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let filename = format!("/{path}");
+    let json = api::wants_json(&headers);
+    let metadata = match state.source.metadata(&filename).await {
+        Ok(metadata) => metadata,
+        Err(e) if json => return api::api_error(api::source_error(Some(&filename), &e)).into_response(),
+        Err(e) => return state.source_error_page(Some(&filename), &e),
+    };
+    // The page changes with the documents its links point to
+    let validators = if json {
+        Validators::new(&metadata, "json")
+    } else {
+        // Stale or missing links are better than no page
+        if let Err(e) = state.links.refresh(&state.source, &state.parser_config).await {
+            eprintln!("Cannot refresh the links between documents: {e}");
+        }
+        Validators::new(&metadata, &state.links.version())
+    };
+    if validators.fresh(&headers) {
+        return validators.apply(StatusCode::NOT_MODIFIED.into_response());
+    }
 
-        let addr = ([0, 0, 0, 0], self.port);
-        axum::Server::bind(&addr.into())
-            .serve(app.into_make_service())
-            .await?;
+    let response = if json {
+        match api::doc(&state, &filename).await {
+            Ok(doc) => Json(doc).into_response(),
+            Err(e) => return api::api_error(api::source_error(Some(&filename), &e)).into_response(),
+        }
+    } else {
+        match state.source.read(&filename).await {
+            Ok(doc) => {
+                let title = parser::doc_title(doc.content()).map(str::to_string)
+                    .unwrap_or_else(|| state.source.doc_name(&filename).trim_start_matches('/').to_string());
+                let page = state.page().titled(title);
+                let links = state.links.resolver(&filename);
+                let content = PreEscaped(doc.render_with_links(&state.parser_config, &links));
+                let backlinks = render_backlinks(&state, state.links.backlinks(&filename));
+                page.render(html! { (content) (backlinks) }).into_response()
+            },
+            Err(e) => return state.source_error_page(Some(&filename), &e),
+        }
+    };
+    validators.apply(response)
+}
 
-        Ok(())
+/// The "Linked from" panel of a document, with the headlines linking to it.
+fn render_backlinks<D, S>(state: &ServerState<D, S>, mut backlinks: Vec<DocLink>) -> Markup
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    // Once for each headline linking to the same target
+    backlinks.dedup_by(|a, b| a.from == b.from && a.anchor == b.anchor && a.target == b.target);
+    html! {
+        @if !backlinks.is_empty() {
+            aside.backlinks {
+                h2 { "Linked from" }
+                ul {
+                    @for link in &backlinks {
+                        li {
+                            a href=(link.url()) {
+                                (state.source.doc_name(&link.from))
+                                @if let Some(heading) = &link.heading { " › " (heading) }
+                            }
+                            @if let Some((anchor, heading)) = &link.target {
+                                span.link-target { " to " a href={ "#" (anchor) } { (heading) } }
+                            }
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
-struct ServerState<D, S>
+async fn list_todos<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                          extract::Path(keyword): extract::Path<String>, headers: HeaderMap) -> Response
 where D: OrgDoc,
       S: OrgSource<Doc = D>
 {
-    source: S,
-    parser_config: ParserConfig,
+    if api::wants_json(&headers) {
+        let filter = TodoFilter { keyword: Some(keyword), tag: None, done: None };
+        return match api::todos(&state, &filter).await {
+            Ok(todos) => Json(todos).into_response(),
+            Err(e) => api::api_error(api::source_error(None, &e)).into_response(),
+        };
+    }
+
+    let redirect = format!("/todo/{keyword}");
+    let mut items = Vec::new();
+    let found = api::for_each_doc(&state, |path, doc| {
+        let version = content_version(doc.content());
+        let doc_config = state.parser_config.for_doc(doc.content());
+        doc.items(&state.parser_config, &mut |item| {
+            if item.keyword() == Some(keyword.as_str()) {
+                let form = state.writable.then(|| render_state_form(&doc_config, path, &version, item, &redirect));
+                items.push(html! { (render_todo_item(item)) (form.unwrap_or_default()) });
+            }
+        });
+    }).await;
+    if let Err(e) = found {
+        return state.source_error_page(None, &e);
+    }
+
+    let page = state.page().titled(keyword.as_str());
+    page.render(html! {
+        ol {
+            @for item in items {
+                li { (item) }
+            }
+        }
+    }).into_response()
+}
+
+fn render_todo_item(item: &TodoItem) -> Markup {
+    let has_meta = item.category().is_some() || !item.path().is_empty()
+        || item.scheduled().is_some() || item.deadline().is_some();
+    let tags: Vec<&str> = item.tags().collect();
+
+    html! {
+        @if let Some(keyword) = item.keyword() {
+            strong.keyword.todo[!item.is_done()].done[item.is_done()] { (keyword) }
+        }
+        @if let Some(priority) = item.priority() {
+            " " span.priority { "[#" (priority) "]" }
+        }
+        " " (item.heading())
+        @if !tags.is_empty() {
+            " "
+            span.tags {
+                @for tag in tags {
+                    span.tag { (tag) }
+                }
+            }
+        }
+        @if has_meta {
+            div.meta {
+                @if let Some(category) = item.category() {
+                    span.category { (category) }
+                }
+                @if !item.path().is_empty() {
+                    " " span.outline-path { (item.path().join(" / ")) }
+                }
+                @if let Some(scheduled) = item.scheduled() {
+                    " " span.planning-keyword { "SCHEDULED:" } " " span.timestamp { (scheduled) }
+                }
+                @if let Some(deadline) = item.deadline() {
+                    " " span.planning-keyword { "DEADLINE:" } " " span.timestamp { (deadline) }
+                }
+            }
+        }
+    }
+}
+
+/// Button switching the item to the next keyword of its sequence.
+fn render_state_form(config: &ParserConfig, doc: &str, version: &str, item: &TodoItem, redirect: &str) -> Markup {
+    let next = config.next_keyword(item.keyword());
+
+    html! {
+        form.todo-state method="post" action="/state" {
+            input type="hidden" name="doc" value=(doc);
+            input type="hidden" name="line" value=(item.line());
+            input type="hidden" name="version" value=(version);
+            input type="hidden" name="redirect" value=(redirect);
+            button type="submit" { (next.as_deref().unwrap_or("No keyword")) }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StateForm {
+    doc: String,
+    line: Option<usize>,
+    id: Option<String>,
+    keyword: Option<String>,
+    version: Option<String>,
+    /// Page to go back to, the document by default
+    redirect: Option<String>,
+}
+
+async fn post_state<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                          Form(form): Form<StateForm>) -> Result<Redirect, (StatusCode, String)>
+where D: OrgDoc,
+      S: WritableOrgSource<Doc = D>
+{
+    let change = StateChange {
+        doc: form.doc, line: form.line, id: form.id, keyword: form.keyword, version: form.version,
+    };
+    api::change_state(&state, &change).await?;
+
+    let target = form.redirect
+        .filter(|target| is_local_path(target))
+        .unwrap_or(change.doc);
+    Ok(Redirect::to(&target))
+}
+
+/// Whether a redirect to `target` stays within the server.
+pub(crate) fn is_local_path(target: &str) -> bool {
+    target.starts_with('/') && !target.starts_with("//") && !target.contains('\\')
 }
 
-async fn render_index<D, S>(State(state): State<&ServerState<D, S>>) -> Markup
+#[derive(Deserialize)]
+struct SearchForm {
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn render_search<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                             Query(form): Query<SearchForm>, headers: HeaderMap) -> Response
 where D: OrgDoc,
       S: OrgSource<Doc = D>
 {
-    let paths = state.source.list().await;
-    let docs: Vec<_> = paths.iter()
-        .map(|path| (state.source.doc_name(path), path))
-        .collect();
+    let params = SearchParams { q: form.q.unwrap_or_default(), limit: form.limit };
+    let hits = if params.q.trim().is_empty() { Ok(Vec::new()) } else { api::search(&state, &params).await };
+    let hits = match hits {
+        Ok(hits) => hits,
+        Err(e) if api::wants_json(&headers) => return api::api_error(api::source_error(None, &e)).into_response(),
+        Err(e) => return state.source_error_page(None, &e),
+    };
+    if api::wants_json(&headers) {
+        return Json(hits.into_iter().map(SearchResult::from).collect::<Vec<_>>()).into_response();
+    }
 
-    let page = Page::default();
+    let page = state.page().titled("Search");
     page.render(html! {
-        ul {
-            @for doc in docs {
-                li { a href = (doc.1) { (doc.0) } }
+        form.search method="get" action="/search" {
+            input type="search" name="q" value=(params.q);
+            " "
+            button type="submit" { "Search" }
+        }
+        @if !params.q.trim().is_empty() && hits.is_empty() {
+            p.no-results { "No matches" }
+        }
+        ol.search-results {
+            @for hit in &hits {
+                li {
+                    a href=(hit.url()) {
+                        @match &hit.heading {
+                            Some(heading) => (PreEscaped(search::highlight(heading, &params.q))),
+                            None => (state.source.doc_name(&hit.doc)),
+                        }
+                    }
+                    " " span.doc { (hit.doc) }
+                    @if !hit.snippet.is_empty() {
+                        p.snippet { (PreEscaped(&hit.snippet)) }
+                    }
+                }
             }
         }
-    })
+    }).into_response()
+}
+
+#[derive(Deserialize)]
+struct QueryForm {
+    q: Option<String>,
 }
 
-async fn render_doc<D, S>(State(state): State<&ServerState<D, S>>,
-                       extract::Path(filename): extract::Path<String>) -> Result<Markup, StatusCode>
+async fn render_query<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                            Query(form): Query<QueryForm>, headers: HeaderMap) -> Response
 where D: OrgDoc,
       S: OrgSource<Doc = D>
 {
-    let filename = format!("/{filename}");
-    let page = Page::default();
-    match state.source.read(&filename).await {
-        Ok(doc) => Ok(page.render(html! { pre { (doc.content()) } })),
-        Err(_) => Err(StatusCode::NOT_FOUND)
+    let q = form.q.unwrap_or_default();
+    let parsed = OrgQuery::parse(&q);
+    if api::wants_json(&headers) {
+        return match parsed {
+            Ok(query) => match api::query(&state, &query).await {
+                Ok(groups) => Json(QueryResult::from(groups)).into_response(),
+                Err(e) => api::api_error(api::source_error(None, &e)).into_response(),
+            },
+            Err(e) => api::query_error(e).into_response(),
+        };
+    }
+
+    let page = state.page().titled("Query");
+    let query_form = html! {
+        form.query method="get" action="/query" {
+            input type="search" name="q" value=(q);
+            " "
+            button type="submit" { "Run" }
+        }
+    };
+    let query = match parsed {
+        Ok(query) => query,
+        Err(e) => {
+            let markup = page.render(html! {
+                (query_form)
+                div.query-error {
+                    p { (e) }
+                    pre { (q) "\n" (" ".repeat(e.position)) "^" }
+                }
+            });
+            return (StatusCode::BAD_REQUEST, markup).into_response();
+        },
+    };
+    let groups = if q.trim().is_empty() { Ok(Vec::new()) } else { api::query(&state, &query).await };
+    let groups = match groups {
+        Ok(groups) => groups,
+        Err(e) => return state.source_error_page(None, &e),
+    };
+
+    // The state buttons need each document's own keywords
+    let mut configs = HashMap::new();
+    if state.writable {
+        for group in &groups {
+            for found in &group.matches {
+                if !configs.contains_key(&found.doc) {
+                    if let Ok(doc) = state.source.read(&found.doc).await {
+                        configs.insert(found.doc.clone(), state.parser_config.for_doc(doc.content()).into_owned());
+                    }
+                }
+            }
+        }
     }
+    let redirect = query_url(&q);
+    let render_group = |group: &QueryGroup| html! {
+        ol {
+            @for found in &group.matches {
+                li {
+                    (render_todo_item(&found.item))
+                    " " a.doc href={ (found.doc) "#" (found.item.anchor()) } { (state.source.doc_name(&found.doc)) }
+                    @if let Some(config) = configs.get(&found.doc) {
+                        (render_state_form(config, &found.doc, &found.version, &found.item, &redirect))
+                    }
+                }
+            }
+        }
+    };
+
+    page.render(html! {
+        (query_form)
+        @if query.group().is_some() {
+            @for group in &groups {
+                section.query-group {
+                    h2 { (group.name.as_deref().unwrap_or("(none)")) }
+                    (render_group(group))
+                }
+            }
+        } @else {
+            @for group in &groups {
+                (render_group(group))
+            }
+        }
+    }).into_response()
+}
+
+/// Local URL of the query page for `q`, to come back to after changing a headline.
+fn query_url(q: &str) -> String {
+    let mut url = Url::parse("http://localhost/query").expect("Valid URL");
+    url.query_pairs_mut().append_pair("q", q);
+    format!("{}?{}", url.path(), url.query().unwrap_or_default())
 }
 
-async fn list_todos<D, S>(State(state): State<&ServerState<D, S>>,
-                          extract::Path(keyword): extract::Path<String>) -> Result<Markup, StatusCode>
+#[derive(Deserialize)]
+struct CalendarParams {
+    file: Option<String>,
+    tag: Option<String>,
+    keyword: Option<String>,
+}
+
+async fn render_calendar<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                               Query(params): Query<CalendarParams>) -> Response
 where D: OrgDoc,
       S: OrgSource<Doc = D>
 {
-    let mut items = String::new();
-    for path in state.source.list().await {
-        let doc = state.source.read(&path).await.unwrap();
-        let content = doc.content();
-        parser::doc_to_items(content, &state.parser_config, |item| {
-            if item.keyword() == keyword {
-                items.push_str(&format!("<li><strong>{}</strong> {}</li>", item.keyword(), item.heading()));
+    let mut calendar = Calendar::new(state.timezone, Utc::now()).with_name(state.layout.title.as_deref());
+    let file = params.file.as_deref().map(|file| file.trim_start_matches('/'));
+    let found = api::for_each_doc(&state, |path, doc| {
+        if file.is_some_and(|file| file != path.trim_start_matches('/')) {
+            return;
+        }
+        doc.headlines(&state.parser_config, &mut |item| {
+            if params.tag.as_deref().is_none_or(|tag| item.tags().any(|t| t == tag))
+                && params.keyword.as_deref().is_none_or(|keyword| item.keyword() == Some(keyword)) {
+                calendar.add(path, item);
             }
         });
+    }).await;
+    if let Err(e) = found {
+        let (status, message) = api::source_error(None, &e);
+        return (status, message).into_response();
     }
 
-    let page = Page::default();
+    ([(header::CONTENT_TYPE, "text/calendar; charset=utf-8")], calendar.render()).into_response()
+}
+
+#[derive(Deserialize)]
+struct AgendaParams {
+    span: Option<String>,
+    start: Option<String>,
+}
+
+async fn render_agenda<D, S>(State(state): State<Arc<ServerState<D, S>>>,
+                             Query(params): Query<AgendaParams>, headers: HeaderMap) -> Result<Markup, Response>
+where D: OrgDoc,
+      S: OrgSource<Doc = D>
+{
+    let error = |error: (StatusCode, String)| match api::wants_json(&headers) {
+        true => api::api_error(error).into_response(),
+        false => state.error_page(error),
+    };
+    let span = match params.span.as_deref() {
+        Some(span) => span.parse().map_err(|_| error((StatusCode::BAD_REQUEST, format!("Invalid span: {span}"))))?,
+        None => Span::default(),
+    };
+    let today = Local::now().date_naive();
+    let start = match params.start.as_deref() {
+        Some(start) => NaiveDate::parse_from_str(start, "%Y-%m-%d")
+            .map_err(|_| error((StatusCode::BAD_REQUEST, format!("Invalid start date: {start}"))))?,
+        None => span.default_start(today),
+    };
+    let (Some(previous), Some(next)) = (span.previous(start), span.end(start)) else {
+        return Err(error((StatusCode::BAD_REQUEST, format!("Start date out of range: {start}"))));
+    };
+
+    let mut agenda = Agenda::new(start, span, today);
+    api::for_each_doc(&state, |path, doc| doc.headlines(&state.parser_config, &mut |item| agenda.add(path, item)))
+        .await.map_err(|e| error(api::source_error(None, &e)))?;
+
+    let page = state.page().titled("Agenda");
     Ok(page.render(html! {
-        ol {
-            (PreEscaped(items))
+        nav.agenda-nav {
+            a href={ "/agenda?span=" (span.as_str()) "&start=" (previous) } { "Previous" }
+            " "
+            a href={ "/agenda?span=" (span.as_str()) } { "Today" }
+            " "
+            a href={ "/agenda?span=" (span.as_str()) "&start=" (next) } { "Next" }
+        }
+        @for (day, entries) in agenda.days() {
+            section.agenda-day.today[day == agenda.today()] {
+                h2 { (day.format("%A %-d %B %Y")) }
+                @if !entries.is_empty() {
+                    ul {
+                        @for entry in entries {
+                            li { (render_agenda_entry(entry)) }
+                        }
+                    }
+                }
+            }
         }
     }))
 }
+
+fn render_agenda_entry(entry: &AgendaEntry) -> Markup {
+    let label = match entry.kind {
+        EntryKind::Scheduled => "Scheduled:".to_string(),
+        EntryKind::ScheduledPast { days } => format!("Sched.{days}x:"),
+        EntryKind::Deadline => "Deadline:".to_string(),
+        EntryKind::DeadlineUpcoming { days } => format!("In {days} d.:"),
+        EntryKind::DeadlineOverdue { days } => format!("{days} d. ago:"),
+        EntryKind::Timestamp => String::new(),
+    };
+
+    html! {
+        @if let Some(category) = &entry.category {
+            span.category { (category) ":" } " "
+        }
+        @if let Some(time) = entry.time {
+            span.time { (time.format("%H:%M")) } " "
+        }
+        @if !label.is_empty() {
+            span.agenda-label { (label) } " "
+        }
+        @if let Some(keyword) = &entry.keyword {
+            strong.keyword.done[entry.done] { (keyword) } " "
+        }
+        @if let Some(priority) = entry.priority {
+            span.priority { "[#" (priority) "]" } " "
+        }
+        a href=(entry.doc) { (entry.heading) }
+        @if !entry.tags.is_empty() {
+            " "
+            span.tags {
+                @for tag in &entry.tags {
+                    span.tag { (tag) }
+                }
+            }
+        }
+    }
+}
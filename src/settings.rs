@@ -0,0 +1,483 @@
+use std::{fmt, net::IpAddr, path::{Path, PathBuf}, time::Duration};
+
+use chrono_tz::Tz;
+use config::{Config, ConfigError, Environment, File};
+use ignore::gitignore::GitignoreBuilder;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{assets::Assets, auth::{self, Auth}, ical, page::Template, parser::ParserConfig, tls::{self, TlsConfig}};
+
+/// Prefix of the environment variables overriding the configuration, e.g.
+/// `ORG_SERVER_LISTEN__PORT=9000`.
+pub const ENV_PREFIX: &str = "ORG_SERVER";
+
+/// Config file looked up in the working directory when none is given.
+pub const DEFAULT_CONFIG_FILE: &str = "org-server.toml";
+
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub listen: ListenSettings,
+    pub title: Option<String>,
+    /// Refuse changes to the documents, like ticking off TODO items
+    pub read_only: bool,
+    pub source: SourceSettings,
+    pub todo: TodoSettings,
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub calendar: CalendarSettings,
+    #[serde(default)]
+    pub page: PageSettings,
+    pub auth: Option<AuthSettings>,
+    pub tls: Option<TlsSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListenSettings {
+    /// Only local connections by default, as anyone who can connect can read
+    /// the documents unless `auth` is set
+    pub address: IpAddr,
+    pub port: u16,
+    /// Unix domain socket to listen on instead of `address` and `port`
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    /// Permissions of the socket, e.g. `0o660`
+    #[serde(default)]
+    pub socket_mode: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceSettings {
+    Filesystem {
+        /// Directory with the documents. Its `api`, `static` and `todo`
+        /// subdirectories are skipped, their paths belong to the server.
+        path: PathBuf,
+        /// Levels of subdirectories to serve, unlimited by default
+        #[serde(default)]
+        max_depth: Option<usize>,
+        /// `.gitignore` style patterns of files and directories to skip
+        #[serde(default)]
+        ignore: Vec<String>,
+    },
+    WebDav {
+        base_url: String,
+        username: String,
+        password: String,
+        collection: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TodoSettings {
+    /// Keyword sequences in the `#+TODO:` syntax, e.g. `NEW NEXT | DONE`
+    pub sequences: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheSettings {
+    /// Keep parsed documents in memory, see [`crate::cache::CachedSource`]
+    pub enabled: bool,
+    /// Seconds between checks for changes when the source can't be watched
+    pub poll_interval: u64,
+}
+
+impl CacheSettings {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CalendarSettings {
+    /// IANA name of the time zone of the timestamps, e.g. `Europe/Berlin`, the system's by default
+    pub timezone: Option<String>,
+}
+
+impl CalendarSettings {
+    pub fn timezone(&self) -> Result<Tz, SettingsError> {
+        match &self.timezone {
+            Some(name) => name.parse().map_err(|_| SettingsError::Invalid(format!("unknown calendar timezone {name}"))),
+            None => Ok(ical::local_timezone()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PageSettings {
+    /// URLs of stylesheets to link from every page
+    #[serde(default)]
+    pub stylesheets: Vec<String>,
+    /// URL of the page icon
+    pub favicon: Option<String>,
+    /// Directory with a `page.html` replacing the built-in layout, see [`Template`]
+    pub template_dir: Option<PathBuf>,
+    /// Stylesheet replacing the built-in one
+    pub css: Option<PathBuf>,
+    /// Directory of files to serve under `/static/`, its `style.css` replaces
+    /// the built-in stylesheet
+    pub asset_dir: Option<PathBuf>,
+}
+
+impl PageSettings {
+    pub fn template(&self) -> Result<Option<Template>, SettingsError> {
+        self.template_dir.as_deref()
+            .map(Template::load)
+            .transpose()
+            .map_err(|e| SettingsError::Invalid(format!("page template: {e}")))
+    }
+
+    pub fn assets(&self) -> Result<Assets, SettingsError> {
+        let mut assets = Assets::default();
+        if let Some(dir) = &self.asset_dir {
+            assets = assets.with_dir(dir)
+                .map_err(|e| SettingsError::Invalid(format!("page asset_dir {}: {e}", dir.display())))?;
+        }
+        if let Some(css) = &self.css {
+            assets = assets.with_css(css)
+                .map_err(|e| SettingsError::Invalid(format!("page css {}: {e}", css.display())))?;
+        }
+        Ok(assets)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthSettings {
+    #[serde(default)]
+    pub users: Vec<UserSettings>,
+    /// Tokens for the JSON API and pages requested with `Accept: application/json`,
+    /// sent as `Authorization: Bearer <name>.<secret>`
+    #[serde(default)]
+    pub tokens: Vec<TokenSettings>,
+    /// Seconds until browsers have to log in again
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: u64,
+}
+
+fn default_session_ttl() -> u64 {
+    auth::DEFAULT_SESSION_TTL.as_secs()
+}
+
+impl AuthSettings {
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSettings {
+    pub username: String,
+    /// argon2 or bcrypt hash, see `org-server --hash-password`
+    pub password_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenSettings {
+    /// Picks the token, without a `.`
+    pub name: String,
+    /// argon2 or bcrypt hash of the secret after the name
+    pub token_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsSettings {
+    /// PEM certificate chain, reloaded when it changes
+    pub cert: PathBuf,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1
+    pub key: PathBuf,
+    /// Seconds between checks of the files for changes
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+    /// Port to redirect plain HTTP from
+    #[serde(default)]
+    pub redirect_http_port: Option<u16>,
+}
+
+fn default_reload_interval() -> u64 {
+    tls::DEFAULT_RELOAD_INTERVAL.as_secs()
+}
+
+impl TlsSettings {
+    pub fn config(&self) -> TlsConfig {
+        TlsConfig {
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            reload_interval: Duration::from_secs(self.reload_interval),
+            redirect_http_port: self.redirect_http_port,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Load(ConfigError),
+    Invalid(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Load(e) => write!(f, "Cannot load configuration: {e}"),
+            SettingsError::Invalid(msg) => write!(f, "Invalid configuration: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl From<ConfigError> for SettingsError {
+    fn from(e: ConfigError) -> Self {
+        SettingsError::Load(e)
+    }
+}
+
+impl Settings {
+    /// Loads the settings from the defaults, the config file, the environment
+    /// and the `overrides` (keys like `listen.port`), each overriding the previous one.
+    pub fn load(file: Option<&Path>, overrides: &[(&str, String)]) -> Result<Self, SettingsError> {
+        Self::load_with_env(file, overrides, None)
+    }
+
+    fn load_with_env(file: Option<&Path>, overrides: &[(&str, String)],
+                     env: Option<config::Map<String, String>>) -> Result<Self, SettingsError> {
+        let file = match file {
+            Some(path) => File::from(path).required(true),
+            None => File::from(Path::new(DEFAULT_CONFIG_FILE)).required(false),
+        };
+
+        let mut builder = Config::builder()
+            .set_default("listen.address", "127.0.0.1")?
+            .set_default("listen.port", 8080)?
+            .set_default("read_only", true)?
+            .set_default("source.type", "filesystem")?
+            .set_default("source.path", ".")?
+            .set_default("todo.sequences", vec!["TODO | DONE"])?
+            .set_default("cache.enabled", true)?
+            .set_default("cache.poll_interval", 5)?
+            .add_source(file)
+            .add_source(Environment::with_prefix(ENV_PREFIX)
+                        .prefix_separator("_")
+                        .separator("__")
+                        .list_separator(",")
+                        .with_list_parse_key("todo.sequences")
+                        .try_parsing(true)
+                        .source(env));
+        for (key, value) in overrides {
+            builder = builder.set_override(*key, value.as_str())?;
+        }
+
+        Ok(builder.build()?.try_deserialize()?)
+    }
+
+    /// Checks the parts of the configuration that deserialization can't.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        self.parser_config()?;
+        self.calendar.timezone()?;
+        self.page.template()?;
+        self.page.assets()?;
+
+        match &self.source {
+            SourceSettings::Filesystem { path, ignore, .. } => {
+                if !path.is_dir() {
+                    return Err(SettingsError::Invalid(format!("source path {} is not a directory", path.display())));
+                }
+                let mut builder = GitignoreBuilder::new(path);
+                for pattern in ignore {
+                    builder.add_line(None, pattern)
+                        .map_err(|e| SettingsError::Invalid(format!("source ignore pattern {pattern:?}: {e}")))?;
+                }
+            },
+            SourceSettings::WebDav { base_url, .. } => {
+                if !self.read_only {
+                    return Err(SettingsError::Invalid("WebDAV sources are read-only".to_string()));
+                }
+                let url = Url::parse(base_url)
+                    .map_err(|e| SettingsError::Invalid(format!("source base_url {base_url}: {e}")))?;
+                if url.cannot_be_a_base() {
+                    return Err(SettingsError::Invalid(format!("source base_url {base_url} can't have a path")));
+                }
+            },
+        }
+
+        if let Some(auth) = &self.auth {
+            if auth.users.iter().any(|user| user.username.is_empty() || user.password_hash.is_empty()) {
+                return Err(SettingsError::Invalid("auth users need a username and a password_hash".to_string()));
+            }
+            if auth.users.is_empty() && auth.tokens.is_empty() {
+                return Err(SettingsError::Invalid("auth needs at least one user or token".to_string()));
+            }
+            Auth::from_settings(auth)?;
+        }
+
+        if let Some(socket) = &self.listen.socket {
+            if !cfg!(unix) {
+                return Err(SettingsError::Invalid("listen socket is only supported on Unix".to_string()));
+            }
+            if socket.parent().is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+                return Err(SettingsError::Invalid(format!("listen socket {} is not in a directory", socket.display())));
+            }
+            if self.tls.is_some() {
+                return Err(SettingsError::Invalid("tls can't be served on a listen socket".to_string()));
+            }
+        }
+        if self.listen.socket_mode.is_some_and(|mode| mode > 0o777) {
+            return Err(SettingsError::Invalid("listen socket_mode is more than permission bits".to_string()));
+        }
+
+        if let Some(tls) = &self.tls {
+            tls.config().check().map_err(|e| SettingsError::Invalid(format!("tls: {e}")))?;
+            if tls.redirect_http_port == Some(self.listen.port) {
+                return Err(SettingsError::Invalid("tls redirect_http_port is the port HTTPS is served on".to_string()));
+            }
+            if tls.reload_interval == 0 {
+                return Err(SettingsError::Invalid("tls reload_interval must be at least a second".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn parser_config(&self) -> Result<ParserConfig, SettingsError> {
+        ParserConfig::from_sequences(&self.todo.sequences).map_err(SettingsError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn config_file(content: &str) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        write!(file, "{content}").unwrap();
+        file
+    }
+
+    #[test]
+    fn test_defaults() {
+        let file = config_file("");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+
+        assert_eq!(settings.listen.address.to_string(), "127.0.0.1");
+        assert_eq!(settings.listen.port, 8080);
+        assert!(settings.listen.socket.is_none());
+        assert!(matches!(settings.source, SourceSettings::Filesystem { ref path, max_depth: None, .. } if path == Path::new(".")));
+        assert_eq!(settings.todo.sequences, ["TODO | DONE"]);
+        assert!(settings.title.is_none());
+        assert!(settings.cache.enabled);
+        assert!(settings.read_only);
+        assert_eq!(settings.cache.poll_interval(), Duration::from_secs(5));
+        assert!(settings.calendar.timezone.is_none());
+        assert!(settings.page.stylesheets.is_empty());
+        assert!(settings.page.template_dir.is_none());
+        assert!(settings.auth.is_none());
+        assert!(settings.tls.is_none());
+    }
+
+    #[test]
+    fn test_file_env_and_overrides() {
+        let file = config_file(r#"
+title = "My tasks"
+
+[listen]
+address = "192.168.1.2"
+port = 9000
+
+[source]
+type = "webdav"
+base_url = "https://cloud.example.com"
+username = "user"
+password = "secret"
+collection = "remote.php/dav/files/user/org"
+
+[todo]
+sequences = ["NEW NEXT | DONE", "WAIT | CLND"]
+
+[calendar]
+timezone = "Europe/Berlin"
+"#);
+        let env = [("ORG_SERVER_LISTEN__PORT".to_string(), "9100".to_string()),
+                   ("ORG_SERVER_TITLE".to_string(), "From env".to_string())].into_iter().collect();
+        let overrides = [("title", "From CLI".to_string())];
+        let settings = Settings::load_with_env(Some(file.path()), &overrides, Some(env)).unwrap();
+
+        assert_eq!(settings.listen.address.to_string(), "192.168.1.2");
+        assert_eq!(settings.listen.port, 9100);
+        assert_eq!(settings.title.as_deref(), Some("From CLI"));
+        assert!(matches!(settings.source, SourceSettings::WebDav { ref username, .. } if username == "user"));
+        assert_eq!(settings.todo.sequences.len(), 2);
+        assert_eq!(settings.calendar.timezone().unwrap(), chrono_tz::Europe::Berlin);
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn test_listen_socket() {
+        let file = config_file("[listen]\nsocket = \"org-server.sock\"\nsocket_mode = 0o660");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+
+        assert_eq!(settings.listen.socket.as_deref(), Some(Path::new("org-server.sock")));
+        assert_eq!(settings.listen.socket_mode, Some(0o660));
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let file = config_file("[todo]\nsequences = [\"NEW | | DONE\"]");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[source]\npath = \"/does/not/exist\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[source]\npath = \".\"\nignore = [\"[z-a].org\"]");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("read_only = false\n[source]\ntype = \"webdav\"\nbase_url = \"https://example.com\"\n\
+                                username = \"user\"\npassword = \"secret\"\ncollection = \"org\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[calendar]\ntimezone = \"Europe/Nowhere\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[page]\ntemplate_dir = \"/nonexistent\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[page]\ncss = \"/nonexistent.css\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[[auth.users]]\nusername = \"me\"\npassword_hash = \"plain text\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let file = config_file(&format!("[[auth.tokens]]\nname = \"my.phone\"\ntoken_hash = \"{hash}\""));
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[auth]\nsession_ttl = 60");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[tls]\ncert = \"/does/not/exist.pem\"\nkey = \"/does/not/exist.pem\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[listen]\nsocket = \"/does/not/exist/org.sock\"");
+        let settings = Settings::load_with_env(Some(file.path()), &[], Some(Default::default())).unwrap();
+        assert!(matches!(settings.validate(), Err(SettingsError::Invalid(_))));
+
+        let file = config_file("[listen]\nport = \"not a port\"");
+        assert!(matches!(Settings::load_with_env(Some(file.path()), &[], Some(Default::default())), Err(SettingsError::Load(_))));
+
+        assert!(matches!(Settings::load_with_env(Some(Path::new("/does/not/exist.toml")), &[], Some(Default::default())),
+                         Err(SettingsError::Load(_))));
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use nom::{
+    IResult,
+    branch::alt,
+    bytes::complete::{tag, take_while1, take_while_m_n},
+    character::complete::{char, digit1, one_of, space0, space1},
+    combinator::{map, map_res, opt, verify},
+    sequence::{preceded, tuple},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub value: u32,
+    pub unit: TimeUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeaterKind {
+    /// `+1w`: shift by the interval once
+    Cumulate,
+    /// `++1w`: shift by the interval until the date is in the future
+    CatchUp,
+    /// `.+1w`: shift relative to the completion date
+    Restart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeater {
+    pub kind: RepeaterKind,
+    pub interval: Interval,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelayKind {
+    /// `-3d`: applies to every repetition
+    All,
+    /// `--3d`: applies to the first repetition only
+    First,
+}
+
+/// Warning period of a deadline or delay of a scheduled item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delay {
+    pub kind: DelayKind,
+    pub interval: Interval,
+}
+
+/// An org timestamp like `<2024-01-29 Mon 10:00-11:00 +1w -2d>` or `[2024-01-29 Mon]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    pub active: bool,
+    pub date: NaiveDate,
+    pub time: Option<NaiveTime>,
+    /// End of a range, either on the same day (`10:00-11:00`) or a different one (`<...>--<...>`)
+    pub end: Option<(NaiveDate, Option<NaiveTime>)>,
+    pub repeater: Option<Repeater>,
+    pub delay: Option<Delay>,
+}
+
+impl Timestamp {
+    pub fn parse(input: &str) -> Option<Timestamp> {
+        timestamp(input.trim()).ok().filter(|(rest, _)| rest.is_empty()).map(|(_, ts)| ts)
+    }
+}
+
+/// Finds all active timestamps in a line of text.
+pub fn find_active_timestamps(text: &str) -> Vec<Timestamp> {
+    let mut found = Vec::new();
+    let mut rest = text;
+    while let Some(idx) = rest.find('<') {
+        match timestamp(&rest[idx..]) {
+            Ok((tail, ts)) => {
+                found.push(ts);
+                rest = tail;
+            },
+            Err(_) => rest = &rest[idx + 1..],
+        }
+    }
+
+    found
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Planning {
+    pub scheduled: Option<Timestamp>,
+    pub deadline: Option<Timestamp>,
+    pub closed: Option<Timestamp>,
+}
+
+impl Planning {
+    /// Parses a planning line (the line right after a headline), e.g.
+    /// `DEADLINE: <2024-02-01 Thu -3d> SCHEDULED: <2024-01-29 Mon +1w>`.
+    pub fn parse(line: &str) -> Option<Planning> {
+        let mut planning = Planning::default();
+        let mut input = line.trim_start();
+        if input.is_empty() {
+            return None;
+        }
+
+        while !input.is_empty() {
+            let (rest, (keyword, _, ts)) = tuple((
+                alt((tag("SCHEDULED:"), tag("DEADLINE:"), tag("CLOSED:"))),
+                space0,
+                timestamp,
+            ))(input).ok()?;
+
+            let slot = match keyword {
+                "SCHEDULED:" => &mut planning.scheduled,
+                "DEADLINE:" => &mut planning.deadline,
+                _ => &mut planning.closed,
+            };
+            if slot.is_some() {
+                return None;
+            }
+            *slot = Some(ts);
+            input = rest.trim_start();
+        }
+
+        Some(planning)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled.is_none() && self.deadline.is_none() && self.closed.is_none()
+    }
+}
+
+fn number<'a, T: std::str::FromStr>(digits: usize) -> impl FnMut(&'a str) -> IResult<&'a str, T> {
+    map_res(take_while_m_n(digits, digits, |c: char| c.is_ascii_digit()), str::parse)
+}
+
+fn date(input: &str) -> IResult<&str, NaiveDate> {
+    map_res(
+        tuple((number::<i32>(4), char('-'), number::<u32>(2), char('-'), number::<u32>(2))),
+        |(year, _, month, _, day)| NaiveDate::from_ymd_opt(year, month, day).ok_or(()),
+    )(input)
+}
+
+fn time(input: &str) -> IResult<&str, NaiveTime> {
+    map_res(
+        tuple((verify(digit1, |s: &str| s.len() <= 2), char(':'), number::<u32>(2))),
+        |(hour, _, minute): (&str, char, u32)| {
+            hour.parse().ok().and_then(|hour| NaiveTime::from_hms_opt(hour, minute, 0)).ok_or(())
+        },
+    )(input)
+}
+
+fn interval(input: &str) -> IResult<&str, Interval> {
+    map(
+        tuple((map_res(digit1, str::parse), one_of("hdwmy"))),
+        |(value, unit)| Interval {
+            value,
+            unit: match unit {
+                'h' => TimeUnit::Hour,
+                'd' => TimeUnit::Day,
+                'w' => TimeUnit::Week,
+                'm' => TimeUnit::Month,
+                _ => TimeUnit::Year,
+            },
+        },
+    )(input)
+}
+
+fn repeater(input: &str) -> IResult<&str, Repeater> {
+    let kind = alt((
+        map(tag("++"), |_| RepeaterKind::CatchUp),
+        map(tag(".+"), |_| RepeaterKind::Restart),
+        map(tag("+"), |_| RepeaterKind::Cumulate),
+    ));
+    map(tuple((kind, interval)), |(kind, interval)| Repeater { kind, interval })(input)
+}
+
+fn delay(input: &str) -> IResult<&str, Delay> {
+    let kind = alt((
+        map(tag("--"), |_| DelayKind::First),
+        map(tag("-"), |_| DelayKind::All),
+    ));
+    map(tuple((kind, interval)), |(kind, interval)| Delay { kind, interval })(input)
+}
+
+type Inner = (NaiveDate, Option<NaiveTime>, Option<NaiveTime>, Option<Repeater>, Option<Delay>);
+
+/// The part of a timestamp between the brackets.
+fn inner(input: &str) -> IResult<&str, Inner> {
+    let (input, date) = date(input)?;
+    let (input, _) = opt(preceded(space1, take_while1(|c: char| c.is_alphabetic() || c == '.')))(input)?;
+    let (input, time) = opt(preceded(space1, time))(input)?;
+    let (input, end_time) = match time {
+        Some(_) => opt(preceded(char('-'), self::time))(input)?,
+        None => (input, None),
+    };
+    let (input, mut repeater) = opt(preceded(space1, repeater))(input)?;
+    let (input, delay) = opt(preceded(space1, delay))(input)?;
+    let input = if repeater.is_none() && delay.is_some() {
+        let (input, late_repeater) = opt(preceded(space1, self::repeater))(input)?;
+        repeater = late_repeater;
+        input
+    } else {
+        input
+    };
+    let (input, _) = space0(input)?;
+
+    Ok((input, (date, time, end_time, repeater, delay)))
+}
+
+fn timestamp(input: &str) -> IResult<&str, Timestamp> {
+    let (input, open) = one_of("<[")(input)?;
+    let close = if open == '<' { '>' } else { ']' };
+    let (input, (date, time, end_time, repeater, delay)) = inner(input)?;
+    let (input, _) = char(close)(input)?;
+
+    let (input, end) = match end_time {
+        Some(end_time) => (input, Some((date, Some(end_time)))),
+        None => {
+            let (input, range_end) = opt(preceded(tuple((tag("--"), char(open))), inner))(input)?;
+            match range_end {
+                Some((end_date, end_time, _, _, _)) => {
+                    let (input, _) = char(close)(input)?;
+                    (input, Some((end_date, end_time)))
+                },
+                None => (input, None),
+            }
+        },
+    };
+
+    Ok((input, Timestamp { active: open == '<', date, time, end, repeater, delay }))
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.unit {
+            TimeUnit::Hour => 'h',
+            TimeUnit::Day => 'd',
+            TimeUnit::Week => 'w',
+            TimeUnit::Month => 'm',
+            TimeUnit::Year => 'y',
+        };
+        write!(f, "{}{unit}", self.value)
+    }
+}
+
+impl fmt::Display for Repeater {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = match self.kind {
+            RepeaterKind::Cumulate => "+",
+            RepeaterKind::CatchUp => "++",
+            RepeaterKind::Restart => ".+",
+        };
+        write!(f, "{prefix}{}", self.interval)
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = match self.kind {
+            DelayKind::All => "-",
+            DelayKind::First => "--",
+        };
+        write!(f, "{prefix}{}", self.interval)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (open, close) = if self.active { ('<', '>') } else { ('[', ']') };
+        let write_date = |f: &mut fmt::Formatter<'_>, date: NaiveDate| {
+            write!(f, "{} {}", date.format("%Y-%m-%d"), date.weekday())
+        };
+
+        write!(f, "{open}")?;
+        write_date(f, self.date)?;
+        if let Some(time) = self.time {
+            write!(f, " {}", time.format("%H:%M"))?;
+        }
+        if let Some((_, Some(end_time))) = self.end.filter(|(end_date, _)| *end_date == self.date) {
+            write!(f, "-{}", end_time.format("%H:%M"))?;
+        }
+        if let Some(repeater) = self.repeater {
+            write!(f, " {repeater}")?;
+        }
+        if let Some(delay) = self.delay {
+            write!(f, " {delay}")?;
+        }
+        write!(f, "{close}")?;
+
+        if let Some((end_date, end_time)) = self.end.filter(|(end_date, _)| *end_date != self.date) {
+            write!(f, "--{open}")?;
+            write_date(f, end_date)?;
+            if let Some(end_time) = end_time {
+                write!(f, " {}", end_time.format("%H:%M"))?;
+            }
+            write!(f, "{close}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_simple_timestamps() {
+        let ts = Timestamp::parse("<2024-01-29 Mon>").unwrap();
+        assert!(ts.active);
+        assert_eq!(ts.date, date(2024, 1, 29));
+        assert_eq!(ts.time, None);
+
+        let ts = Timestamp::parse("[2024-01-29 Mon 9:05]").unwrap();
+        assert!(!ts.active);
+        assert_eq!(ts.time, Some(time(9, 5)));
+
+        assert!(Timestamp::parse("<2024-02-30 Fri>").is_none());
+        assert!(Timestamp::parse("<2024-01-29 Mon").is_none());
+        assert!(Timestamp::parse("<2024-01-29 Mon]").is_none());
+    }
+
+    #[test]
+    fn test_parse_repeater_and_delay() {
+        let ts = Timestamp::parse("<2024-01-29 Mon 10:00 .+1d -3d>").unwrap();
+        assert_eq!(ts.repeater, Some(Repeater{ kind: RepeaterKind::Restart, interval: Interval{ value: 1, unit: TimeUnit::Day } }));
+        assert_eq!(ts.delay, Some(Delay{ kind: DelayKind::All, interval: Interval{ value: 3, unit: TimeUnit::Day } }));
+
+        let ts = Timestamp::parse("<2024-01-29 Mon ++2m>").unwrap();
+        assert_eq!(ts.repeater, Some(Repeater{ kind: RepeaterKind::CatchUp, interval: Interval{ value: 2, unit: TimeUnit::Month } }));
+
+        let ts = Timestamp::parse("<2024-01-29 Mon --1w +1y>").unwrap();
+        assert_eq!(ts.delay.unwrap().kind, DelayKind::First);
+        assert_eq!(ts.repeater.unwrap().interval.unit, TimeUnit::Year);
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        let ts = Timestamp::parse("<2024-01-29 Mon 10:00-11:30>").unwrap();
+        assert_eq!(ts.end, Some((date(2024, 1, 29), Some(time(11, 30)))));
+
+        let ts = Timestamp::parse("<2024-01-29 Mon>--<2024-02-02 Fri>").unwrap();
+        assert_eq!(ts.end, Some((date(2024, 2, 2), None)));
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for input in ["<2024-01-29 Mon>", "[2024-01-29 Mon 09:05]", "<2024-01-29 Mon 10:00-11:30 +1w -2d>",
+                      "<2024-01-29 Mon>--<2024-02-02 Fri>"] {
+            assert_eq!(Timestamp::parse(input).unwrap().to_string(), input);
+        }
+    }
+
+    #[test]
+    fn test_find_active_timestamps() {
+        let found = find_active_timestamps("Meeting <2024-01-29 Mon 10:00> and [2024-01-30 Tue] <or> <2024-02-01 Thu>");
+        let dates: Vec<_> = found.iter().map(|ts| ts.date).collect();
+        assert_eq!(dates, [date(2024, 1, 29), date(2024, 2, 1)]);
+    }
+
+    #[test]
+    fn test_parse_planning() {
+        let planning = Planning::parse("DEADLINE: <2024-02-01 Thu -3d> SCHEDULED: <2024-01-29 Mon +1w>").unwrap();
+        assert_eq!(planning.deadline.unwrap().date, date(2024, 2, 1));
+        assert_eq!(planning.scheduled.unwrap().date, date(2024, 1, 29));
+        assert_eq!(planning.closed, None);
+
+        let planning = Planning::parse("  CLOSED: [2024-01-30 Tue 12:00]").unwrap();
+        assert!(!planning.closed.unwrap().active);
+
+        assert!(Planning::parse("Some text").is_none());
+        assert!(Planning::parse("SCHEDULED: <2024-01-29 Mon> and more").is_none());
+        assert!(Planning::parse("").is_none());
+    }
+}
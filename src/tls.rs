@@ -0,0 +1,308 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock, Weak},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{IntoResponse, Redirect, Response},
+};
+use hyper::server::accept::Accept;
+use tokio::{net::{TcpListener, TcpStream}, sync::mpsc};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self, ServerConfig,
+        crypto::ring::sign::any_supported_type,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    server::TlsStream,
+};
+
+/// How often the certificate and key files are checked for changes by default.
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Handshakes taking longer than this are abandoned.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A PEM certificate chain and private key to serve HTTPS with.
+///
+/// The files are reloaded when they change, e.g. when a renewed certificate
+/// was written, without dropping open connections.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub reload_interval: Duration,
+    /// Port to answer plain HTTP on with a redirect to HTTPS, 0 for any free one
+    pub redirect_http_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+            redirect_http_port: None,
+        }
+    }
+
+    pub fn with_reload_interval(mut self, reload_interval: Duration) -> Self {
+        self.reload_interval = reload_interval;
+        self
+    }
+
+    pub fn with_http_redirect(mut self, port: u16) -> Self {
+        self.redirect_http_port = Some(port);
+        self
+    }
+
+    /// Checks that the certificate and key can be loaded.
+    pub fn check(&self) -> Result<(), TlsError> {
+        load_certified_key(&self.cert, &self.key).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, io::Error),
+    NoCertificate(PathBuf),
+    NoKey(PathBuf),
+    InvalidKey(PathBuf, rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Read(path, e) => write!(f, "Cannot read {}: {e}", path.display()),
+            TlsError::NoCertificate(path) => write!(f, "No PEM certificate in {}", path.display()),
+            TlsError::NoKey(path) => write!(f, "No PEM private key in {}", path.display()),
+            TlsError::InvalidKey(path, e) => write!(f, "Unusable private key in {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let cert_pem = fs::read(cert).map_err(|e| TlsError::Read(cert.to_path_buf(), e))?;
+    let chain = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Read(cert.to_path_buf(), e))?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificate(cert.to_path_buf()));
+    }
+
+    let key_pem = fs::read(key).map_err(|e| TlsError::Read(key.to_path_buf(), e))?;
+    let private_key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+        .map_err(|e| TlsError::Read(key.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoKey(key.to_path_buf()))?;
+    let signing_key = any_supported_type(&private_key).map_err(|e| TlsError::InvalidKey(key.to_path_buf(), e))?;
+
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+/// Hands out the certificate last loaded from the files of a [`TlsConfig`].
+#[derive(Debug)]
+struct ReloadingCert {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of the certificate and key when they were loaded
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let modified = (modified(&config.cert), modified(&config.key));
+        let certified_key = load_certified_key(&config.cert, &config.key)?;
+        Ok(ReloadingCert {
+            cert: config.cert.clone(),
+            key: config.key.clone(),
+            current: RwLock::new(Arc::new(certified_key)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reloads the files if either changed, keeping the previous certificate
+    /// when the new one can't be loaded. Returns whether it was replaced.
+    fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let modified = (modified(&self.cert), modified(&self.key));
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let certified_key = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn watch(cert: Weak<ReloadingCert>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(cert) = cert.upgrade() else {
+                return;
+            };
+            if let Err(e) = cert.reload_if_changed() {
+                eprintln!("Keeping the previous TLS certificate: {e}");
+            }
+        }
+    });
+}
+
+/// Connections to a TCP listener after their TLS handshake, for [`axum::Server::builder`].
+pub(crate) struct TlsIncoming {
+    connections: mpsc::Receiver<TlsStream<TcpStream>>,
+}
+
+impl TlsIncoming {
+    pub(crate) fn new(listener: TcpListener, config: &TlsConfig) -> Result<Self, TlsError> {
+        let cert = Arc::new(ReloadingCert::load(config)?);
+        watch(Arc::downgrade(&cert), config.reload_interval);
+
+        let mut server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(cert);
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let (sender, connections) = mpsc::channel(32);
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = sender.closed() => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            // Likely out of file descriptors, like hyper give it some time
+                            eprintln!("Cannot accept connection: {e}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        },
+                    },
+                };
+
+                // Handshake concurrently, so a slow client doesn't hold up the others
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if let Ok(Ok(stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        let _ = sender.send(stream).await;
+                    }
+                });
+            }
+        });
+
+        Ok(TlsIncoming { connections })
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<TcpStream>;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections.poll_recv(cx).map(|connection| connection.map(Ok))
+    }
+}
+
+/// Routes answering every plain HTTP request with a redirect to the same URL on
+/// HTTPS at `https_port`.
+pub(crate) fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(redirect_to_https).with_state(https_port)
+}
+
+async fn redirect_to_https(State(https_port): State<u16>, uri: Uri, headers: HeaderMap) -> Response {
+    let Some(host) = headers.get(header::HOST).and_then(|host| host.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let host = strip_port(host);
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let target = match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    };
+    Redirect::permanent(&target).into_response()
+}
+
+/// The host of a `Host` header, which may be an IPv6 address in brackets.
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path, name: &str) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let config = TlsConfig::new(dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&config.cert, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&config.key, cert.serialize_private_key_pem()).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_cert(dir.path(), "localhost");
+        let cert = ReloadingCert::load(&config).unwrap();
+        let first = Arc::clone(&cert.current.read().unwrap());
+        assert!(!cert.reload_if_changed().unwrap());
+
+        // Pretend an older certificate was loaded, as the file times may be too coarse to tell
+        *cert.modified.lock().unwrap() = (Some(SystemTime::UNIX_EPOCH), Some(SystemTime::UNIX_EPOCH));
+        write_cert(dir.path(), "example.com");
+        assert!(cert.reload_if_changed().unwrap());
+        assert_ne!(first.cert, cert.current.read().unwrap().cert);
+
+        *cert.modified.lock().unwrap() = (Some(SystemTime::UNIX_EPOCH), Some(SystemTime::UNIX_EPOCH));
+        fs::write(&config.key, "not a key").unwrap();
+        assert!(matches!(cert.reload_if_changed(), Err(TlsError::NoKey(_))));
+        assert!(matches!(config.check(), Err(TlsError::NoKey(_))));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TlsConfig::new(dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        assert!(matches!(config.check(), Err(TlsError::Read(..))));
+
+        fs::write(&config.cert, "not a certificate").unwrap();
+        assert!(matches!(config.check(), Err(TlsError::NoCertificate(_))));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:80"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
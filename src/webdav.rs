@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use reqwest::{Client, Method, StatusCode, Url, header};
+use xml::{EventReader, reader::XmlEvent};
+
+use crate::doc::{OrgDoc, OrgSource, SourceError};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+pub struct WebDavSource {
+    client: Client,
+    collection: Url,
+    username: String,
+    password: String,
+}
+
+pub struct WebDavDoc(String);
+
+impl OrgDoc for WebDavDoc {
+    fn content(&self) -> &str {
+        &self.0
+    }
+}
+
+impl WebDavSource {
+    /// `collection` is the path of the directory holding the org files,
+    /// relative to `base_url`, e.g. `remote.php/dav/files/<user>/org`.
+    pub fn new(base_url: Url, username: &str, password: &str, collection: &str) -> Self {
+        assert!(!base_url.cannot_be_a_base());
+        let mut url = base_url;
+        url.path_segments_mut()
+            .expect("Base URL has to have a path")
+            .pop_if_empty()
+            .extend(collection.split('/').filter(|s| !s.is_empty()))
+            .push("");
+
+        Self {
+            client: Client::new(),
+            collection: url,
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn doc_url(&self, name: &str) -> Url {
+        let mut url = self.collection.clone();
+        url.path_segments_mut()
+            .expect("Collection URL has to have a path")
+            .pop_if_empty()
+            .push(name);
+        url
+    }
+
+    async fn propfind(&self) -> Result<String, reqwest::Error> {
+        let method = Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method");
+        self.client.request(method, self.collection.clone())
+            .basic_auth(&self.username, Some(&self.password))
+            .header("Depth", "1")
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send().await?
+            .error_for_status()?
+            .text().await
+    }
+}
+
+#[async_trait]
+impl OrgSource for WebDavSource {
+    type Doc = WebDavDoc;
+
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        let body = self.propfind().await.map_err(remote_error)?;
+        let entries = parse_multistatus(&body)
+            .map_err(|e| SourceError::Remote(format!("Invalid PROPFIND response: {e}")))?;
+
+        Ok(entries.into_iter()
+            .filter(|entry| !entry.collection)
+            .filter_map(|entry| href_file_name(&entry.href))
+            .filter(|name| name.ends_with(".org"))
+            .map(|name| format!("/{name}"))
+            .collect())
+    }
+
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError> {
+        let name = Path::new(doc).file_name().and_then(|s| s.to_str()).ok_or(SourceError::InvalidPath)?;
+        let content = self.client.get(self.doc_url(name))
+            .basic_auth(&self.username, Some(&self.password))
+            .send().await.map_err(remote_error)?
+            .error_for_status().map_err(remote_error)?
+            .text().await.map_err(remote_error)?;
+
+        Ok(WebDavDoc(content))
+    }
+
+    fn doc_name(&self, doc: &str) -> String {
+        Path::new(doc).file_name()
+            .map(|s| s.to_str().expect("Path has to be a valid string").to_string())
+            .expect("Has to be a vaild name")
+    }
+}
+
+fn remote_error(e: reqwest::Error) -> SourceError {
+    match e.status() {
+        Some(StatusCode::NOT_FOUND) => SourceError::NotFound,
+        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => SourceError::Forbidden,
+        _ => SourceError::Remote(e.to_string()),
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct DavEntry {
+    href: String,
+    collection: bool,
+}
+
+/// Parses a `207 Multi-Status` response body into the resources it describes.
+fn parse_multistatus(body: &str) -> Result<Vec<DavEntry>, xml::reader::Error> {
+    let mut entries = Vec::new();
+    let mut current: Option<DavEntry> = None;
+    let mut in_href = false;
+
+    for event in EventReader::from_str(body) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => match name.local_name.as_str() {
+                "response" => current = Some(DavEntry::default()),
+                "href" => in_href = current.is_some(),
+                "collection" => if let Some(entry) = current.as_mut() {
+                    entry.collection = true;
+                },
+                _ => {},
+            },
+            XmlEvent::Characters(text) if in_href => {
+                if let Some(entry) = current.as_mut() {
+                    entry.href.push_str(&text);
+                }
+            },
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "href" => in_href = false,
+                "response" => entries.extend(current.take().filter(|entry| !entry.href.is_empty())),
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+
+    Ok(entries)
+}
+
+fn href_file_name(href: &str) -> Option<String> {
+    let path = href.trim().trim_end_matches('/');
+    let segment = path.rsplit('/').next().filter(|s| !s.is_empty())?;
+    percent_decode(segment)
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTISTATUS: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/tasks.org</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/my%20notes.org</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    #[test]
+    fn test_parse_multistatus() {
+        let entries = parse_multistatus(MULTISTATUS).unwrap();
+
+        assert_eq!(entries, [
+            DavEntry{ href: "/remote.php/dav/files/user/org/".into(), collection: true },
+            DavEntry{ href: "/remote.php/dav/files/user/org/tasks.org".into(), collection: false },
+            DavEntry{ href: "/remote.php/dav/files/user/org/my%20notes.org".into(), collection: false },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_invalid_multistatus() {
+        assert!(parse_multistatus("<d:multistatus xmlns:d=\"DAV:\"><d:response>").is_err());
+        assert!(parse_multistatus("").is_err());
+    }
+
+    #[test]
+    fn test_href_file_name() {
+        assert_eq!(href_file_name("/dav/org/tasks.org"), Some("tasks.org".to_string()));
+        assert_eq!(href_file_name("/dav/org/my%20notes.org"), Some("my notes.org".to_string()));
+        assert_eq!(href_file_name("/dav/org/"), Some("org".to_string()));
+        assert_eq!(href_file_name("/dav/org/bad%2"), None);
+    }
+
+    #[test]
+    fn test_collection_url() {
+        let base = Url::parse("https://cloud.example.com/").unwrap();
+        let source = WebDavSource::new(base, "user", "pass", "remote.php/dav/files/user/org");
+
+        assert_eq!(source.collection.as_str(), "https://cloud.example.com/remote.php/dav/files/user/org/");
+        assert_eq!(source.doc_url("my notes.org").as_str(),
+                   "https://cloud.example.com/remote.php/dav/files/user/org/my%20notes.org");
+    }
+}
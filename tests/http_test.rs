@@ -1,13 +1,21 @@
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::{fs::{self, File}, net::TcpListener, time::{Duration, SystemTime}};
 
-use org_server::{empty_doc::EmptyOrgSource, doc::{OrgSource, StaticOrgSource}, parser::ParserConfig};
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version, password_hash::SaltString};
+use async_trait::async_trait;
+use org_server::{
+    assets::Assets, auth::Auth, empty_doc::EmptyOrgSource, fs_doc::FilesystemSource, doc::{OrgSource, SourceError, StaticOrgDoc, StaticOrgSource, WritableOrgSource},
+    listen::{ListenAddr, Listener}, page::Template, parser::ParserConfig, server::{Server, ServerHandle}, tls::TlsConfig,
+};
 use reqwest::StatusCode;
 use scraper::{Html, Selector, ElementRef};
+use serde_json::{Value, json};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixStream};
 
 
 #[tokio::test]
 async fn test_connect() {
-    let TestServer { port } = prepare_server(EmptyOrgSource).await;
+    let server = prepare_server(EmptyOrgSource).await;
+    let port = server.port;
 
     let resp = reqwest::get(format!("http://0.0.0.0:{port}"))
         .await.unwrap();
@@ -17,7 +25,8 @@ async fn test_connect() {
 
 #[tokio::test]
 async fn test_doc_not_found() {
-    let TestServer { port } = prepare_server(EmptyOrgSource).await;
+    let server = prepare_server(EmptyOrgSource).await;
+    let port = server.port;
 
     let resp = reqwest::get(format!("http://0.0.0.0:{port}/tasks.org"))
         .await.unwrap();
@@ -25,11 +34,57 @@ async fn test_doc_not_found() {
     assert_eq!(resp.status(), StatusCode::NOT_FOUND);
 }
 
+#[tokio::test]
+async fn test_source_errors() {
+    let server = prepare_server(FailingSource { down: false }).await;
+    let port = server.port;
+    let error_heading = Selector::parse(".error h1").unwrap();
+    let get_page = |path: &'static str| async move {
+        let resp = reqwest::get(format!("http://0.0.0.0:{port}{path}")).await.unwrap();
+        let status = resp.status();
+        let html = Html::parse_document(&resp.text().await.unwrap());
+        (status, html)
+    };
+
+    let (status, html) = get_page("/secret.org").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(element_to_text(html.select(&error_heading).next().unwrap()), "403 Forbidden");
+    let (status, html) = get_page("/missing.org").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(element_to_text(html.select(&Selector::parse(".error p").unwrap()).next().unwrap()).contains("/missing.org"));
+    let (status, _) = get_page("/remote.org").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    // Documents that can't be read are left out of the lists
+    let (status, html) = get_page("/todo/TODO").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(html.root_element().html().contains("Visible"));
+
+    let client = reqwest::Client::new();
+    let resp = client.get(format!("http://0.0.0.0:{port}/api/v1/docs/remote.org")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(body["error"].as_str().unwrap().contains("connection refused"));
+
+    let server = prepare_server(FailingSource { down: true }).await;
+    let port = server.port;
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    assert_eq!(element_to_text(html.select(&error_heading).next().unwrap()), "502 Bad Gateway");
+    let resp = client.get(format!("http://0.0.0.0:{port}/api/v1/todos")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    // A document that can be read is shown without the links between documents
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/ok.org")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
 #[tokio::test]
 async fn test_static_org_source() {
     let mut source = StaticOrgSource::default();
     source.add_doc("tasks.org", "the content");
-    let TestServer { port } = prepare_server(source).await;
+    let server = prepare_server(source).await;
+    let port = server.port;
 
     let resp = reqwest::get(format!("http://0.0.0.0:{port}/")).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
@@ -42,6 +97,272 @@ async fn test_static_org_source() {
     assert!(text.contains("the content"));
 }
 
+#[tokio::test]
+async fn test_nested_docs() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Top");
+    source.add_doc("projects/foo.org", "* TODO Nested");
+    source.add_doc("projects/old/bar.org", "* TODO Deeper");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/")).await.unwrap();
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let dirs: Vec<String> = html.select(&Selector::parse("li.dir > .dir-name").unwrap()).map(element_to_text).collect();
+    assert_eq!(dirs, ["projects/", "old/"]);
+    let nested: Vec<&str> = html.select(&Selector::parse("li.dir > ul > li > a").unwrap())
+        .filter_map(|a| a.value().attr("href"))
+        .collect();
+    assert_eq!(nested, ["/projects/old/bar.org", "/projects/foo.org"]);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/projects/old/bar.org")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await.unwrap().contains("Deeper"));
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/docs/projects/foo.org")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/projects/missing.org")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_conditional_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("tasks.org"), "* TODO Task").unwrap();
+    let server = prepare_server(FilesystemSource::new(dir.path())).await;
+    let port = server.port;
+    let client = reqwest::Client::new();
+    let url = format!("http://0.0.0.0:{port}/tasks.org");
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let last_modified = resp.headers()["last-modified"].to_str().unwrap().to_string();
+
+    let resp = client.get(&url).header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    let resp = client.get(&url).header("If-Modified-Since", &last_modified).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let resp = client.get(&url).header("If-None-Match", "\"other\"").header("If-Modified-Since", &last_modified)
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The JSON representation is tagged differently
+    let resp = client.get(&url).header("Accept", "application/json").header("If-None-Match", &etag)
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+
+    fs::write(dir.path().join("tasks.org"), "* DONE Task, changed").unwrap();
+    let resp = client.get(&url).header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await.unwrap().contains("changed"));
+}
+
+#[tokio::test]
+async fn test_doc_links() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("notes")).unwrap();
+    fs::write(dir.path().join("notes/daily.org"),
+              "[[file:../projects.org::*Garden][Garden]], [[id:roof-id][Roof]] and [[file:cellar.org][Cellar]]").unwrap();
+    fs::write(dir.path().join("projects.org"), "* Garden\n* Roof\n:PROPERTIES:\n:ID: roof-id\n:END:\n").unwrap();
+    // Links are refreshed on every request, as the source can't tell about changes
+    let app = Server { link_refresh: Duration::ZERO, ..test_app() };
+    let server = TestServer::new(app.start(FilesystemSource::new(dir.path())).await.unwrap());
+    let port = server.port;
+    let client = reqwest::Client::new();
+    let url = format!("http://0.0.0.0:{port}/notes/daily.org");
+    let links = |html: &str| -> Vec<(Option<String>, String)> {
+        Html::parse_document(html).select(&Selector::parse("main a").unwrap())
+            .map(|a| (a.value().attr("href").map(str::to_string), a.value().attr("class").unwrap_or_default().to_string()))
+            .collect()
+    };
+
+    let resp = client.get(&url).send().await.unwrap();
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert_eq!(links(&resp.text().await.unwrap()), [
+        (Some("/projects.org#garden".to_string()), String::new()),
+        (Some("/projects.org#roof".to_string()), String::new()),
+        (None, "broken-link".to_string()),
+    ]);
+
+    // Creating the missing document fixes the link, and changes the page
+    fs::write(dir.path().join("notes/cellar.org"), "* Shelves").unwrap();
+    let resp = client.get(&url).header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(links(&resp.text().await.unwrap())[2], (Some("/notes/cellar.org".to_string()), String::new()));
+}
+
+#[tokio::test]
+async fn test_backlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("daily.org"), "* Today\nFix [[id:roof-id][the roof]], see [[file:projects.org]]\n").unwrap();
+    fs::write(dir.path().join("projects.org"), "* Roof\n:PROPERTIES:\n:ID: roof-id\n:END:\n").unwrap();
+    // Links are refreshed on every request, as the source can't tell about changes
+    let app = Server { link_refresh: Duration::ZERO, ..test_app() };
+    let server = TestServer::new(app.start(FilesystemSource::new(dir.path())).await.unwrap());
+    let port = server.port;
+    let backlinks = || async move {
+        let html = reqwest::get(format!("http://0.0.0.0:{port}/projects.org")).await.unwrap().text().await.unwrap();
+        Html::parse_document(&html).select(&Selector::parse("aside.backlinks li").unwrap())
+            .map(element_to_text)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(backlinks().await, ["daily.org › Today to Roof", "daily.org › Today"]);
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/links?doc=/projects.org")).await.unwrap();
+    let links: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(links[0], json!({
+        "from": "/daily.org", "line": 2, "heading": "Today", "anchor": "today", "url": "/daily.org#today",
+        "link": "id:roof-id", "to": "/projects.org", "to_anchor": "roof", "to_heading": "Roof",
+    }));
+
+    // Kept current as documents change
+    fs::write(dir.path().join("daily.org"), "* Today\nNothing to fix\n").unwrap();
+    assert_eq!(backlinks().await, Vec::<String>::new());
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/links")).await.unwrap();
+    let links: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(links, json!([]));
+}
+
+#[tokio::test]
+async fn test_index_by_modified() {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, age) in [("old.org", 300), ("new.org", 0), ("middle.org", 100)] {
+        let file = File::create(dir.path().join(name)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+    let server = prepare_server(FilesystemSource::new(dir.path())).await;
+    let port = server.port;
+    let links = |html: &str| -> Vec<String> {
+        Html::parse_document(html).select(&Selector::parse("main li > a").unwrap())
+            .filter_map(|a| a.value().attr("href").map(str::to_string))
+            .collect()
+    };
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/")).await.unwrap();
+    let html = resp.text().await.unwrap();
+    assert_eq!(links(&html), ["/middle.org", "/new.org", "/old.org"]);
+    assert_eq!(Html::parse_document(&html).select(&Selector::parse("li > time.modified").unwrap()).count(), 3);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/?sort=modified")).await.unwrap();
+    assert_eq!(links(&resp.text().await.unwrap()), ["/new.org", "/middle.org", "/old.org"]);
+
+    let resp = reqwest::Client::new().get(format!("http://0.0.0.0:{port}/"))
+        .header("Accept", "application/json").send().await.unwrap();
+    let docs: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(docs[0]["modified"].as_str().is_some());
+}
+
+#[tokio::test]
+async fn test_page_layout() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("household.org", "#+TITLE: Household\n* TODO Water plants");
+    source.add_doc("tasks.org", "* TODO Task");
+    let app = Server { title: Some("My org".to_string()), ..test_app() };
+    let server = TestServer::new(app.start(source).await.unwrap());
+    let port = server.port;
+    let get_html = |path: &'static str| async move {
+        Html::parse_document(&reqwest::get(format!("http://0.0.0.0:{port}{path}")).await.unwrap().text().await.unwrap())
+    };
+    let title = |html: &Html| element_to_text(html.select(&Selector::parse("head > title").unwrap()).next().unwrap());
+
+    let html = get_html("/household.org").await;
+    assert_eq!(title(&html), "Household – My org");
+    assert_eq!(html.select(&Selector::parse("head > meta[charset]").unwrap()).count(), 1);
+    assert_eq!(html.select(&Selector::parse("body > main h1").unwrap()).count(), 1);
+    let nav: Vec<&str> = html.select(&Selector::parse("body > header > nav a").unwrap())
+        .filter_map(|a| a.value().attr("href"))
+        .collect();
+    assert_eq!(nav, ["/", "/todo/TODO", "/agenda", "/search"]);
+
+    assert_eq!(title(&get_html("/tasks.org").await), "tasks.org – My org");
+    assert_eq!(title(&get_html("/").await), "My org");
+    assert_eq!(title(&get_html("/missing.org").await), "404 Not Found – My org");
+}
+
+#[tokio::test]
+async fn test_page_template() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(Template::PAGE_FILE),
+              "<!DOCTYPE html><html><head>{{ head }}</head><body><div id=\"site\">{{ content }}</div></body></html>").unwrap();
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Task");
+    let app = Server { template: Some(Template::load(dir.path()).unwrap()), ..test_app() };
+    let server = TestServer::new(app.start(source).await.unwrap());
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/tasks.org")).await.unwrap();
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    assert_eq!(html.select(&Selector::parse("#site h1").unwrap()).count(), 1);
+    assert_eq!(html.select(&Selector::parse("nav").unwrap()).count(), 0);
+}
+
+#[tokio::test]
+async fn test_static_assets() {
+    let server = prepare_server(EmptyOrgSource).await;
+    let port = server.port;
+    let stylesheet_url = |port: u16| async move {
+        let resp = reqwest::get(format!("http://0.0.0.0:{port}/")).await.unwrap();
+        let html = Html::parse_document(&resp.text().await.unwrap());
+        let link = html.select(&Selector::parse("head > link[rel=stylesheet]").unwrap()).next().unwrap();
+        link.value().attr("href").unwrap().to_string()
+    };
+
+    let url = stylesheet_url(port).await;
+    assert!(url.starts_with("/static/style."));
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}{url}")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "text/css; charset=utf-8");
+    assert!(resp.headers()["cache-control"].to_str().unwrap().contains("immutable"));
+    assert!(resp.text().await.unwrap().contains("prefers-color-scheme: dark"));
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/static/logo.svg")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("style.css"), "body { color: teal }").unwrap();
+    fs::write(dir.path().join("logo.svg"), "<svg/>").unwrap();
+    let app = Server { assets: Assets::default().with_dir(dir.path()).unwrap(), ..test_app() };
+    let server = TestServer::new(app.start(EmptyOrgSource).await.unwrap());
+    let port = server.port;
+
+    let custom_url = stylesheet_url(port).await;
+    assert_ne!(custom_url, url);
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}{custom_url}")).await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "body { color: teal }");
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/static/logo.svg")).await.unwrap();
+    assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+    assert_eq!(resp.text().await.unwrap(), "<svg/>");
+
+    // Styling the login page needs no login
+    let server = prepare_auth_server(EmptyOrgSource).await;
+    let port = server.port;
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}{url}")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_render_doc() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "
+* TODO Get stuff
+Some /text/ here.
+");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/tasks.org")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+
+    let heading: Vec<String> = html.select(&Selector::parse("h1").unwrap()).map(element_to_text).collect();
+    assert_eq!(heading, ["TODO Get stuff"]);
+    let emphasis: Vec<String> = html.select(&Selector::parse("p > i").unwrap()).map(element_to_text).collect();
+    assert_eq!(emphasis, ["text"]);
+}
+
 #[tokio::test]
 async fn test_list_todo() {
     let mut source = StaticOrgSource::default();
@@ -50,7 +371,8 @@ async fn test_list_todo() {
 * DONE Buy stuff
 * TODO Do stuff
 ");
-    let TestServer { port } = prepare_server(source).await;
+    let server = prepare_server(source).await;
+    let port = server.port;
 
     let resp = reqwest::get(format!("http://0.0.0.0:{port}/todo/TODO")).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
@@ -65,35 +387,649 @@ async fn test_list_todo() {
     assert_eq!(elements, ["TODO Get stuff", "TODO Do stuff"]);
 }
 
-static PORT_NUMBER: AtomicU16 = AtomicU16::new(8000);
+#[tokio::test]
+async fn test_list_todo_details() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "
+* Shopping
+:PROPERTIES:
+:CATEGORY: Shopping
+:END:
+** TODO [#A] Buy groceries                                              :buy:
+SCHEDULED: <2024-01-29 Mon +1w>
+");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/todo/TODO")).await.unwrap();
+    let html = Html::parse_document(&resp.text().await.unwrap());
+
+    let select = |selector: &str| -> Vec<String> {
+        html.select(&Selector::parse(selector).unwrap()).map(element_to_text).collect()
+    };
+    assert_eq!(select("li .priority"), ["[#A]"]);
+    assert_eq!(select("li .tag"), ["buy"]);
+    assert_eq!(select("li .category"), ["Shopping"]);
+    assert_eq!(select("li .outline-path"), ["Shopping"]);
+    assert_eq!(select("li .timestamp"), ["<2024-01-29 Mon +1w>"]);
+}
+
+#[tokio::test]
+async fn test_agenda() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "
+* TODO Water plants
+SCHEDULED: <2024-01-30 Tue +1d>
+* DONE Buy stuff
+SCHEDULED: <2024-01-31 Wed>
+");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/agenda?span=week&start=2024-01-29")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+
+    let days: Vec<String> = html.select(&Selector::parse(".agenda-day > h2").unwrap()).map(element_to_text).collect();
+    assert_eq!(days.len(), 7);
+    assert_eq!(days[0], "Monday 29 January 2024");
+    let entries: Vec<String> = html.select(&Selector::parse(".agenda-day li a").unwrap()).map(element_to_text).collect();
+    assert_eq!(entries, ["Water plants", "Water plants", "Buy stuff", "Water plants", "Water plants", "Water plants", "Water plants"]);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/agenda?span=year")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let error: Vec<String> = html.select(&Selector::parse(".error p").unwrap()).map(element_to_text).collect();
+    assert_eq!(error, ["Invalid span: year"]);
+    assert_eq!(html.select(&Selector::parse("body > header > nav a").unwrap()).count(), 4);
+
+    let resp = reqwest::Client::new().get(format!("http://0.0.0.0:{port}/agenda?start=yesterday"))
+        .header("Accept", "application/json")
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let error: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(error["error"], "Invalid start date: yesterday");
+
+    for start in ["+262142-12-30", "-262143-01-01"] {
+        let resp = reqwest::get(format!("http://0.0.0.0:{port}/agenda?span=week&start={}", start.replace('+', "%2B"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let html = Html::parse_document(&resp.text().await.unwrap());
+        let error: Vec<String> = html.select(&Selector::parse(".error p").unwrap()).map(element_to_text).collect();
+        assert_eq!(error, [format!("Start date out of range: {start}")]);
+    }
+}
+
+#[tokio::test]
+async fn test_api() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "
+* Home                                                                 :home:
+** TODO [#B] Water plants
+SCHEDULED: <2024-01-30 Tue 10:00 +1d>
+** DONE Buy stuff
+");
+    let server = prepare_server(source).await;
+    let port = server.port;
+    let get_json = |path: &str| {
+        let url = format!("http://0.0.0.0:{port}{path}");
+        async move {
+            let resp = reqwest::get(url).await.unwrap();
+            (resp.status(), serde_json::from_str::<Value>(&resp.text().await.unwrap()).unwrap())
+        }
+    };
+
+    let (status, docs) = get_json("/api/v1/docs").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(docs, json!([{"path": "/tasks.org", "name": "/tasks.org"}]));
+
+    let (status, doc) = get_json("/api/v1/docs/tasks.org").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(doc["content"].as_str().unwrap().contains("Water plants"));
+    let headings: Vec<&str> = doc["headlines"].as_array().unwrap().iter()
+        .map(|headline| headline["heading"].as_str().unwrap())
+        .collect();
+    assert_eq!(headings, ["Home", "Water plants", "Buy stuff"]);
+
+    let (status, todos) = get_json("/api/v1/todos?done=false").await;
+    assert_eq!(status, StatusCode::OK);
+    let todos = todos.as_array().unwrap();
+    assert_eq!(todos.len(), 1);
+    assert_eq!(todos[0]["doc"], "/tasks.org");
+    assert_eq!(todos[0]["keyword"], "TODO");
+    assert_eq!(todos[0]["priority"], "B");
+    assert_eq!(todos[0]["tags"], json!(["home"]));
+    assert_eq!(todos[0]["path"], json!(["Home"]));
+    assert_eq!(todos[0]["scheduled"]["date"], "2024-01-30");
+    assert_eq!(todos[0]["scheduled"]["time"], "10:00");
+    assert_eq!(todos[0]["scheduled"]["repeater"], "+1d");
+
+    let (_, todos) = get_json("/api/v1/todos?keyword=DONE").await;
+    assert_eq!(todos.as_array().unwrap().len(), 1);
+
+    let (status, error) = get_json("/api/v1/docs/missing.org").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(error["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_content_negotiation() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Get stuff");
+    let server = prepare_server(source).await;
+    let port = server.port;
+    let client = reqwest::Client::new();
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/todo/TODO"))
+        .header("Accept", "application/json")
+        .send().await.unwrap();
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    let todos: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(todos[0]["heading"], "Get stuff");
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org"))
+        .header("Accept", "application/json")
+        .send().await.unwrap();
+    let doc: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(doc["headlines"][0]["keyword"], "TODO");
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/"))
+        .header("Accept", "text/html,*/*;q=0.8")
+        .send().await.unwrap();
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+}
+
+#[tokio::test]
+async fn test_change_state() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants
+* TODO Buy stuff
+:PROPERTIES:
+:ID: buy-stuff
+:END:
+");
+    let server = prepare_writable_server(source).await;
+    let port = server.port;
+    let client = reqwest::Client::new();
+    let post = |body: Value| client.post(format!("http://0.0.0.0:{port}/api/v1/state"))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send();
+
+    let resp = post(json!({"doc": "/tasks.org", "line": 1})).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let changed: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(changed["headline"]["keyword"], "DONE");
+    assert_eq!(changed["headline"]["done"], true);
+    assert!(changed["headline"]["closed"]["raw"].as_str().unwrap().starts_with('['));
+    let version = changed["headline"]["version"].as_str().unwrap().to_string();
+
+    let resp = post(json!({"doc": "/tasks.org", "id": "buy-stuff", "keyword": "DONE", "version": version})).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = post(json!({"doc": "/tasks.org", "id": "buy-stuff", "keyword": "TODO", "version": version})).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/docs/tasks.org")).await.unwrap();
+    let doc: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    let content = doc["content"].as_str().unwrap();
+    assert!(content.starts_with("* DONE Water plants\nCLOSED: ["));
+    assert!(content.contains("* DONE Buy stuff\nCLOSED: ["));
+
+    assert_eq!(post(json!({"doc": "/tasks.org", "line": 2})).await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(post(json!({"doc": "/tasks.org", "line": 1, "keyword": "WAIT"})).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(post(json!({"doc": "/missing.org", "line": 1})).await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_change_state_form() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let server = prepare_writable_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/todo/TODO")).await.unwrap();
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let form: Vec<(String, String)> = html.select(&Selector::parse("form.todo-state input").unwrap())
+        .map(|input| (input.value().attr("name").unwrap().to_string(), input.value().attr("value").unwrap().to_string()))
+        .collect();
+    assert_eq!(form.len(), 4);
+
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    // Other sites can't make the browser change anything
+    let resp = client.post(format!("http://0.0.0.0:{port}/state")).form(&form)
+        .header("Origin", "https://evil.example").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = client.post(format!("http://0.0.0.0:{port}/state")).form(&form)
+        .header("Sec-Fetch-Site", "cross-site").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = client.post(format!("http://0.0.0.0:{port}/api/v1/state")).header("Sec-Fetch-Site", "cross-site")
+        .header("Content-Type", "application/json").body(json!({"doc": "/tasks.org", "line": 1}).to_string())
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(resp.text().await.unwrap().contains("\"error\""));
+
+    let resp = client.post(format!("http://0.0.0.0:{port}/state")).form(&form)
+        .header("Origin", format!("http://0.0.0.0:{port}")).header("Sec-Fetch-Site", "same-origin")
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(resp.headers()["location"], "/todo/TODO");
+
+    let resp = client.post(format!("http://0.0.0.0:{port}/state")).form(&form).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_read_only_server() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/todo/TODO")).await.unwrap();
+    assert!(!resp.text().await.unwrap().contains("<form"));
+    let resp = reqwest::Client::new().post(format!("http://0.0.0.0:{port}/state"))
+        .form(&[("doc", "/tasks.org"), ("line", "1")])
+        .send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn test_search() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("home.org", "* TODO Call the plumber :home:\nThe kitchen sink is leaking.\n");
+    source.add_doc("projects/work.org", "* Meeting notes\n** Budget\nKitchen renovation costs.\n");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/search?q=kitchen")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let links: Vec<&str> = html.select(&Selector::parse("ol.search-results > li > a").unwrap())
+        .filter_map(|a| a.value().attr("href"))
+        .collect();
+    assert_eq!(links, ["/home.org#call-the-plumber", "/projects/work.org#budget"]);
+    let marks: Vec<String> = html.select(&Selector::parse(".snippet mark").unwrap()).map(element_to_text).collect();
+    assert_eq!(marks, ["kitchen", "Kitchen"]);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/home.org")).await.unwrap();
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    assert_eq!(html.select(&Selector::parse("h1#call-the-plumber").unwrap()).count(), 1);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/search?q=budget+kitch")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let hits: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(hits.as_array().unwrap().len(), 1);
+    assert_eq!(hits[0]["doc"], "/projects/work.org");
+    assert_eq!(hits[0]["line"], 2);
+    assert_eq!(hits[0]["url"], "/projects/work.org#budget");
+    assert_eq!(hits[0]["snippet"], "<mark>Kitchen</mark> renovation costs.");
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/search?q=nothing")).await.unwrap();
+    assert!(resp.text().await.unwrap().contains("No matches"));
+}
+
+#[tokio::test]
+async fn test_query() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("home.org", "#+CATEGORY: Home
+* TODO [#A] Buy milk :buy:
+* TODO [#C] Buy a sofa :buy:someday:
+* DONE Pay rent
+");
+    source.add_doc("work.org", "#+CATEGORY: Work\n* TODO [#B] Buy paper :buy:\n");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/query?q=tags:buy+-tags:someday+sort:priority+group:category")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let groups: Vec<String> = html.select(&Selector::parse("section.query-group > h2").unwrap()).map(element_to_text).collect();
+    assert_eq!(groups, ["Home", "Work"]);
+    let links: Vec<&str> = html.select(&Selector::parse("section.query-group li > a.doc").unwrap())
+        .filter_map(|a| a.value().attr("href"))
+        .collect();
+    assert_eq!(links, ["/home.org#buy-milk", "/work.org#buy-paper"]);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/query?q=todo:TODO+priority%3E%3DB+sort:priority")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let result: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    let headings: Vec<&str> = result["groups"][0]["headlines"].as_array().unwrap().iter()
+        .map(|headline| headline["heading"].as_str().unwrap())
+        .collect();
+    assert_eq!(headings, ["Buy milk", "Buy paper"]);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/api/v1/query?q=todo:TODO+colour:red")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let error: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(error["position"], 10);
+    assert_eq!(error["error"], "Unknown field `colour` at column 11");
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/query?q=deadline<soon")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(resp.text().await.unwrap().contains("class=\"query-error\""));
+}
+
+#[tokio::test]
+async fn test_calendar() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("home.org", "* TODO Water plants :home:
+SCHEDULED: <2024-01-29 Mon 09:00 +1w>
+* Dentist <2024-02-01 Thu 14:00>
+");
+    source.add_doc("work.org", "* TODO Report\nDEADLINE: <2024-02-02 Fri>\n");
+    let server = prepare_server(source).await;
+    let port = server.port;
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/calendar.ics")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "text/calendar; charset=utf-8");
+    let ics = resp.text().await.unwrap();
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+    assert_eq!(ics.matches("BEGIN:VTODO").count(), 2);
+    assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+    assert!(ics.contains("RRULE:FREQ=WEEKLY\r\n"));
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/calendar.ics?tag=home")).await.unwrap();
+    let ics = resp.text().await.unwrap();
+    assert_eq!(ics.matches("BEGIN:VTODO").count(), 1);
+    assert_eq!(ics.matches("BEGIN:VEVENT").count(), 0);
+
+    let resp = reqwest::get(format!("http://0.0.0.0:{port}/calendar.ics?file=work.org")).await.unwrap();
+    let ics = resp.text().await.unwrap();
+    assert!(ics.contains("SUMMARY:Report\r\n"));
+    assert_eq!(ics.matches("BEGIN:VTODO").count(), 1);
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let server = prepare_auth_server(source).await;
+    let port = server.port;
+    let client = reqwest::Client::new();
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers()["www-authenticate"].to_str().unwrap().starts_with("Basic "));
+    let resp = client.get(format!("http://0.0.0.0:{port}/missing.org")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    for (username, password) in [("alice", "wonderland"), ("bob", "builder")] {
+        let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org"))
+            .basic_auth(username, Some(password)).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.text().await.unwrap().contains("Water plants"));
+    }
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org"))
+        .basic_auth("alice", Some("builder")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/api/v1/docs")).bearer_auth("tests.t0ken").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client.get(format!("http://0.0.0.0:{port}/api/v1/docs")).bearer_auth("wrong").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let www_authenticate: Vec<&str> = resp.headers().get_all("www-authenticate").iter().map(|v| v.to_str().unwrap()).collect();
+    assert!(www_authenticate.iter().any(|value| value.starts_with("Bearer ")));
+    assert_eq!(serde_json::from_str::<Value>(&resp.text().await.unwrap()).unwrap()["error"], "Authentication required");
+    // Tokens are only for the API and other JSON responses
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org")).bearer_auth("tests.t0ken").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    for path in ["/tasks.org", "/query?q=todo:TODO", "/search?q=plants"] {
+        let resp = client.get(format!("http://0.0.0.0:{port}{path}")).bearer_auth("tests.t0ken")
+            .header("Accept", "application/json").send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn test_auth_login() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let server = prepare_auth_server(source).await;
+    let port = server.port;
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org?x=1")).header("accept", "text/html").send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers()["www-authenticate"].to_str().unwrap().starts_with("Cookie "));
+    let html = Html::parse_document(&resp.text().await.unwrap());
+    let next = html.select(&Selector::parse("form.login input[name=next]").unwrap()).next().unwrap();
+    assert_eq!(next.value().attr("value"), Some("/tasks.org?x=1"));
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/login")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let login = |password: &'static str| client.post(format!("http://0.0.0.0:{port}/login"))
+        .form(&[("username", "alice"), ("password", password), ("next", "/tasks.org")])
+        .send();
+    let resp = login("wrong").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().get("set-cookie").is_none());
+
+    let resp = login("wonderland").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(resp.headers()["location"], "/tasks.org");
+    let cookie = resp.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_string();
+    assert!(cookie.starts_with("org_session="));
+
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org")).header("cookie", &cookie).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client.get(format!("http://0.0.0.0:{port}/api/v1/docs")).header("cookie", &cookie).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client.post(format!("http://0.0.0.0:{port}/logout")).header("cookie", &cookie).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = client.get(format!("http://0.0.0.0:{port}/tasks.org")).header("cookie", &cookie).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let write_cert = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        // Serializing signs again, the client has to trust the exact certificate served
+        let pem = cert.serialize_pem().unwrap();
+        std::fs::write(&cert_path, &pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        reqwest::Certificate::from_pem(pem.as_bytes()).unwrap()
+    };
+    let client_trusting = |cert: reqwest::Certificate| reqwest::Client::builder()
+        .add_root_certificate(cert)
+        .tls_built_in_root_certs(false)
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_max_idle_per_host(0)
+        .build().unwrap();
+
+    let first = client_trusting(write_cert());
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let mut app = test_app();
+    app.tls = Some(TlsConfig::new(&cert_path, &key_path)
+        .with_reload_interval(Duration::from_millis(100))
+        .with_http_redirect(0));
+    let server = app.start(source).await.unwrap();
+    let port = server.local_addr().tcp().unwrap().port();
+    let http_port = server.redirect_addr().unwrap().port();
+
+    let url = format!("https://localhost:{port}/tasks.org");
+    let resp = first.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await.unwrap().contains("Water plants"));
+
+    // Plain HTTP isn't served on the HTTPS port, but redirected from the other one
+    assert!(reqwest::get(format!("http://localhost:{port}/tasks.org")).await.is_err());
+    let resp = first.get(format!("http://localhost:{http_port}/tasks.org?x=1")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(resp.headers()["location"].to_str().unwrap(), format!("https://localhost:{port}/tasks.org?x=1"));
+
+    // A renewed certificate is picked up for new connections, after a second for
+    // filesystems with coarse modification times
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let second = client_trusting(write_cert());
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if second.get(&url).send().await.is_ok() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(reloaded, "certificate wasn't reloaded");
+    assert!(first.get(&url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let server = prepare_server(SlowSource(source)).await;
+    let port = server.port;
+
+    let in_flight = tokio::spawn(reqwest::get(format!("http://0.0.0.0:{port}/tasks.org")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    server.handle.stop().await.unwrap();
+
+    let resp = in_flight.await.unwrap().unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await.unwrap().contains("Water plants"));
+    assert!(reqwest::get(format!("http://0.0.0.0:{port}/")).await.is_err());
+
+    // Dropping the handle shuts down as well
+    let server = prepare_server(EmptyOrgSource).await;
+    let port = server.port;
+    assert!(reqwest::get(format!("http://0.0.0.0:{port}/")).await.is_ok());
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(reqwest::get(format!("http://0.0.0.0:{port}/")).await.is_err());
+}
+
+#[tokio::test]
+async fn test_provided_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Server { listener: Some(listener.into()), ..test_app() };
+    let server = app.start(EmptyOrgSource).await.unwrap();
+    assert_eq!(server.local_addr(), &ListenAddr::Tcp(addr));
+
+    let resp = reqwest::get(format!("http://{addr}/")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(server.redirect_addr().is_none());
+}
+
+#[tokio::test]
+async fn test_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("org-server.sock");
+    let mut source = StaticOrgSource::default();
+    source.add_doc("tasks.org", "* TODO Water plants\n");
+    let app = Server { listener: Some(Listener::bind_unix(&path, Some(0o660)).unwrap()), ..test_app() };
+    let server = app.start(source).await.unwrap();
+    assert_eq!(server.local_addr(), &ListenAddr::Unix(Some(path.clone())));
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(b"GET /tasks.org HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Water plants"));
+
+    // HTTPS is only for TCP
+    let app = Server {
+        listener: Some(Listener::bind_unix(&dir.path().join("tls.sock"), None).unwrap()),
+        tls: Some(TlsConfig::new("cert.pem", "key.pem")),
+        ..test_app()
+    };
+    assert!(app.start(EmptyOrgSource).await.is_err());
+}
+
+/// Takes its time reading documents, to have requests in flight.
+struct SlowSource(StaticOrgSource);
+
+#[async_trait]
+impl OrgSource for SlowSource {
+    type Doc = <StaticOrgSource as OrgSource>::Doc;
+
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        self.0.list().await
+    }
 
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError> {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        self.0.read(doc).await
+    }
+}
+
+/// Serves `/ok.org`, refuses `/secret.org` and fails on `/remote.org`, or fails
+/// to list anything while `down`.
+struct FailingSource {
+    down: bool,
+}
+
+#[async_trait]
+impl OrgSource for FailingSource {
+    type Doc = StaticOrgDoc;
+
+    async fn list(&self) -> Result<Vec<String>, SourceError> {
+        if self.down {
+            return Err(SourceError::Remote("connection refused".to_string()));
+        }
+        Ok(vec!["/ok.org".to_string(), "/secret.org".to_string()])
+    }
+
+    async fn read(&self, doc: &str) -> Result<Self::Doc, SourceError> {
+        match doc {
+            "/ok.org" => Ok(StaticOrgDoc::new("* TODO Visible")),
+            "/secret.org" => Err(SourceError::Forbidden),
+            "/remote.org" => Err(SourceError::Remote("connection refused".to_string())),
+            _ => Err(SourceError::NotFound),
+        }
+    }
+}
+
+/// A running server, shut down when dropped.
 struct TestServer {
     port: u16,
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    fn new(handle: ServerHandle) -> Self {
+        TestServer { port: handle.local_addr().tcp().unwrap().port(), handle }
+    }
 }
 
 #[must_use]
 async fn prepare_server(source: impl OrgSource + 'static) -> TestServer {
-    let port = PORT_NUMBER.fetch_add(1, Ordering::Relaxed);
-    println!("using port: {port}");
-    let app = org_server::server::Server{
-        port,
-        parser_config: ParserConfig::with_keywords(&["TODO"], &["DONE"]),
-    };
-    tokio::spawn(async move {
-        app.start(source).await.unwrap();
-    });
+    TestServer::new(test_app().start(source).await.unwrap())
+}
 
-    let server = TestServer { port };
-    wait_for_server(&server).await;
-    server
+#[must_use]
+async fn prepare_writable_server(source: impl WritableOrgSource + 'static) -> TestServer {
+    TestServer::new(test_app().start_writable(source).await.unwrap())
 }
 
-async fn wait_for_server(TestServer { port }: &TestServer) {
-    for _ in 0..5 {
-        match reqwest::get(format!("http://0.0.0.0:{port}")).await {
-            Ok(_) => return,
-            Err(_) => continue,
-        }
+/// A server letting in alice and bob with HTTP basic auth or the login form, and the
+/// API token `tests.t0ken`.
+#[must_use]
+async fn prepare_auth_server(source: impl OrgSource + 'static) -> TestServer {
+    let mut app = test_app();
+    // Cheap hashes, the default parameters take a while in debug builds
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap());
+    let salt = SaltString::encode_b64(b"not very random").unwrap();
+    app.auth = Some(Auth::default()
+        .with_user("alice", &argon2.hash_password(b"wonderland", &salt).unwrap().to_string())
+        .with_user("bob", &bcrypt::hash("builder", 4).unwrap())
+        .with_token("tests", &bcrypt::hash("t0ken", 4).unwrap()));
+    TestServer::new(app.start(source).await.unwrap())
+}
+
+/// A server on a free port.
+fn test_app() -> Server {
+    Server{
+        port: 0,
+        parser_config: ParserConfig::with_keywords(&["TODO"], &["DONE"]),
+        ..Default::default()
     }
 }
 
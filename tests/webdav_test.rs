@@ -0,0 +1,133 @@
+use std::net::{SocketAddr, TcpListener};
+
+use axum::{Router, routing, http::{Method, StatusCode, HeaderMap, header}, extract, response::IntoResponse};
+use org_server::{doc::{OrgDoc, OrgSource, SourceError}, webdav::WebDavSource};
+use reqwest::Url;
+
+const USERNAME: &str = "user";
+const PASSWORD: &str = "secret";
+// base64 of "user:secret"
+const AUTHORIZATION: &str = "Basic dXNlcjpzZWNyZXQ=";
+
+#[tokio::test]
+async fn test_list_documents() {
+    let addr = start_stub_server();
+    let source = make_source(addr, PASSWORD);
+
+    let mut docs = source.list().await.unwrap();
+    docs.sort();
+    assert_eq!(docs, ["/my notes.org", "/tasks.org"]);
+}
+
+#[tokio::test]
+async fn test_read_document() {
+    let addr = start_stub_server();
+    let source = make_source(addr, PASSWORD);
+
+    let doc = source.read("/tasks.org").await.unwrap();
+    assert_eq!(doc.content(), "* TODO Water the plants");
+
+    let doc = source.read("/my notes.org").await.unwrap();
+    assert_eq!(doc.content(), "* Notes");
+
+    assert!(matches!(source.read("/missing.org").await, Err(SourceError::NotFound)));
+}
+
+#[tokio::test]
+async fn test_wrong_credentials() {
+    let addr = start_stub_server();
+    let source = make_source(addr, "wrong");
+
+    assert!(matches!(source.list().await, Err(SourceError::Forbidden)));
+    assert!(matches!(source.read("/tasks.org").await, Err(SourceError::Forbidden)));
+}
+
+#[tokio::test]
+async fn test_unreachable_server() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let source = make_source(addr, PASSWORD);
+
+    assert!(matches!(source.list().await, Err(SourceError::Remote(_))));
+    assert!(matches!(source.read("/tasks.org").await, Err(SourceError::Remote(_))));
+}
+
+#[tokio::test]
+async fn test_doc_name() {
+    let addr = start_stub_server();
+    let source = make_source(addr, PASSWORD);
+
+    assert_eq!(source.doc_name("/my notes.org"), "my notes.org");
+}
+
+fn make_source(addr: SocketAddr, password: &str) -> WebDavSource {
+    let base = Url::parse(&format!("http://{addr}/")).unwrap();
+    WebDavSource::new(base, USERNAME, password, "remote.php/dav/files/user/org")
+}
+
+fn start_stub_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route("/remote.php/dav/files/user/org/", routing::any(stub_collection))
+        .route("/remote.php/dav/files/user/org/:name", routing::any(stub_file));
+
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener).unwrap()
+            .serve(app.into_make_service())
+            .await.unwrap();
+    });
+
+    addr
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    headers.get(header::AUTHORIZATION).map(|value| value == AUTHORIZATION).unwrap_or(false)
+}
+
+async fn stub_collection(method: Method, headers: HeaderMap) -> impl IntoResponse {
+    if !authorized(&headers) {
+        return (StatusCode::UNAUTHORIZED, String::new());
+    }
+    if method.as_str() != "PROPFIND" || headers.get("Depth").map(|d| d != "1").unwrap_or(true) {
+        return (StatusCode::METHOD_NOT_ALLOWED, String::new());
+    }
+
+    (StatusCode::MULTI_STATUS, r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/tasks.org</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/my%20notes.org</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/picture.png</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/org/archive.org/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#.to_string())
+}
+
+async fn stub_file(method: Method, headers: HeaderMap, extract::Path(name): extract::Path<String>) -> impl IntoResponse {
+    if !authorized(&headers) {
+        return (StatusCode::UNAUTHORIZED, String::new());
+    }
+    if method != Method::GET {
+        return (StatusCode::METHOD_NOT_ALLOWED, String::new());
+    }
+
+    match name.as_str() {
+        "tasks.org" => (StatusCode::OK, "* TODO Water the plants".to_string()),
+        "my notes.org" => (StatusCode::OK, "* Notes".to_string()),
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    }
+}